@@ -0,0 +1,76 @@
+#![allow(clippy::needless_return)]
+
+// Parses a set of queries in a loop into a recycled buffer and reports the heap
+// allocations per query, which must be zero once the buffer has grown. Only parsing is
+// measured, the passes after it allocate.
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+use finne_parser::query_parser::{parse_query, recycle_buffer, QueryNode};
+
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        return System.alloc(layout);
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        return System.realloc(ptr, layout, new_size);
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+const QUERIES: [&str; 6] = [
+    "title:fox",
+    "title:\"quick brown fox\"~3 and not body:lazy",
+    "(title:fo* or title:fox~1) and count:[1 TO 10}",
+    "title:fox^2 or body:dog^0.5 or body:cat",
+    "quick and brown or not (lazy and dog)",
+    "price:{* TO 2.5] and (title:\"red fox\" or title:r?d)",
+];
+
+const ITERATIONS: usize = 1_000_000;
+
+fn run(mut buffer: Vec<QueryNode<'static>>, iterations: usize) -> (Vec<QueryNode<'static>>, usize) {
+    let mut nodes = 0;
+    for i in 0..iterations {
+        let query = QUERIES[i % QUERIES.len()].as_bytes();
+        let mut query_nodes = recycle_buffer(buffer);
+        parse_query(query, &mut query_nodes).unwrap();
+        nodes += query_nodes.len();
+        buffer = recycle_buffer(query_nodes);
+    }
+    return (buffer, nodes);
+}
+
+fn main() {
+    // Warm up so the buffer reaches the size of the largest query.
+    let (buffer, _) = run(Vec::new(), QUERIES.len());
+
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let start = Instant::now();
+    let (_buffer, nodes) = run(buffer, ITERATIONS);
+    let elapsed = start.elapsed();
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - allocations;
+
+    println!(
+        "parse_query: {} queries, {} nodes, {:.1} ns/query, {:.3} allocations/query",
+        ITERATIONS,
+        nodes,
+        elapsed.as_nanos() as f64 / ITERATIONS as f64,
+        allocations as f64 / ITERATIONS as f64
+    );
+    assert_eq!(allocations, 0, "parsing allocated in steady state");
+}
@@ -1,2 +1,6 @@
+#![allow(clippy::needless_return)]
+
 pub mod request_parser;
+pub mod query_dsl;
 pub mod query_parser;
+pub mod query_rewriter;
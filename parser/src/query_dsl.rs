@@ -0,0 +1,351 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::Bound;
+
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::query_parser::{push_node, NodeType, QueryNode, Term, TermType, MAX_QUERY_NODES};
+
+/*
+ * JSON alternative to the query string syntax, lowered into the same QueryNode buffer.
+ *
+ * {"bool": {
+ *     "must": [{"term": {"al": "dog"}}],
+ *     "should": [{"phrase": {"title": {"value": "quick fox", "slop": 2}}},
+ *                {"prefix": {"title": "fo"}}],
+ *     "must_not": [{"range": {"price": {"gte": 10, "lt": 20}}},
+ *                  {"fuzzy": {"title": {"value": "fox", "fuzziness": 1, "boost": 2}}}]
+ * }}
+ *
+ * must clauses are and'ed, at least one should clause has to match and must_not
+ * clauses are and'ed as not. Every leaf takes a single field, either with a plain
+ * value or an object with the value and an optional boost.
+ */
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum JsonQuery {
+    Bool(BoolQuery),
+    Term(HashMap<String, ValueQuery>),
+    Phrase(HashMap<String, PhraseQuery>),
+    Range(HashMap<String, RangeQuery>),
+    Prefix(HashMap<String, ValueQuery>),
+    Fuzzy(HashMap<String, FuzzyQuery>),
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct BoolQuery {
+    pub must: Vec<JsonQuery>,
+    pub should: Vec<JsonQuery>,
+    pub must_not: Vec<JsonQuery>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+pub enum ValueQuery {
+    Full {
+        value: Value,
+        boost: Option<f32>,
+    },
+    Short(Value),
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+pub enum PhraseQuery {
+    Full {
+        value: String,
+        slop: Option<u32>,
+        boost: Option<f32>,
+    },
+    Short(String),
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+pub enum FuzzyQuery {
+    Full {
+        value: Value,
+        fuzziness: Option<u8>,
+        boost: Option<f32>,
+    },
+    Short(Value),
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct RangeQuery {
+    pub gt: Option<Value>,
+    pub gte: Option<Value>,
+    pub lt: Option<Value>,
+    pub lte: Option<Value>,
+    pub boost: Option<f32>,
+}
+
+#[derive(Debug)]
+pub enum JsonQueryError {
+    Json(serde_json::Error),
+    Invalid(&'static str),
+}
+
+impl fmt::Display for JsonQueryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return match self {
+            JsonQueryError::Json(e) => write!(f, "invalid query json: {}", e),
+            JsonQueryError::Invalid(msg) => write!(f, "invalid query: {}", msg),
+        };
+    }
+}
+
+impl std::error::Error for JsonQueryError {}
+
+const DEFAULT_FUZZY_DISTANCE: u8 = 2;
+
+#[inline]
+pub fn parse_json_query(body: &[u8], query_buffer: &mut Vec<QueryNode<'_>>) -> Result<usize, JsonQueryError> {
+    let query: JsonQuery = match serde_json::from_slice(body) {
+        Ok(query) => query,
+        Err(e) => return Err(JsonQueryError::Json(e)),
+    };
+    query_buffer.clear();
+    // Nesting is bounded by the recursion limit of serde_json, the length of clause lists here.
+    let root = lower_query(&query, query_buffer)?;
+    if query_buffer.len() > MAX_QUERY_NODES {
+        return Err(JsonQueryError::Invalid("query has too many clauses"));
+    }
+    return Ok(root);
+}
+
+fn lower_query(query: &JsonQuery, nodes: &mut Vec<QueryNode<'_>>) -> Result<usize, JsonQueryError> {
+    let term = match query {
+        JsonQuery::Bool(bool_query) => return lower_bool(bool_query, nodes),
+        JsonQuery::Term(fields) => {
+            let (field, query) = single_field(fields)?;
+            let (value, boost) = match query {
+                ValueQuery::Short(value) => (value, None),
+                ValueQuery::Full { value, boost } => (value, *boost),
+            };
+            let term_type = match boost {
+                Some(_) => TermType::Boosted,
+                None => TermType::Word,
+            };
+            new_term(term_type, field, scalar_to_string(value)?, boost)
+        }
+        JsonQuery::Phrase(fields) => {
+            let (field, query) = single_field(fields)?;
+            let (value, slop, boost) = match query {
+                PhraseQuery::Short(value) => (value, None, None),
+                PhraseQuery::Full { value, slop, boost } => (value, *slop, *boost),
+            };
+            if value.trim().is_empty() {
+                return Err(JsonQueryError::Invalid("phrase must not be empty"));
+            }
+            let term_type = match slop {
+                Some(slop) => TermType::Proximity(slop),
+                None => TermType::Phrase,
+            };
+            new_term(term_type, field, value.clone(), boost)
+        }
+        JsonQuery::Range(fields) => {
+            let (field, range) = single_field(fields)?;
+            let lower = match (&range.gt, &range.gte) {
+                (Some(_), Some(_)) => return Err(JsonQueryError::Invalid("range has both gt and gte")),
+                (Some(v), None) => Bound::Excluded(scalar_to_string(v)?.into()),
+                (None, Some(v)) => Bound::Included(scalar_to_string(v)?.into()),
+                (None, None) => Bound::Unbounded,
+            };
+            let upper = match (&range.lt, &range.lte) {
+                (Some(_), Some(_)) => return Err(JsonQueryError::Invalid("range has both lt and lte")),
+                (Some(v), None) => Bound::Excluded(scalar_to_string(v)?.into()),
+                (None, Some(v)) => Bound::Included(scalar_to_string(v)?.into()),
+                (None, None) => Bound::Unbounded,
+            };
+            new_term(TermType::Range(lower, upper), field, String::new(), range.boost)
+        }
+        JsonQuery::Prefix(fields) => {
+            let (field, query) = single_field(fields)?;
+            let (value, boost) = match query {
+                ValueQuery::Short(value) => (value, None),
+                ValueQuery::Full { value, boost } => (value, *boost),
+            };
+            let mut value = scalar_to_string(value)?;
+            // The prefix is searched as a wildcard, where these would match any text.
+            if value.contains(['*', '?']) {
+                return Err(JsonQueryError::Invalid("prefix contains '*' or '?'"));
+            }
+            value.push('*');
+            new_term(TermType::Wildcard, field, value, boost)
+        }
+        JsonQuery::Fuzzy(fields) => {
+            let (field, query) = single_field(fields)?;
+            let (value, fuzziness, boost) = match query {
+                FuzzyQuery::Short(value) => (value, None, None),
+                FuzzyQuery::Full {
+                    value,
+                    fuzziness,
+                    boost,
+                } => (value, *fuzziness, *boost),
+            };
+            let distance = fuzziness.unwrap_or(DEFAULT_FUZZY_DISTANCE);
+            new_term(TermType::Fuzzy(distance), field, scalar_to_string(value)?, boost)
+        }
+    };
+    return Ok(push_node(nodes, NodeType::Term, term, None, None));
+}
+
+fn lower_bool(query: &BoolQuery, nodes: &mut Vec<QueryNode<'_>>) -> Result<usize, JsonQueryError> {
+    let mut root: Option<usize> = None;
+    for clause in &query.must {
+        let idx = lower_query(clause, nodes)?;
+        root = Some(chain(nodes, NodeType::And, root, idx));
+    }
+    let mut should: Option<usize> = None;
+    for clause in &query.should {
+        let idx = lower_query(clause, nodes)?;
+        should = Some(chain(nodes, NodeType::Or, should, idx));
+    }
+    if let Some(should) = should {
+        root = Some(chain(nodes, NodeType::And, root, should));
+    }
+    for clause in &query.must_not {
+        let idx = lower_query(clause, nodes)?;
+        let not = push_node(nodes, NodeType::Not, Term::default(), Some(idx), None);
+        root = Some(chain(nodes, NodeType::And, root, not));
+    }
+    return match root {
+        Some(root) => Ok(root),
+        None => Err(JsonQueryError::Invalid("bool query needs at least one clause")),
+    };
+}
+
+#[inline]
+fn chain(nodes: &mut Vec<QueryNode<'_>>, node_type: NodeType, left: Option<usize>, right: usize) -> usize {
+    return match left {
+        Some(left) => push_node(nodes, node_type, Term::default(), Some(left), Some(right)),
+        None => right,
+    };
+}
+
+#[inline]
+fn single_field<T>(fields: &HashMap<String, T>) -> Result<(&String, &T), JsonQueryError> {
+    if fields.len() != 1 {
+        return Err(JsonQueryError::Invalid("leaf queries take exactly one field"));
+    }
+    return Ok(fields.iter().next().unwrap());
+}
+
+#[inline]
+fn scalar_to_string(value: &Value) -> Result<String, JsonQueryError> {
+    return match value {
+        Value::String(s) if !s.is_empty() => Ok(s.clone()),
+        Value::Number(n) => Ok(n.to_string()),
+        _ => Err(JsonQueryError::Invalid("values must be non empty strings or numbers")),
+    };
+}
+
+// The query json is dropped after lowering so terms own their text.
+#[inline]
+fn new_term<'a>(term_type: TermType<'a>, field: &str, value: String, boost: Option<f32>) -> Term<'a> {
+    return Term {
+        term_type,
+        field: Cow::Owned(field.to_owned()),
+        value: Cow::Owned(value),
+        term_boost: boost.unwrap_or(1.0),
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::query_parser::{parse_query, to_query_string};
+
+    fn assert_same_query(json: &str, query: &str) {
+        let mut json_nodes = Vec::new();
+        let json_root = parse_json_query(json.as_bytes(), &mut json_nodes).unwrap();
+        let mut nodes = Vec::new();
+        let root = parse_query(query.as_bytes(), &mut nodes).unwrap();
+        assert_eq!(to_query_string(&json_nodes, json_root), to_query_string(&nodes, root));
+    }
+
+    #[test]
+    fn test_leaf_queries() {
+        assert_same_query(r#"{"term": {"al": "dog"}}"#, "al:dog");
+        assert_same_query(r#"{"term": {"price": 10}}"#, "price:10");
+        assert_same_query(r#"{"term": {"title": {"value": "fox", "boost": 3}}}"#, "title:fox^3");
+        assert_same_query(r#"{"phrase": {"title": "quick fox"}}"#, "title:\"quick fox\"");
+        assert_same_query(
+            r#"{"phrase": {"title": {"value": "quick fox", "slop": 5, "boost": 2}}}"#,
+            "title:\"quick fox\"~5^2",
+        );
+        assert_same_query(r#"{"range": {"price": {"gte": 10, "lt": 20}}}"#, "price:[10 TO 20}");
+        assert_same_query(r#"{"range": {"price": {"gt": 1.5}}}"#, "price:{1.5 TO *]");
+        assert_same_query(r#"{"prefix": {"title": "fo"}}"#, "title:fo*");
+        assert_same_query(r#"{"fuzzy": {"title": "fox"}}"#, "title:fox~2");
+        assert_same_query(
+            r#"{"fuzzy": {"title": {"value": "fox", "fuzziness": 1, "boost": 0.5}}}"#,
+            "title:fox~1^0.5",
+        );
+    }
+
+    #[test]
+    fn test_bool_query() {
+        assert_same_query(
+            r#"{"bool": {
+                "must": [{"term": {"al": "dog"}}],
+                "should": [{"term": {"al": "cat"}}, {"term": {"al": "mouse"}}],
+                "must_not": [{"term": {"al": "bird"}}]
+            }}"#,
+            "al:dog and (al:cat or al:mouse) and not al:bird",
+        );
+        assert_same_query(
+            r#"{"bool": {"must_not": [{"bool": {"must": [{"term": {"a": 1}}, {"term": {"b": 2}}]}}]}}"#,
+            "not (a:1 and b:2)",
+        );
+    }
+
+    #[test]
+    fn test_invalid_queries() {
+        let mut nodes = Vec::new();
+        assert!(matches!(
+            parse_json_query(b"{\"term\": ", &mut nodes),
+            Err(JsonQueryError::Json(_))
+        ));
+        assert!(matches!(
+            parse_json_query(b"{\"match\": {\"al\": \"dog\"}}", &mut nodes),
+            Err(JsonQueryError::Json(_))
+        ));
+        assert!(matches!(
+            parse_json_query(b"{\"bool\": {}}", &mut nodes),
+            Err(JsonQueryError::Invalid(_))
+        ));
+        assert!(matches!(
+            parse_json_query(b"{\"term\": {\"a\": \"1\", \"b\": \"2\"}}", &mut nodes),
+            Err(JsonQueryError::Invalid(_))
+        ));
+        assert!(matches!(
+            parse_json_query(b"{\"term\": {\"a\": true}}", &mut nodes),
+            Err(JsonQueryError::Invalid(_))
+        ));
+        assert!(matches!(
+            parse_json_query(b"{\"range\": {\"a\": {\"gt\": 1, \"gte\": 2}}}", &mut nodes),
+            Err(JsonQueryError::Invalid(_))
+        ));
+        for prefix in [r#"{"prefix": {"a": "a?c"}}"#, r#"{"prefix": {"a": {"value": "*"}}}"#] {
+            assert!(matches!(
+                parse_json_query(prefix.as_bytes(), &mut nodes),
+                Err(JsonQueryError::Invalid("prefix contains '*' or '?'"))
+            ));
+        }
+        let should = vec![r#"{"term": {"a": 1}}"#; MAX_QUERY_NODES / 2 + 1].join(",");
+        let query = format!(r#"{{"bool": {{"should": [{}]}}}}"#, should);
+        assert!(matches!(
+            parse_json_query(query.as_bytes(), &mut nodes),
+            Err(JsonQueryError::Invalid("query has too many clauses"))
+        ));
+        let nested = "[".repeat(10_000);
+        assert!(matches!(parse_json_query(nested.as_bytes(), &mut nodes), Err(JsonQueryError::Json(_))));
+    }
+}
@@ -1,37 +1,79 @@
-#![allow(dead_code)]
+use std::borrow::Cow;
+use std::fmt;
+use std::ops::Bound;
 
-//use nom::IResult;
+use nom::{
+    branch::alt,
+    bytes::complete::{tag, tag_no_case, take, take_while1},
+    character::complete::{char, multispace0, multispace1, u32 as parse_u32, u8 as parse_u8},
+    combinator::{cut, map, not, opt, recognize, verify},
+    error::{context, ContextError, ErrorKind, ParseError},
+    multi::many0_count,
+    number::complete::{double, float},
+    sequence::{delimited, preceded, terminated, tuple},
+    IResult,
+};
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum NodeType {
     And,
     Or,
     Not,
     Group,
+    Term,
 }
 
-pub struct QueryNode {
-    node_type: NodeType,
-    term: Term,
-    left: Option<usize>,
-    right: Option<usize>,
+#[derive(Debug, PartialEq, Clone)]
+pub struct QueryNode<'a> {
+    pub node_type: NodeType,
+    pub term: Term<'a>,
+    pub left: Option<usize>,
+    pub right: Option<usize>,
 }
 
-pub enum TermType {
+#[derive(Debug, PartialEq, Clone)]
+pub enum TermType<'a> {
     Phrase,
     Word,
     Wildcard,
-    Fuzzy,
-    Proximity,
-    Range,
-    Boosted
+    // Maximum edit distance.
+    Fuzzy(u8),
+    // Maximum number of positions the phrase words may be apart.
+    Proximity(u32),
+    // Lower and upper bounds, the term value is left empty.
+    Range(Bound<Cow<'a, str>>, Bound<Cow<'a, str>>),
+    Boosted,
+    // Lower left and upper right corner, the term value is left empty.
+    GeoBox(GeoPoint, GeoPoint),
+    // Center and maximum distance in meters, the term value is left empty.
+    GeoDistance(GeoPoint, f64),
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct GeoPoint {
+    pub lat: f64,
+    pub lon: f64,
 }
 
-pub struct Term {
-    term_type: TermType,
-    field: String,
-    value: String,
-    term_boost: f32,
+#[derive(Debug, PartialEq, Clone)]
+// Field and value borrow from the query, later passes only allocate for text they rewrite.
+pub struct Term<'a> {
+    pub term_type: TermType<'a>,
+    pub field: Cow<'a, str>,
+    pub value: Cow<'a, str>,
+    pub term_boost: f32,
+}
+
+impl Default for Term<'_> {
+    #[inline]
+    fn default() -> Self {
+        return Term {
+            term_type: TermType::Word,
+            field: Cow::Borrowed(""),
+            value: Cow::Borrowed(""),
+            term_boost: 1.0,
+        };
+    }
 }
 
 /*
@@ -39,8 +81,1089 @@ pub struct Term {
  *
  * Example query:
  * al:dog and (al:cat or al:mouse) and not al:bird
+ *
+ * Precedence from loosest to tightest: or, and, not, (group).
+ * Operators are case insensitive and left associative.
+ *
+ * Term values:
+ * al:dog              Word
+ * al:"quick fox"      Phrase
+ * al:fo*  al:f?x      Wildcard
+ * al:fox~  al:fox~1   Fuzzy (default edit distance of 2)
+ * al:"quick fox"~5    Proximity
+ * al:[10 TO 20}       Range, [ ] are inclusive, { } exclusive and * is unbounded
+ * al:fox^3            Boosted, a boost on any other term type keeps its type. A negative
+ *                     boost lowers the score of the documents the term matches.
+ *
+ * A backslash takes the next byte literally in words, phrases and range bounds, as in
+ * al:ice\ cream, al:"say \"hi\"" or al:fo\*. In a wildcard an escaped * or ? still matches
+ * any text.
+ *
+ * The field may be left out (dog and "quick fox"), such terms have an empty field
+ * and are expanded to the collection's default fields before execution.
+ *
+ * The query is flattened into a buffer of QueryNodes. Children are always
+ * written before their parent so the root is the last node in the buffer.
+ * And/Or use both left and right, Not/Group only use left and Term nodes
+ * have no children. The example above becomes:
+ *
+ * 0: Term al:dog
+ * 1: Term al:cat
+ * 2: Term al:mouse
+ * 3: Or (1, 2)
+ * 4: Group (3)
+ * 5: And (0, 4)
+ * 6: Term al:bird
+ * 7: Not (6)
+ * 8: And (5, 7)
+ *
+ * Terms borrow from the query so parsing into a buffer that already has enough
+ * capacity does not allocate, see recycle_buffer for reusing one across requests.
  */
 
+// Bytes of query shown on either side of the error in the snippet.
+const SNIPPET_CONTEXT: usize = 40;
+
+// The parser, rewriter and executor walk the query tree recursively, these bound its depth so
+// a query from a client cannot overflow the stack. Groups and nots nest at most MAX_NESTING
+// deep and a query has at most about MAX_QUERY_NODES nodes, which also bounds the length of
+// and/or chains.
+pub const MAX_NESTING: usize = 64;
+pub const MAX_QUERY_NODES: usize = 2048;
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct QuerySyntaxError {
+    // Byte offset into the query where parsing failed.
+    pub offset: usize,
+    pub expected: &'static str,
+    // The query around the offset with a caret underneath the failing byte.
+    pub snippet: String,
+}
+
+impl QuerySyntaxError {
+    fn new(query: &[u8], offset: usize, expected: &'static str) -> QuerySyntaxError {
+        let start = offset.saturating_sub(SNIPPET_CONTEXT);
+        let end = query.len().min(offset + SNIPPET_CONTEXT);
+        let before = String::from_utf8_lossy(&query[start..offset]);
+        let line = String::from_utf8_lossy(&query[start..end]);
+        let mut snippet = String::with_capacity(line.len() * 2 + 1);
+        snippet.push_str(&line);
+        snippet.push('\n');
+        snippet.extend(std::iter::repeat_n(' ', before.chars().count()));
+        snippet.push('^');
+        return QuerySyntaxError {
+            offset,
+            expected,
+            snippet,
+        };
+    }
+}
+
+impl fmt::Display for QuerySyntaxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return write!(
+            f,
+            "expected {} at offset {}\n{}",
+            self.expected, self.offset, self.snippet
+        );
+    }
+}
+
+impl std::error::Error for QuerySyntaxError {}
+
+// Internal nom error that remembers the innermost context that failed.
+#[derive(Debug, PartialEq)]
+struct ExpectedError<'a> {
+    input: &'a [u8],
+    expected: Option<&'static str>,
+}
+
+impl<'a> ParseError<&'a [u8]> for ExpectedError<'a> {
+    #[inline]
+    fn from_error_kind(input: &'a [u8], _kind: ErrorKind) -> Self {
+        return ExpectedError {
+            input,
+            expected: None,
+        };
+    }
+
+    #[inline]
+    fn append(_input: &'a [u8], _kind: ErrorKind, other: Self) -> Self {
+        return other;
+    }
+
+    #[inline]
+    fn or(self, other: Self) -> Self {
+        // Report whichever alternative got furthest into the query.
+        if other.input.len() <= self.input.len() {
+            return other;
+        }
+        return self;
+    }
+}
+
+impl<'a> ContextError<&'a [u8]> for ExpectedError<'a> {
+    #[inline]
+    fn add_context(_input: &'a [u8], ctx: &'static str, mut other: Self) -> Self {
+        if other.expected.is_none() {
+            other.expected = Some(ctx);
+        }
+        return other;
+    }
+}
+
+type ParseResult<'a, T> = IResult<&'a [u8], T, ExpectedError<'a>>;
+
+#[inline]
+pub fn parse_query<'a>(
+    query: &'a [u8],
+    query_buffer: &mut Vec<QueryNode<'a>>,
+) -> Result<usize, QuerySyntaxError> {
+    query_buffer.clear();
+    let (rest, root) = match parse_or(query, query_buffer, 0) {
+        Ok(res) => res,
+        Err(nom::Err::Error(e) | nom::Err::Failure(e)) => {
+            return Err(QuerySyntaxError::new(
+                query,
+                query.len() - e.input.len(),
+                e.expected.unwrap_or("term"),
+            ));
+        }
+        Err(nom::Err::Incomplete(_)) => {
+            return Err(QuerySyntaxError::new(query, query.len(), "term"));
+        }
+    };
+    let rest = rest.trim_ascii_start();
+    if !rest.is_empty() {
+        return Err(QuerySyntaxError::new(
+            query,
+            query.len() - rest.len(),
+            "'and', 'or' or end of query",
+        ));
+    }
+    return Ok(root);
+}
+
+// Hands the allocation of a node buffer to a buffer of another lifetime. Pooled
+// buffers outlive the requests their terms borrow from, so they are stored with a
+// 'static lifetime and recycled for every query.
+#[inline]
+pub fn recycle_buffer<'b>(buffer: Vec<QueryNode<'_>>) -> Vec<QueryNode<'b>> {
+    let mut buffer = std::mem::ManuallyDrop::new(buffer);
+    buffer.clear();
+    let (ptr, capacity) = (buffer.as_mut_ptr(), buffer.capacity());
+    // Safety: the buffer is empty, so no node borrowing for the old lifetime can be reached
+    // through the new one. Lifetimes do not change the layout, so the allocation was made for
+    // QueryNode<'b> with this size and alignment, and it is handed over whole with its capacity.
+    return unsafe { Vec::from_raw_parts(ptr.cast::<QueryNode<'b>>(), 0, capacity) };
+}
+
+#[inline]
+pub(crate) fn push_node<'a>(
+    nodes: &mut Vec<QueryNode<'a>>,
+    node_type: NodeType,
+    term: Term<'a>,
+    left: Option<usize>,
+    right: Option<usize>,
+) -> usize {
+    nodes.push(QueryNode {
+        node_type,
+        term,
+        left,
+        right,
+    });
+    return nodes.len() - 1;
+}
+
+#[inline]
+fn is_ident_byte(i: u8) -> bool {
+    return i.is_ascii_alphanumeric() || matches!(i, b'_' | b'.' | b'-');
+}
+
+const DEFAULT_FUZZY_DISTANCE: u8 = 2;
+
+#[inline]
+fn is_value_byte(i: u8) -> bool {
+    return !i.is_ascii_whitespace()
+        && !matches!(
+            i,
+            b'(' | b')' | b'"' | b':' | b'~' | b'^' | b'[' | b']' | b'{' | b'}'
+        );
+}
+
+#[inline]
+fn is_range_byte(i: u8) -> bool {
+    return !i.is_ascii_whitespace() && !matches!(i, b']' | b'}');
+}
+
+// Checks the value as written, escaped bytes do not count.
+#[inline]
+fn is_wildcard(value: &[u8]) -> bool {
+    let mut escaped = false;
+    for &i in value {
+        if !escaped && (i == b'*' || i == b'?') {
+            return true;
+        }
+        escaped = !escaped && i == b'\\';
+    }
+    return false;
+}
+
+// Only invalid UTF-8 allocates, it is replaced the same way the rest of the request is.
+#[inline]
+fn to_str(value: &[u8]) -> Cow<'_, str> {
+    return String::from_utf8_lossy(value);
+}
+
+// The bytes of a value as written: bytes for which `is_plain` holds and bytes escaped with a
+// backslash.
+#[inline]
+fn escaped<'a>(is_plain: impl Fn(u8) -> bool + Copy) -> impl FnMut(&'a [u8]) -> ParseResult<'a, &'a [u8]> {
+    return recognize(many0_count(alt((
+        take_while1(move |i| i != b'\\' && is_plain(i)),
+        preceded(char('\\'), take(1usize)),
+    ))));
+}
 
-// pub fn parse_query(query: &[u8], query_buffer: &mut [QueryNode]) -> Option<NodeType> {
-// }
+// Only values with escapes allocate.
+#[inline]
+fn unescape(value: &[u8]) -> Cow<'_, str> {
+    if !value.contains(&b'\\') {
+        return to_str(value);
+    }
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut escaped = false;
+    for &i in value {
+        if !escaped && i == b'\\' {
+            escaped = true;
+            continue;
+        }
+        escaped = false;
+        bytes.push(i);
+    }
+    return Cow::Owned(String::from_utf8_lossy(&bytes).into_owned());
+}
+
+#[inline]
+fn keyword<'a>(kw: &'static str) -> impl FnMut(&'a [u8]) -> ParseResult<'a, &'a [u8]> {
+    // A keyword followed by ':' is a field name rather than an operator.
+    return terminated(
+        verify(take_while1(is_ident_byte), move |word: &[u8]| {
+            word.eq_ignore_ascii_case(kw.as_bytes())
+        }),
+        not(char(':')),
+    );
+}
+
+// `depth` counts the groups and nots around the input.
+fn parse_or<'a>(input: &'a [u8], nodes: &mut Vec<QueryNode<'a>>, depth: usize) -> ParseResult<'a, usize> {
+    let (mut input, mut left) = parse_and(input, nodes, depth)?;
+    while let Ok((rest, _)) = preceded(multispace0, keyword("or"))(input) {
+        let (rest, right) = parse_and(rest, nodes, depth)?;
+        left = push_node(nodes, NodeType::Or, Term::default(), Some(left), Some(right));
+        input = rest;
+    }
+    return Ok((input, left));
+}
+
+fn parse_and<'a>(input: &'a [u8], nodes: &mut Vec<QueryNode<'a>>, depth: usize) -> ParseResult<'a, usize> {
+    let (mut input, mut left) = parse_not(input, nodes, depth)?;
+    while let Ok((rest, _)) = preceded(multispace0, keyword("and"))(input) {
+        let (rest, right) = parse_not(rest, nodes, depth)?;
+        left = push_node(nodes, NodeType::And, Term::default(), Some(left), Some(right));
+        input = rest;
+    }
+    return Ok((input, left));
+}
+
+fn parse_not<'a>(input: &'a [u8], nodes: &mut Vec<QueryNode<'a>>, depth: usize) -> ParseResult<'a, usize> {
+    let (input, _) = multispace0(input)?;
+    if let Ok((rest, _)) = keyword("not")(input) {
+        if depth >= MAX_NESTING {
+            return Err(too_large(input, "fewer nested groups and nots"));
+        }
+        let (rest, child) = parse_not(rest, nodes, depth + 1)?;
+        let idx = push_node(nodes, NodeType::Not, Term::default(), Some(child), None);
+        return Ok((rest, idx));
+    }
+    return parse_primary(input, nodes, depth);
+}
+
+fn parse_primary<'a>(input: &'a [u8], nodes: &mut Vec<QueryNode<'a>>, depth: usize) -> ParseResult<'a, usize> {
+    if let Ok((rest, _)) = char::<&[u8], ExpectedError>('(')(input) {
+        if depth >= MAX_NESTING {
+            return Err(too_large(input, "fewer nested groups and nots"));
+        }
+        let (rest, child) = parse_or(rest, nodes, depth + 1)?;
+        let (rest, _) = preceded(multispace0, context("')'", tag(")")))(rest)?;
+        let idx = push_node(nodes, NodeType::Group, Term::default(), Some(child), None);
+        return Ok((rest, idx));
+    }
+    // Checked per term with room for the term and the operator joining it, the operators
+    // above one add at most the nesting to the nodes.
+    if nodes.len() + 2 > MAX_QUERY_NODES {
+        return Err(too_large(input, "fewer terms"));
+    }
+    let (rest, term) = context("term", parse_term)(input)?;
+    let idx = push_node(nodes, NodeType::Term, term, None, None);
+    return Ok((rest, idx));
+}
+
+#[inline]
+fn too_large<'a>(input: &'a [u8], expected: &'static str) -> nom::Err<ExpectedError<'a>> {
+    return nom::Err::Failure(ExpectedError {
+        input,
+        expected: Some(expected),
+    });
+}
+
+#[inline]
+fn parse_term(input: &[u8]) -> ParseResult<'_, Term<'_>> {
+    // Terms without a field are expanded to the collection's default fields later.
+    let (rest, field) = opt(terminated(take_while1(is_ident_byte), char(':')))(input)?;
+    let (rest, (term_type, value)) = match field {
+        Some(_) => context("term value", parse_term_value)(rest)?,
+        None => parse_term_value(rest)?,
+    };
+    let (rest, boost) = opt(preceded(
+        char('^'),
+        cut(context("finite boost", verify(float, |boost: &f32| boost.is_finite()))),
+    ))(rest)?;
+    let term_type = match (term_type, boost) {
+        (TermType::Word, Some(_)) => TermType::Boosted,
+        (term_type, _) => term_type,
+    };
+    return Ok((
+        rest,
+        Term {
+            term_type,
+            field: to_str(field.unwrap_or_default()),
+            value,
+            term_boost: boost.unwrap_or(1.0),
+        },
+    ));
+}
+
+#[inline]
+fn parse_term_value(input: &[u8]) -> ParseResult<'_, (TermType<'_>, Cow<'_, str>)> {
+    return alt((parse_phrase, parse_geo_box, parse_range, parse_geo_distance, parse_word))(input);
+}
+
+#[inline]
+fn parse_phrase(input: &[u8]) -> ParseResult<'_, (TermType<'_>, Cow<'_, str>)> {
+    let (rest, phrase) = delimited(
+        char('"'),
+        cut(context(
+            "phrase text",
+            verify(escaped(|i| i != b'"'), |p: &[u8]| !p.iter().all(u8::is_ascii_whitespace)),
+        )),
+        cut(context("closing '\"'", char('"'))),
+    )(input)?;
+    let (rest, slop) = opt(preceded(char('~'), cut(context("proximity distance", parse_u32))))(rest)?;
+    let term_type = match slop {
+        Some(slop) => TermType::Proximity(slop),
+        None => TermType::Phrase,
+    };
+    return Ok((rest, (term_type, unescape(phrase))));
+}
+
+#[inline]
+fn parse_range_bound(input: &[u8]) -> ParseResult<'_, Option<&[u8]>> {
+    return alt((
+        map(terminated(char('*'), not(take_while1(is_range_byte))), |_| None),
+        map(verify(escaped(is_range_byte), |v: &[u8]| !v.is_empty()), Some),
+    ))(input);
+}
+
+#[inline]
+fn parse_range(input: &[u8]) -> ParseResult<'_, (TermType<'_>, Cow<'_, str>)> {
+    let (rest, open) = alt((char('['), char('{')))(input)?;
+    let (rest, (_, lower, _, _, _, upper, _, close)) = cut(tuple((
+        multispace0,
+        context("range lower bound", parse_range_bound),
+        multispace1,
+        context("'TO'", tag_no_case("to")),
+        multispace1,
+        context("range upper bound", parse_range_bound),
+        multispace0,
+        context("']' or '}'", alt((char(']'), char('}')))),
+    )))(rest)?;
+    let lower = match (lower, open) {
+        (None, _) => Bound::Unbounded,
+        (Some(v), '[') => Bound::Included(unescape(v)),
+        (Some(v), _) => Bound::Excluded(unescape(v)),
+    };
+    let upper = match (upper, close) {
+        (None, _) => Bound::Unbounded,
+        (Some(v), ']') => Bound::Included(unescape(v)),
+        (Some(v), _) => Bound::Excluded(unescape(v)),
+    };
+    return Ok((rest, (TermType::Range(lower, upper), Cow::Borrowed(""))));
+}
+
+#[inline]
+fn parse_geo_point(input: &[u8]) -> ParseResult<'_, GeoPoint> {
+    let (rest, (lat, _, lon)) = tuple((double, char(','), double))(input)?;
+    return Ok((rest, GeoPoint { lat, lon }));
+}
+
+// [lat,lon TO lat,lon], a range whose bounds are both points.
+#[inline]
+fn parse_geo_box(input: &[u8]) -> ParseResult<'_, (TermType<'_>, Cow<'_, str>)> {
+    let (rest, (_, _, lower, _, _, _, upper, _, _)) = tuple((
+        char('['),
+        multispace0,
+        parse_geo_point,
+        multispace1,
+        tag_no_case("to"),
+        multispace1,
+        parse_geo_point,
+        multispace0,
+        char(']'),
+    ))(input)?;
+    return Ok((rest, (TermType::GeoBox(lower, upper), Cow::Borrowed(""))));
+}
+
+// lat,lon~distance with a unit of m, km or mi. Without the unit it backtracks, al:1,2~1 is a
+// fuzzy word.
+#[inline]
+fn parse_geo_distance(input: &[u8]) -> ParseResult<'_, (TermType<'_>, Cow<'_, str>)> {
+    let (rest, (center, _, distance, unit)) = tuple((
+        parse_geo_point,
+        char('~'),
+        double,
+        alt((tag("km"), tag("mi"), tag("m"))),
+    ))(input)?;
+    let meters = match unit {
+        b"km" => distance * 1000.0,
+        b"mi" => distance * 1609.344,
+        _ => distance,
+    };
+    return Ok((rest, (TermType::GeoDistance(center, meters), Cow::Borrowed(""))));
+}
+
+#[inline]
+fn parse_word(input: &[u8]) -> ParseResult<'_, (TermType<'_>, Cow<'_, str>)> {
+    let (rest, word) = verify(escaped(is_value_byte), |w: &[u8]| !w.is_empty())(input)?;
+    if is_wildcard(word) {
+        return Ok((rest, (TermType::Wildcard, unescape(word))));
+    }
+    let (rest, fuzzy) = opt(preceded(char('~'), opt(parse_u8)))(rest)?;
+    let term_type = match fuzzy {
+        Some(distance) => TermType::Fuzzy(distance.unwrap_or(DEFAULT_FUZZY_DISTANCE)),
+        None => TermType::Word,
+    };
+    return Ok((rest, (term_type, unescape(word))));
+}
+
+// Writes the value with a backslash before every byte the parser would not take literally.
+fn write_escaped(f: &mut fmt::Formatter<'_>, value: &str, is_special: impl Fn(u8) -> bool) -> fmt::Result {
+    let mut start = 0;
+    for (i, c) in value.char_indices() {
+        if c.is_ascii() && (c == '\\' || is_special(c as u8)) {
+            f.write_str(&value[start..i])?;
+            f.write_str("\\")?;
+            start = i;
+        }
+    }
+    return f.write_str(&value[start..]);
+}
+
+#[inline]
+fn write_word(f: &mut fmt::Formatter<'_>, term: &Term<'_>) -> fmt::Result {
+    // Without a field an operator word would be read as the operator.
+    let is_keyword = ["and", "or", "not"].iter().any(|kw| term.value.eq_ignore_ascii_case(kw));
+    if term.field.is_empty() && is_keyword {
+        f.write_str("\\")?;
+    }
+    let wildcard = term.term_type == TermType::Wildcard;
+    return write_escaped(f, &term.value, |i| !is_value_byte(i) || (!wildcard && (i == b'*' || i == b'?')));
+}
+
+#[inline]
+fn write_range_bound(f: &mut fmt::Formatter<'_>, value: &str) -> fmt::Result {
+    if value == "*" {
+        return f.write_str("\\*");
+    }
+    return write_escaped(f, value, |i| !is_range_byte(i));
+}
+
+impl fmt::Display for Term<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !self.field.is_empty() {
+            write!(f, "{}:", self.field)?;
+        }
+        match &self.term_type {
+            TermType::Word | TermType::Wildcard | TermType::Boosted => write_word(f, self)?,
+            TermType::Phrase | TermType::Proximity(_) => {
+                f.write_str("\"")?;
+                write_escaped(f, &self.value, |i| i == b'"')?;
+                f.write_str("\"")?;
+                if let TermType::Proximity(slop) = self.term_type {
+                    write!(f, "~{}", slop)?;
+                }
+            }
+            TermType::Fuzzy(distance) => {
+                write_word(f, self)?;
+                write!(f, "~{}", distance)?;
+            }
+            TermType::Range(lower, upper) => {
+                match lower {
+                    Bound::Included(v) => {
+                        f.write_str("[")?;
+                        write_range_bound(f, v)?;
+                    }
+                    Bound::Excluded(v) => {
+                        f.write_str("{")?;
+                        write_range_bound(f, v)?;
+                    }
+                    Bound::Unbounded => write!(f, "[*")?,
+                }
+                f.write_str(" TO ")?;
+                match upper {
+                    Bound::Included(v) => {
+                        write_range_bound(f, v)?;
+                        f.write_str("]")?;
+                    }
+                    Bound::Excluded(v) => {
+                        write_range_bound(f, v)?;
+                        f.write_str("}")?;
+                    }
+                    Bound::Unbounded => write!(f, "*]")?,
+                }
+            }
+            TermType::GeoBox(lower, upper) => write!(f, "[{} TO {}]", lower, upper)?,
+            TermType::GeoDistance(center, meters) => write!(f, "{}~{}m", center, meters)?,
+        }
+        // An explicit boost of 1 is kept so a Boosted term stays Boosted.
+        if self.term_type == TermType::Boosted || self.term_boost != 1.0 {
+            write!(f, "^{}", self.term_boost)?;
+        }
+        return Ok(());
+    }
+}
+
+impl fmt::Display for GeoPoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return write!(f, "{},{}", self.lat, self.lon);
+    }
+}
+
+// Prints a query buffer back to canonical query text that parses to the same tree.
+pub struct QueryDisplay<'a> {
+    pub nodes: &'a [QueryNode<'a>],
+    pub root: usize,
+}
+
+impl QueryDisplay<'_> {
+    // Binding strength of a node, a child binding looser than its parent needs parenthesis.
+    #[inline]
+    fn precedence(&self, idx: usize) -> u8 {
+        return match self.nodes[idx].node_type {
+            NodeType::Or => 0,
+            NodeType::And => 1,
+            NodeType::Not => 2,
+            NodeType::Group | NodeType::Term => 3,
+        };
+    }
+
+    fn write_child(&self, f: &mut fmt::Formatter<'_>, idx: usize, parent: u8) -> fmt::Result {
+        if self.precedence(idx) < parent {
+            write!(f, "(")?;
+            self.write_node(f, idx)?;
+            return write!(f, ")");
+        }
+        return self.write_node(f, idx);
+    }
+
+    fn write_node(&self, f: &mut fmt::Formatter<'_>, idx: usize) -> fmt::Result {
+        let node = &self.nodes[idx];
+        let precedence = self.precedence(idx);
+        return match node.node_type {
+            NodeType::Term => write!(f, "{}", node.term),
+            NodeType::Group => {
+                write!(f, "(")?;
+                self.write_node(f, node.left.unwrap())?;
+                write!(f, ")")
+            }
+            NodeType::Not => {
+                write!(f, "not ")?;
+                self.write_child(f, node.left.unwrap(), precedence)
+            }
+            NodeType::And | NodeType::Or => {
+                let op = match node.node_type {
+                    NodeType::And => " and ",
+                    _ => " or ",
+                };
+                self.write_child(f, node.left.unwrap(), precedence)?;
+                write!(f, "{}", op)?;
+                // Operators are left associative, an equal operator on the right needs parenthesis.
+                self.write_child(f, node.right.unwrap(), precedence + 1)
+            }
+        };
+    }
+}
+
+impl fmt::Display for QueryDisplay<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return self.write_node(f, self.root);
+    }
+}
+
+#[inline]
+pub fn to_query_string(nodes: &[QueryNode<'_>], root: usize) -> String {
+    return QueryDisplay { nodes, root }.to_string();
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn word<'a>(field: &'a str, value: &'a str) -> QueryNode<'a> {
+        return QueryNode {
+            node_type: NodeType::Term,
+            term: Term {
+                term_type: TermType::Word,
+                field: field.into(),
+                value: value.into(),
+                term_boost: 1.0,
+            },
+            left: None,
+            right: None,
+        };
+    }
+
+    fn op(node_type: NodeType, left: usize, right: Option<usize>) -> QueryNode<'static> {
+        return QueryNode {
+            node_type,
+            term: Term::default(),
+            left: Some(left),
+            right,
+        };
+    }
+
+    #[test]
+    fn test_parse_single_term() {
+        let mut nodes = Vec::new();
+        assert_eq!(parse_query(b"su:dog", &mut nodes), Ok(0));
+        assert_eq!(nodes, vec![word("su", "dog")]);
+    }
+
+    #[test]
+    fn test_parse_example_query() {
+        let mut nodes = Vec::new();
+        let root = parse_query(b"al:dog and (al:cat or al:mouse) and not al:bird", &mut nodes);
+        assert_eq!(root, Ok(8));
+        assert_eq!(
+            nodes,
+            vec![
+                word("al", "dog"),
+                word("al", "cat"),
+                word("al", "mouse"),
+                op(NodeType::Or, 1, Some(2)),
+                op(NodeType::Group, 3, None),
+                op(NodeType::And, 0, Some(4)),
+                word("al", "bird"),
+                op(NodeType::Not, 6, None),
+                op(NodeType::And, 5, Some(7)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_and_binds_tighter_than_or() {
+        let mut nodes = Vec::new();
+        let root = parse_query(b"a:1 or b:2 AND c:3", &mut nodes);
+        assert_eq!(root, Ok(4));
+        assert_eq!(
+            nodes,
+            vec![
+                word("a", "1"),
+                word("b", "2"),
+                word("c", "3"),
+                op(NodeType::And, 1, Some(2)),
+                op(NodeType::Or, 0, Some(3)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_not_binds_tightest() {
+        let mut nodes = Vec::new();
+        let root = parse_query(b"not a:1 and not not b:2", &mut nodes);
+        assert_eq!(root, Ok(5));
+        assert_eq!(
+            nodes,
+            vec![
+                word("a", "1"),
+                op(NodeType::Not, 0, None),
+                word("b", "2"),
+                op(NodeType::Not, 2, None),
+                op(NodeType::Not, 3, None),
+                op(NodeType::And, 1, Some(4)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_nested_groups() {
+        let mut nodes = Vec::new();
+        let root = parse_query(b"((a:1 or b:2) and(c:3))or not(d:4)", &mut nodes);
+        assert_eq!(root, Ok(11));
+        assert_eq!(
+            nodes,
+            vec![
+                word("a", "1"),
+                word("b", "2"),
+                op(NodeType::Or, 0, Some(1)),
+                op(NodeType::Group, 2, None),
+                word("c", "3"),
+                op(NodeType::Group, 4, None),
+                op(NodeType::And, 3, Some(5)),
+                op(NodeType::Group, 6, None),
+                word("d", "4"),
+                op(NodeType::Group, 8, None),
+                op(NodeType::Not, 9, None),
+                op(NodeType::Or, 7, Some(10)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_bare_terms() {
+        let mut nodes = Vec::new();
+        let root = parse_query(b"dog and not \"quick fox\"^2 or al:cat", &mut nodes);
+        assert_eq!(root, Ok(5));
+        assert_eq!(
+            nodes,
+            vec![
+                word("", "dog"),
+                term("", "quick fox", TermType::Phrase, 2.0),
+                op(NodeType::Not, 1, None),
+                op(NodeType::And, 0, Some(2)),
+                word("al", "cat"),
+                op(NodeType::Or, 3, Some(4)),
+            ]
+        );
+        assert_eq!(to_query_string(&nodes, 5), "dog and not \"quick fox\"^2 or al:cat");
+    }
+
+    #[test]
+    fn test_keyword_as_field() {
+        let mut nodes = Vec::new();
+        assert_eq!(parse_query(b"not:dog or and:cat", &mut nodes), Ok(2));
+        assert_eq!(
+            nodes,
+            vec![word("not", "dog"), word("and", "cat"), op(NodeType::Or, 0, Some(1))]
+        );
+    }
+
+    fn term<'a>(field: &'a str, value: &'a str, term_type: TermType<'a>, term_boost: f32) -> QueryNode<'a> {
+        let mut node = word(field, value);
+        node.term.term_type = term_type;
+        node.term.term_boost = term_boost;
+        return node;
+    }
+
+    fn parse_single(query: &[u8]) -> QueryNode<'_> {
+        let mut nodes = Vec::new();
+        assert_eq!(parse_query(query, &mut nodes), Ok(0));
+        return nodes.pop().unwrap();
+    }
+
+    #[test]
+    fn test_parse_phrase() {
+        assert_eq!(
+            parse_single(b"al:\"quick fox\""),
+            term("al", "quick fox", TermType::Phrase, 1.0)
+        );
+        assert_eq!(
+            parse_single(b"al:\"quick fox\"~5"),
+            term("al", "quick fox", TermType::Proximity(5), 1.0)
+        );
+        assert_eq!(
+            parse_single(b"al:\"quick (fox) and\"^2"),
+            term("al", "quick (fox) and", TermType::Phrase, 2.0)
+        );
+    }
+
+    #[test]
+    fn test_parse_wildcard_and_fuzzy() {
+        assert_eq!(parse_single(b"al:fo*"), term("al", "fo*", TermType::Wildcard, 1.0));
+        assert_eq!(parse_single(b"al:f?x"), term("al", "f?x", TermType::Wildcard, 1.0));
+        assert_eq!(parse_single(b"al:fox~2"), term("al", "fox", TermType::Fuzzy(2), 1.0));
+        assert_eq!(parse_single(b"al:fox~1"), term("al", "fox", TermType::Fuzzy(1), 1.0));
+        assert_eq!(parse_single(b"al:fox~"), term("al", "fox", TermType::Fuzzy(2), 1.0));
+    }
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(
+            parse_single(b"price:[10 TO 20}"),
+            term(
+                "price",
+                "",
+                TermType::Range(Bound::Included("10".into()), Bound::Excluded("20".into())),
+                1.0
+            )
+        );
+        assert_eq!(
+            parse_single(b"price:{ * to 2.5 ]"),
+            term(
+                "price",
+                "",
+                TermType::Range(Bound::Unbounded, Bound::Included("2.5".into())),
+                1.0
+            )
+        );
+        assert_eq!(
+            parse_single(b"date:[2020-01-01 TO *]"),
+            term(
+                "date",
+                "",
+                TermType::Range(Bound::Included("2020-01-01".into()), Bound::Unbounded),
+                1.0
+            )
+        );
+    }
+
+    #[test]
+    fn test_parse_boost() {
+        assert_eq!(parse_single(b"title:fox^3"), term("title", "fox", TermType::Boosted, 3.0));
+        assert_eq!(parse_single(b"title:fox^0.5"), term("title", "fox", TermType::Boosted, 0.5));
+        assert_eq!(parse_single(b"title:fo*^2"), term("title", "fo*", TermType::Wildcard, 2.0));
+        assert_eq!(parse_single(b"title:fox~1^2"), term("title", "fox", TermType::Fuzzy(1), 2.0));
+        assert_eq!(parse_single(b"title:fox^-3"), term("title", "fox", TermType::Boosted, -3.0));
+    }
+
+    #[test]
+    fn test_parse_mixed_terms() {
+        let mut nodes = Vec::new();
+        let root = parse_query(b"(title:\"quick fox\"~2 or title:fo*) and not price:[* TO 10]", &mut nodes);
+        assert_eq!(root, Ok(6));
+        assert_eq!(nodes[0].term.term_type, TermType::Proximity(2));
+        assert_eq!(nodes[1].term.term_type, TermType::Wildcard);
+        assert_eq!(
+            nodes[4].term.term_type,
+            TermType::Range(Bound::Unbounded, Bound::Included("10".into()))
+        );
+    }
+
+    fn assert_round_trip(query: &str, canonical: &str) {
+        let mut nodes = Vec::new();
+        let root = parse_query(query.as_bytes(), &mut nodes).unwrap();
+        let printed = to_query_string(&nodes, root);
+        assert_eq!(printed, canonical);
+        let mut reparsed = Vec::new();
+        assert_eq!(parse_query(printed.as_bytes(), &mut reparsed), Ok(root));
+        assert_eq!(reparsed, nodes);
+    }
+
+    #[test]
+    fn test_round_trip_term_types() {
+        assert_round_trip("al:dog", "al:dog");
+        assert_round_trip("al:\"quick fox\"", "al:\"quick fox\"");
+        assert_round_trip("al:fo*", "al:fo*");
+        assert_round_trip("al:fox~", "al:fox~2");
+        assert_round_trip("al:\"quick fox\"~5", "al:\"quick fox\"~5");
+        assert_round_trip("price:[10 to 20}", "price:[10 TO 20}");
+        assert_round_trip("price:{10 TO *]", "price:{10 TO *]");
+        assert_round_trip("price:{* TO 20}", "price:[* TO 20}");
+        assert_round_trip("title:fox^3", "title:fox^3");
+        assert_round_trip("title:fox^1", "title:fox^1");
+        assert_round_trip("title:fox~1^0.5", "title:fox~1^0.5");
+        assert_round_trip("title:\"quick fox\"^2", "title:\"quick fox\"^2");
+    }
+
+    #[test]
+    fn test_parse_geo() {
+        let point = |lat, lon| GeoPoint { lat, lon };
+        assert_eq!(
+            parse_single(b"loc:[52.3,4.85 to 52.4,4.95]"),
+            term("loc", "", TermType::GeoBox(point(52.3, 4.85), point(52.4, 4.95)), 1.0)
+        );
+        assert_eq!(
+            parse_single(b"loc:-33.9,151.2~2.5km"),
+            term("loc", "", TermType::GeoDistance(point(-33.9, 151.2), 2500.0), 1.0)
+        );
+        assert_eq!(
+            parse_single(b"loc:40,-74~1mi"),
+            term("loc", "", TermType::GeoDistance(point(40.0, -74.0), 1609.344), 1.0)
+        );
+        assert_eq!(parse_single(b"al:12,5"), term("al", "12,5", TermType::Word, 1.0));
+        assert_round_trip("loc:[52.3,4.85 TO 52.4,4.95]", "loc:[52.3,4.85 TO 52.4,4.95]");
+        assert_round_trip("loc:52.3,4.85~300m and loc:1,2~1km", "loc:52.3,4.85~300m and loc:1,2~1000m");
+
+        // Without a unit the value is a fuzzy word, validation rejects it on a geo field.
+        assert_eq!(parse_single(b"al:1,2~1"), term("al", "1,2", TermType::Fuzzy(1), 1.0));
+        assert_eq!(parse_single(b"loc:52.3,4.85~30"), term("loc", "52.3,4.85", TermType::Fuzzy(30), 1.0));
+        assert_eq!(parse_single(b"al:a,b~1"), term("al", "a,b", TermType::Fuzzy(1), 1.0));
+    }
+
+    #[test]
+    fn test_parse_escapes() {
+        assert_eq!(parse_single(br"al:ice\ cream"), term("al", "ice cream", TermType::Word, 1.0));
+        assert_eq!(parse_single(br"al:fo\*"), term("al", "fo*", TermType::Word, 1.0));
+        assert_eq!(parse_single(br"al:f\?x*"), term("al", "f?x*", TermType::Wildcard, 1.0));
+        assert_eq!(parse_single(br"al:a\:b\\~1"), term("al", r"a:b\", TermType::Fuzzy(1), 1.0));
+        assert_eq!(parse_single(br#"al:"say \"hi\"""#), term("al", "say \"hi\"", TermType::Phrase, 1.0));
+        assert_eq!(
+            parse_single(br"x:[\* TO a\ b]"),
+            term(
+                "x",
+                "",
+                TermType::Range(Bound::Included("*".into()), Bound::Included("a b".into())),
+                1.0
+            )
+        );
+        assert_eq!(parse_single(br"\not"), term("", "not", TermType::Word, 1.0));
+        let mut nodes = Vec::new();
+        assert!(parse_query(br"al:fox\", &mut nodes).is_err());
+    }
+
+    fn assert_prints_back(node: QueryNode<'_>) {
+        let printed = to_query_string(std::slice::from_ref(&node), 0);
+        let mut reparsed = Vec::new();
+        assert_eq!(parse_query(printed.as_bytes(), &mut reparsed), Ok(0), "{}", printed);
+        assert_eq!(reparsed, vec![node], "{}", printed);
+    }
+
+    #[test]
+    fn test_round_trip_special_values() {
+        let values = ["ice cream", "a:b", "fo*", "f?x", "(x)", "a~1", "say \"hi\"", r"back\slash", "x^2", "[1", "é:ü"];
+        for value in values {
+            assert_prints_back(word("al", value));
+            assert_prints_back(term("al", value, TermType::Boosted, 2.0));
+            assert_prints_back(term("al", value, TermType::Fuzzy(1), 1.0));
+            assert_prints_back(term("al", value, TermType::Phrase, 1.0));
+            assert_prints_back(term("al", value, TermType::Proximity(3), 0.5));
+            let range = TermType::Range(Bound::Excluded(value.into()), Bound::Included("*".into()));
+            assert_prints_back(term("al", "", range, 1.0));
+        }
+        // Without a field operator words are escaped.
+        for value in ["not", "AND", "or"] {
+            assert_prints_back(word("", value));
+        }
+        assert_round_trip(r"al:ice\ cream and al:fo\*^2", r"al:ice\ cream and al:fo\*^2");
+        assert_round_trip(r#"al:"say \"hi\""~2"#, r#"al:"say \"hi\""~2"#);
+    }
+
+    #[test]
+    fn test_round_trip_operators() {
+        assert_round_trip(
+            "al:dog AND (al:cat  OR al:mouse) and NOT al:bird",
+            "al:dog and (al:cat or al:mouse) and not al:bird",
+        );
+        assert_round_trip("not not (a:1)", "not not (a:1)");
+        assert_round_trip("((a:1 or b:2) and(c:3))or not(d:4)", "((a:1 or b:2) and (c:3)) or not (d:4)");
+    }
+
+    #[test]
+    fn test_print_adds_minimal_parenthesis() {
+        // Trees without Group nodes, as produced by the rewriter.
+        let nodes = vec![
+            word("a", "1"),
+            word("b", "2"),
+            op(NodeType::Or, 0, Some(1)),
+            word("c", "3"),
+            op(NodeType::And, 2, Some(3)),
+            op(NodeType::Not, 4, None),
+            word("d", "4"),
+            word("e", "5"),
+            op(NodeType::Or, 6, Some(7)),
+            op(NodeType::Or, 5, Some(8)),
+        ];
+        assert_eq!(to_query_string(&nodes, 4), "(a:1 or b:2) and c:3");
+        assert_eq!(to_query_string(&nodes, 9), "not ((a:1 or b:2) and c:3) or (d:4 or e:5)");
+    }
+
+    fn parse_error(query: &[u8]) -> (usize, &'static str) {
+        let mut nodes = Vec::new();
+        let err = parse_query(query, &mut nodes).unwrap_err();
+        return (err.offset, err.expected);
+    }
+
+    #[test]
+    fn test_error_positions() {
+        assert_eq!(parse_error(b""), (0, "term"));
+        assert_eq!(parse_error(b"al:dog and "), (11, "term"));
+        assert_eq!(parse_error(b"(al:dog or al:cat"), (17, "')'"));
+        assert_eq!(parse_error(b"al:dog)"), (6, "'and', 'or' or end of query"));
+        assert_eq!(parse_error(b"al:dog al:cat"), (7, "'and', 'or' or end of query"));
+        assert_eq!(parse_error(b":dog"), (0, "term"));
+        assert_eq!(parse_error(b"al: dog"), (3, "term value"));
+        assert_eq!(parse_error(b"al:\"quick fox"), (13, "closing '\"'"));
+        assert_eq!(parse_error(b"al:\"quick fox\"~x"), (15, "proximity distance"));
+        assert_eq!(parse_error(b"price:[10 20]"), (10, "'TO'"));
+        assert_eq!(parse_error(b"price:[10 TO 20"), (15, "']' or '}'"));
+        assert_eq!(parse_error(b"al:fox^"), (7, "finite boost"));
+        assert_eq!(parse_error(b"al:fox^nan"), (7, "finite boost"));
+        assert_eq!(parse_error(b"al:fox^inf or al:cat"), (7, "finite boost"));
+        assert_eq!(parse_error(b"al:fox^1e39"), (7, "finite boost"));
+    }
+
+    #[test]
+    fn test_query_size_limits() {
+        let nested = |depth: usize| "(".repeat(depth) + "al:dog" + &")".repeat(depth);
+        let chain = |terms: usize| vec!["al:dog"; terms].join(" or ");
+        let (deepest, longest) = (nested(MAX_NESTING), chain(MAX_QUERY_NODES / 2));
+        let mut nodes = Vec::new();
+        assert!(parse_query(deepest.as_bytes(), &mut nodes).is_ok());
+        assert_eq!(parse_error(nested(MAX_NESTING + 1).as_bytes()), (MAX_NESTING, "fewer nested groups and nots"));
+        assert_eq!(parse_error(&b"(".repeat(200_000)), (MAX_NESTING, "fewer nested groups and nots"));
+        let nots = "not ".repeat(MAX_NESTING + 1) + "al:dog";
+        assert_eq!(parse_error(nots.as_bytes()), (MAX_NESTING * 4, "fewer nested groups and nots"));
+
+        let root = parse_query(longest.as_bytes(), &mut nodes).unwrap();
+        assert_eq!(root, MAX_QUERY_NODES - 2);
+        let too_long = chain(MAX_QUERY_NODES / 2 + 1);
+        assert_eq!(parse_error(too_long.as_bytes()), (too_long.len() - 6, "fewer terms"));
+    }
+
+    #[test]
+    fn test_error_snippet() {
+        let mut nodes = Vec::new();
+        let err = parse_query(b"al:dog and (al:cat or)", &mut nodes).unwrap_err();
+        assert_eq!(err.offset, 21);
+        assert_eq!(err.snippet, "al:dog and (al:cat or)\n                     ^");
+        assert_eq!(
+            err.to_string(),
+            "expected term at offset 21\nal:dog and (al:cat or)\n                     ^"
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let mut nodes = Vec::new();
+        assert!(parse_query(b"", &mut nodes).is_err());
+        assert!(parse_query(b"al:dog and", &mut nodes).is_err());
+        assert!(parse_query(b"(al:dog or al:cat", &mut nodes).is_err());
+        assert!(parse_query(b"al:dog)", &mut nodes).is_err());
+        assert!(parse_query(b"al: dog", &mut nodes).is_err());
+        assert!(parse_query(b":dog", &mut nodes).is_err());
+        assert!(parse_query(b"al:\"quick fox", &mut nodes).is_err());
+        assert!(parse_query(b"al:\"\"", &mut nodes).is_err());
+        assert!(parse_query(b"price:[10 20]", &mut nodes).is_err());
+        assert!(parse_query(b"price:[10 TO 20", &mut nodes).is_err());
+        assert!(parse_query(b"al:fox^", &mut nodes).is_err());
+        assert!(parse_query(b"al:fo*~2", &mut nodes).is_err());
+    }
+
+    #[test]
+    fn test_recycle_buffer_keeps_allocation() {
+        let mut buffer: Vec<QueryNode<'static>> = Vec::new();
+        {
+            let query = String::from("al:fox and (al:dog or not al:cat)");
+            let mut nodes = recycle_buffer(buffer);
+            parse_query(query.as_bytes(), &mut nodes).unwrap();
+            assert_eq!(nodes[0].term.value, "fox");
+            buffer = recycle_buffer(nodes);
+        }
+        let (ptr, capacity) = (buffer.as_ptr(), buffer.capacity());
+        assert!(buffer.is_empty() && capacity >= 6);
+
+        let mut nodes = recycle_buffer(buffer);
+        parse_query(b"al:fox", &mut nodes).unwrap();
+        assert_eq!((nodes.as_ptr() as *const u8, nodes.capacity()), (ptr as *const u8, capacity));
+    }
+}
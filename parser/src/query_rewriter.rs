@@ -0,0 +1,506 @@
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::ops::Bound;
+
+use crate::query_parser::{push_node, GeoPoint, NodeType, QueryNode, Term, TermType};
+
+/*
+ * Rewrites a parsed query into a canonical form before execution:
+ *
+ * - Groups are dropped, the tree shape already encodes them.
+ * - Not is pushed down to the terms with De Morgan, double negation cancels.
+ * - Nested And/Or are flattened, sorted and deduplicated, then rebuilt as a
+ *   left deep chain so equal queries produce identical buffers.
+ * - a and not a can never match, a or not a always matches.
+ *
+ * The output buffer uses the same layout as query_parser, children before parents.
+ */
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Rewrite {
+    // Root index of the rewritten query in the output buffer.
+    Root(usize),
+    NeverMatches,
+    MatchesAll,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+enum Expr<'t, 'a> {
+    Term(&'t Term<'a>),
+    NotTerm(&'t Term<'a>),
+    And(Vec<Expr<'t, 'a>>),
+    Or(Vec<Expr<'t, 'a>>),
+    Never,
+    Always,
+}
+
+#[inline]
+pub fn rewrite_query<'a>(nodes: &[QueryNode<'a>], root: usize, output: &mut Vec<QueryNode<'a>>) -> Rewrite {
+    output.clear();
+    return match simplify(to_negation_normal_form(nodes, root, false)) {
+        Expr::Never => Rewrite::NeverMatches,
+        Expr::Always => Rewrite::MatchesAll,
+        expr => Rewrite::Root(emit(&expr, output)),
+    };
+}
+
+// Replaces terms without a field by an Or over the default fields, each copy
+// carrying the term boost multiplied by the field boost.
+#[inline]
+pub fn expand_default_fields<'a>(
+    nodes: &[QueryNode<'a>],
+    root: usize,
+    default_fields: &'a [(String, f32)],
+    output: &mut Vec<QueryNode<'a>>,
+) -> usize {
+    output.clear();
+    return expand_node(nodes, root, default_fields, output);
+}
+
+fn expand_node<'a>(
+    nodes: &[QueryNode<'a>],
+    idx: usize,
+    default_fields: &'a [(String, f32)],
+    output: &mut Vec<QueryNode<'a>>,
+) -> usize {
+    let node = &nodes[idx];
+    if node.node_type != NodeType::Term {
+        let left = node.left.map(|left| expand_node(nodes, left, default_fields, output));
+        let right = node.right.map(|right| expand_node(nodes, right, default_fields, output));
+        return push_node(output, node.node_type, Term::default(), left, right);
+    }
+    if !node.term.field.is_empty() || default_fields.is_empty() {
+        return push_node(output, NodeType::Term, node.term.clone(), None, None);
+    }
+
+    let mut expanded: Option<usize> = None;
+    for (field, field_boost) in default_fields {
+        let mut term = node.term.clone();
+        term.field = Cow::Borrowed(field);
+        term.term_boost *= field_boost;
+        if term.term_type == TermType::Word && term.term_boost != 1.0 {
+            term.term_type = TermType::Boosted;
+        }
+        let idx = push_node(output, NodeType::Term, term, None, None);
+        expanded = match expanded {
+            Some(left) => Some(push_node(output, NodeType::Or, Term::default(), Some(left), Some(idx))),
+            None => Some(idx),
+        };
+    }
+    return expanded.unwrap();
+}
+
+// Sets of words that are searched for each other, like "couch, sofa, settee". A word may be
+// in several sets. Entries may be several words, which are searched as a phrase. Entries are
+// found by a key, like the terms the field's analyzer makes of them, so "Couches" finds the
+// set of "couch" on a stemmed field.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Synonyms {
+    // Every entry lowercased with single spaces, and its key.
+    sets: Vec<Vec<(String, String)>>,
+    // The indexes of the sets every key is in.
+    entries: HashMap<String, Vec<usize>>,
+}
+
+impl Synonyms {
+    // Adds a comma separated set, returns false when it has fewer than two entries with
+    // distinct keys. Entries with an empty key, like stopwords, are left out.
+    pub fn add_set(&mut self, set: &str, key: impl Fn(&str) -> String) -> bool {
+        let mut entries: Vec<(String, String)> = Vec::new();
+        for entry in set.split(',') {
+            let entry = entry.split_whitespace().collect::<Vec<&str>>().join(" ").to_lowercase();
+            let entry_key = key(&entry);
+            if !entry_key.is_empty() && !entries.iter().any(|(_, k)| *k == entry_key) {
+                entries.push((entry, entry_key));
+            }
+        }
+        if entries.len() < 2 {
+            return false;
+        }
+        for (_, entry_key) in &entries {
+            self.entries.entry(entry_key.clone()).or_default().push(self.sets.len());
+        }
+        self.sets.push(entries);
+        return true;
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        return self.sets.is_empty();
+    }
+
+    // The entries of every set with the key, in the order of the sets, without the entries of
+    // the key itself.
+    pub fn lookup(&self, key: &str) -> Vec<&str> {
+        let mut synonyms: Vec<&str> = Vec::new();
+        for set in self.entries.get(key).into_iter().flatten() {
+            for (entry, entry_key) in &self.sets[*set] {
+                if entry_key != key && !synonyms.contains(&entry.as_str()) {
+                    synonyms.push(entry);
+                }
+            }
+        }
+        return synonyms;
+    }
+}
+
+// Replaces words with synonyms by an Or of the word and its synonyms, synonyms of several
+// words become phrases. Every copy keeps the field and boost of the word. `lookup` gives the
+// synonyms of a word in a field.
+#[inline]
+pub fn expand_synonyms<'a>(
+    nodes: &[QueryNode<'a>],
+    root: usize,
+    lookup: &impl Fn(&str, &str) -> Vec<&'a str>,
+    output: &mut Vec<QueryNode<'a>>,
+) -> usize {
+    output.clear();
+    return expand_synonyms_node(nodes, root, lookup, output);
+}
+
+fn expand_synonyms_node<'a>(
+    nodes: &[QueryNode<'a>],
+    idx: usize,
+    lookup: &impl Fn(&str, &str) -> Vec<&'a str>,
+    output: &mut Vec<QueryNode<'a>>,
+) -> usize {
+    let node = &nodes[idx];
+    if node.node_type != NodeType::Term {
+        let left = node.left.map(|left| expand_synonyms_node(nodes, left, lookup, output));
+        let right = node.right.map(|right| expand_synonyms_node(nodes, right, lookup, output));
+        return push_node(output, node.node_type, Term::default(), left, right);
+    }
+    let mut expanded = push_node(output, NodeType::Term, node.term.clone(), None, None);
+    if !matches!(node.term.term_type, TermType::Word | TermType::Boosted) {
+        return expanded;
+    }
+    for synonym in lookup(&node.term.field, &node.term.value) {
+        let mut term = node.term.clone();
+        term.value = Cow::Borrowed(synonym);
+        if synonym.contains(' ') {
+            term.term_type = TermType::Phrase;
+        }
+        let idx = push_node(output, NodeType::Term, term, None, None);
+        expanded = push_node(output, NodeType::Or, Term::default(), Some(expanded), Some(idx));
+    }
+    return expanded;
+}
+
+fn to_negation_normal_form<'t, 'a>(nodes: &'t [QueryNode<'a>], idx: usize, negate: bool) -> Expr<'t, 'a> {
+    let node = &nodes[idx];
+    return match (node.node_type, negate) {
+        (NodeType::Term, false) => Expr::Term(&node.term),
+        (NodeType::Term, true) => Expr::NotTerm(&node.term),
+        (NodeType::Group, _) => to_negation_normal_form(nodes, node.left.unwrap(), negate),
+        (NodeType::Not, _) => to_negation_normal_form(nodes, node.left.unwrap(), !negate),
+        (NodeType::And, false) | (NodeType::Or, true) => Expr::And(vec![
+            to_negation_normal_form(nodes, node.left.unwrap(), negate),
+            to_negation_normal_form(nodes, node.right.unwrap(), negate),
+        ]),
+        (NodeType::Or, false) | (NodeType::And, true) => Expr::Or(vec![
+            to_negation_normal_form(nodes, node.left.unwrap(), negate),
+            to_negation_normal_form(nodes, node.right.unwrap(), negate),
+        ]),
+    };
+}
+
+fn simplify<'t, 'a>(expr: Expr<'t, 'a>) -> Expr<'t, 'a> {
+    let (children, is_and) = match expr {
+        Expr::And(children) => (children, true),
+        Expr::Or(children) => (children, false),
+        expr => return expr,
+    };
+    // Absorbing element: Never for And, Always for Or. The identity is the other one.
+    let (absorbing, identity) = match is_and {
+        true => (Expr::Never, Expr::Always),
+        false => (Expr::Always, Expr::Never),
+    };
+
+    let mut operands: Vec<Expr> = Vec::with_capacity(children.len());
+    for child in children {
+        match simplify(child) {
+            Expr::And(grand) if is_and => operands.extend(grand),
+            Expr::Or(grand) if !is_and => operands.extend(grand),
+            child if child == absorbing => return absorbing,
+            child if child == identity => {}
+            child => operands.push(child),
+        }
+    }
+    operands.sort_by(cmp_expr);
+    operands.dedup();
+
+    // After sorting a term and its negation are adjacent.
+    for pair in operands.windows(2) {
+        if let (Expr::Term(a), Expr::NotTerm(b)) = (&pair[0], &pair[1]) {
+            if a == b {
+                return absorbing;
+            }
+        }
+    }
+
+    return match operands.len() {
+        0 => identity,
+        1 => operands.pop().unwrap(),
+        _ if is_and => Expr::And(operands),
+        _ => Expr::Or(operands),
+    };
+}
+
+fn emit<'a>(expr: &Expr<'_, 'a>, output: &mut Vec<QueryNode<'a>>) -> usize {
+    let (operands, node_type) = match expr {
+        Expr::Term(term) => return push_node(output, NodeType::Term, (*term).clone(), None, None),
+        Expr::NotTerm(term) => {
+            let child = push_node(output, NodeType::Term, (*term).clone(), None, None);
+            return push_node(output, NodeType::Not, Term::default(), Some(child), None);
+        }
+        Expr::And(operands) => (operands, NodeType::And),
+        Expr::Or(operands) => (operands, NodeType::Or),
+        Expr::Never | Expr::Always => unreachable!("constants are removed by simplify"),
+    };
+    let mut left = emit(&operands[0], output);
+    for operand in &operands[1..] {
+        let right = emit(operand, output);
+        left = push_node(output, node_type, Term::default(), Some(left), Some(right));
+    }
+    return left;
+}
+
+// Terms sort before nested And/Or and a term sorts directly before its negation.
+fn cmp_expr(a: &Expr, b: &Expr) -> Ordering {
+    return match (a, b) {
+        (Expr::Term(x) | Expr::NotTerm(x), Expr::Term(y) | Expr::NotTerm(y)) => cmp_term(x, y)
+            .then_with(|| matches!(a, Expr::NotTerm(_)).cmp(&matches!(b, Expr::NotTerm(_)))),
+        (Expr::And(x), Expr::And(y)) | (Expr::Or(x), Expr::Or(y)) => {
+            for (x, y) in x.iter().zip(y.iter()) {
+                let ord = cmp_expr(x, y);
+                if ord != Ordering::Equal {
+                    return ord;
+                }
+            }
+            x.len().cmp(&y.len())
+        }
+        _ => expr_rank(a).cmp(&expr_rank(b)),
+    };
+}
+
+#[inline]
+fn expr_rank(expr: &Expr) -> u8 {
+    return match expr {
+        Expr::Term(_) | Expr::NotTerm(_) => 0,
+        Expr::And(_) => 1,
+        Expr::Or(_) => 2,
+        Expr::Never => 3,
+        Expr::Always => 4,
+    };
+}
+
+fn cmp_term(a: &Term, b: &Term) -> Ordering {
+    return a
+        .field
+        .cmp(&b.field)
+        .then_with(|| a.value.cmp(&b.value))
+        .then_with(|| cmp_term_type(&a.term_type, &b.term_type))
+        .then_with(|| a.term_boost.total_cmp(&b.term_boost));
+}
+
+fn cmp_term_type(a: &TermType, b: &TermType) -> Ordering {
+    return match (a, b) {
+        (TermType::Fuzzy(x), TermType::Fuzzy(y)) => x.cmp(y),
+        (TermType::Proximity(x), TermType::Proximity(y)) => x.cmp(y),
+        (TermType::Range(x_lower, x_upper), TermType::Range(y_lower, y_upper)) => {
+            cmp_bound(x_lower, y_lower).then_with(|| cmp_bound(x_upper, y_upper))
+        }
+        (TermType::GeoBox(x_lower, x_upper), TermType::GeoBox(y_lower, y_upper)) => {
+            cmp_geo_point(x_lower, y_lower).then_with(|| cmp_geo_point(x_upper, y_upper))
+        }
+        (TermType::GeoDistance(x_center, x_meters), TermType::GeoDistance(y_center, y_meters)) => {
+            cmp_geo_point(x_center, y_center).then_with(|| x_meters.total_cmp(y_meters))
+        }
+        _ => term_type_rank(a).cmp(&term_type_rank(b)),
+    };
+}
+
+#[inline]
+fn term_type_rank(term_type: &TermType) -> u8 {
+    return match term_type {
+        TermType::Phrase => 0,
+        TermType::Word => 1,
+        TermType::Wildcard => 2,
+        TermType::Fuzzy(_) => 3,
+        TermType::Proximity(_) => 4,
+        TermType::Range(_, _) => 5,
+        TermType::Boosted => 6,
+        TermType::GeoBox(_, _) => 7,
+        TermType::GeoDistance(_, _) => 8,
+    };
+}
+
+#[inline]
+fn cmp_geo_point(a: &GeoPoint, b: &GeoPoint) -> Ordering {
+    return a.lat.total_cmp(&b.lat).then_with(|| a.lon.total_cmp(&b.lon));
+}
+
+#[inline]
+fn cmp_bound(a: &Bound<Cow<'_, str>>, b: &Bound<Cow<'_, str>>) -> Ordering {
+    return match (a, b) {
+        (Bound::Unbounded, Bound::Unbounded) => Ordering::Equal,
+        (Bound::Unbounded, _) => Ordering::Less,
+        (_, Bound::Unbounded) => Ordering::Greater,
+        (Bound::Included(x), Bound::Included(y)) | (Bound::Excluded(x), Bound::Excluded(y)) => {
+            x.cmp(y)
+        }
+        (Bound::Included(_), Bound::Excluded(_)) => Ordering::Less,
+        (Bound::Excluded(_), Bound::Included(_)) => Ordering::Greater,
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::query_parser::{parse_query, to_query_string, MAX_NESTING, MAX_QUERY_NODES};
+
+    fn rewrite(query: &str) -> (Rewrite, Vec<QueryNode<'_>>) {
+        let mut nodes = Vec::new();
+        let root = parse_query(query.as_bytes(), &mut nodes).unwrap();
+        let mut output = Vec::new();
+        let res = rewrite_query(&nodes, root, &mut output);
+        return (res, output);
+    }
+
+    fn assert_same_rewrite(a: &str, b: &str) {
+        assert_eq!(rewrite(a), rewrite(b), "{} != {}", a, b);
+    }
+
+    #[test]
+    fn test_drops_groups_and_flattens() {
+        let (res, nodes) = rewrite("((a:1 and (b:2)) and (c:3 and a:1))");
+        assert_eq!(res, Rewrite::Root(4));
+        let types: Vec<NodeType> = nodes.iter().map(|n| n.node_type).collect();
+        assert_eq!(
+            types,
+            vec![NodeType::Term, NodeType::Term, NodeType::And, NodeType::Term, NodeType::And]
+        );
+        let values: Vec<&str> = nodes.iter().map(|n| n.term.value.as_ref()).collect();
+        assert_eq!(values, vec!["1", "2", "", "3", ""]);
+    }
+
+    #[test]
+    fn test_equal_queries_rewrite_identically() {
+        assert_same_rewrite("a:1 and b:2", "b:2 and a:1");
+        assert_same_rewrite("a:1 or (b:2 or c:3)", "(c:3 or a:1) or b:2 or a:1");
+        assert_same_rewrite("a:1 and (b:2 or c:3)", "(c:3 or b:2) and a:1");
+        assert_same_rewrite("not not a:1", "(a:1)");
+    }
+
+    #[test]
+    fn test_de_morgan() {
+        assert_same_rewrite("not (a:1 and b:2)", "not a:1 or not b:2");
+        assert_same_rewrite("not (a:1 or not b:2)", "b:2 and not a:1");
+        let (_, nodes) = rewrite("not (a:1 or b:2)");
+        assert!(nodes
+            .iter()
+            .filter(|n| n.node_type == NodeType::Not)
+            .all(|n| nodes[n.left.unwrap()].node_type == NodeType::Term));
+    }
+
+    #[test]
+    fn test_never_matches() {
+        assert_eq!(rewrite("a:1 and not a:1").0, Rewrite::NeverMatches);
+        assert_eq!(rewrite("b:2 and (a:1 and not (a:1 or c:3))").0, Rewrite::NeverMatches);
+        assert_eq!(rewrite("not (a:1 or not a:1)").0, Rewrite::NeverMatches);
+        // Only the contradicting branch is dropped.
+        assert_same_rewrite("b:2 or (a:1 and not a:1)", "b:2");
+    }
+
+    #[test]
+    fn test_matches_all() {
+        assert_eq!(rewrite("a:1 or not a:1").0, Rewrite::MatchesAll);
+        assert_same_rewrite("b:2 and (a:1 or not a:1)", "b:2");
+    }
+
+    fn expand(query: &str, default_fields: &[(&str, f32)]) -> String {
+        let mut nodes = Vec::new();
+        let root = parse_query(query.as_bytes(), &mut nodes).unwrap();
+        let default_fields: Vec<(String, f32)> =
+            default_fields.iter().map(|(f, b)| (f.to_string(), *b)).collect();
+        let mut output = Vec::new();
+        let root = expand_default_fields(&nodes, root, &default_fields, &mut output);
+        return to_query_string(&output, root);
+    }
+
+    #[test]
+    fn test_expand_default_fields() {
+        assert_eq!(expand("dog and al:cat", &[("body", 1.0)]), "body:dog and al:cat");
+        assert_eq!(
+            expand("dog and not \"quick fox\"^2", &[("title", 3.0), ("body", 1.0)]),
+            "(title:dog^3 or body:dog) and not (title:\"quick fox\"^6 or body:\"quick fox\"^2)"
+        );
+        assert_eq!(expand("(dog~1)", &[("title", 2.0), ("body", 0.5)]), "(title:dog~1^2 or body:dog~1^0.5)");
+        // Without default fields the bare term is left for validation to reject.
+        assert_eq!(expand("dog", &[]), "dog");
+    }
+
+    fn expand_with_synonyms(query: &str, synonyms: &Synonyms) -> String {
+        let mut nodes = Vec::new();
+        let root = parse_query(query.as_bytes(), &mut nodes).unwrap();
+        let mut output = Vec::new();
+        let lookup = |field: &str, word: &str| match field {
+            "count" => Vec::new(),
+            _ => synonyms.lookup(&word.to_lowercase()),
+        };
+        let root = expand_synonyms(&nodes, root, &lookup, &mut output);
+        return to_query_string(&output, root);
+    }
+
+    #[test]
+    fn test_expand_synonyms() {
+        let mut synonyms = Synonyms::default();
+        assert!(synonyms.add_set("couch, Sofa,  settee", str::to_lowercase));
+        assert!(synonyms.add_set("tv, television   set", str::to_lowercase));
+        assert!(synonyms.add_set("sofa, divan", str::to_lowercase));
+        assert!(synonyms.add_set("1, one", str::to_lowercase));
+        assert!(!synonyms.add_set("lamp, LAMP,", str::to_lowercase));
+        assert_eq!(synonyms.lookup("sofa"), vec!["couch", "settee", "divan"]);
+        assert_eq!(synonyms.lookup("lamp"), Vec::<&str>::new());
+
+        // Entries with the same key are one entry, those with an empty key are left out.
+        let stem = |entry: &str| {
+            let words = entry.split(' ').filter(|w| *w != "the").map(|w| w.trim_end_matches('s'));
+            return words.collect::<Vec<_>>().join(" ");
+        };
+        let mut stemmed = Synonyms::default();
+        assert!(stemmed.add_set("couches, sofa, the", stem));
+        assert!(!stemmed.add_set("lamps, lamp", stem));
+        assert_eq!(stemmed.lookup("couche"), vec!["sofa"]);
+        assert_eq!(stemmed.lookup("sofa"), vec!["couches"]);
+
+        assert_eq!(
+            expand_with_synonyms("al:Couch and not tv^2", &synonyms),
+            "(al:Couch or al:sofa or al:settee) and not (tv^2 or \"television set\"^2)"
+        );
+        // Only words with synonyms in their field are expanded.
+        assert_eq!(
+            expand_with_synonyms("count:1 or al:1 or \"sofa\" or sofa* or al:tv~1", &synonyms),
+            "count:1 or (al:1 or al:one) or \"sofa\" or sofa* or al:tv~1"
+        );
+    }
+
+    #[test]
+    fn test_largest_queries() {
+        // The deepest trees the parser accepts, a chain of terms and nested groups and nots.
+        let chain = vec!["a:1"; MAX_QUERY_NODES / 3].join(" and not ");
+        assert_eq!(rewrite(&chain).0, Rewrite::NeverMatches);
+        let chain = (0..MAX_QUERY_NODES / 2).map(|i| format!("a:{}", i)).collect::<Vec<_>>().join(" or ");
+        let (res, nodes) = rewrite(&chain);
+        assert_eq!((res, nodes.len()), (Rewrite::Root(MAX_QUERY_NODES - 2), MAX_QUERY_NODES - 1));
+        let nested = "not (".repeat(MAX_NESTING / 2) + "a:1 or b:2" + &")".repeat(MAX_NESTING / 2);
+        assert!(matches!(rewrite(&nested).0, Rewrite::Root(_)));
+    }
+
+    #[test]
+    fn test_terms_differ_by_type_and_boost() {
+        let (_, nodes) = rewrite("a:1 and a:1^2 and a:1~1 and a:\"1\"");
+        assert_eq!(nodes.iter().filter(|n| n.node_type == NodeType::Term).count(), 4);
+    }
+}
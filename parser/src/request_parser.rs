@@ -1,10 +1,11 @@
 extern crate nom;
+use bytes::{BufMut, BytesMut};
 use nom::multi::{many_m_n, separated_list0, separated_list1};
 use nom::sequence::{preceded, separated_pair, terminated, Tuple};
 use nom::{
     branch::alt,
-    bytes::complete::{tag, tag_no_case, take_while},
-    character::complete::{alphanumeric1, multispace0},
+    bytes::complete::{tag, tag_no_case, take_while, take_while1},
+    character::complete::{alphanumeric1, line_ending, multispace0, space0},
     character::is_space,
     combinator::map,
     IResult,
@@ -21,9 +22,9 @@ use nom::{
 pub struct HttpRequest<'a> {
     pub method: Method,
     pub path: &'a [u8],
-    params: Option<Vec<(&'a [u8], &'a [u8])>>,
+    params: Option<Params<'a>>,
     pub protocol: Protocol,
-    pub headers: Vec<(&'a [u8], Vec<&'a [u8]>)>,
+    pub headers: Headers<'a>,
     pub body: &'a [u8],
 }
 
@@ -33,8 +34,7 @@ impl HttpRequest<'_> {
             Some(ref params) => params
                 .iter()
                 .find(|&(k, _)| k == &key.as_bytes())
-                .map(|&(_, v)| v)
-                .take(),
+                .map(|&(_, v)| v),
             None => None,
         };
     }
@@ -78,15 +78,12 @@ fn get_string_param(input: &[u8]) -> IResult<&[u8], &[u8]> {
 
 #[inline]
 fn get_string_non_semicolon(input: &[u8]) -> IResult<&[u8], &[u8]> {
-    return take_while(|i| !is_space(i) && i != b':')(input);
+    return take_while1(|i: u8| !i.is_ascii_whitespace() && i != b':')(input);
 }
 
 #[inline]
 fn get_string_non_comma(input: &[u8]) -> IResult<&[u8], &[u8]> {
-    return take_while(|i| match i {
-        b',' | b'\r' | b'\n' => false,
-        _ => true,
-    })(input);
+    return take_while(|i| !matches!(i, b',' | b'\r' | b'\n'))(input);
 }
 
 #[inline]
@@ -99,8 +96,11 @@ fn parse_path(input: &[u8]) -> IResult<&[u8], &[u8]> {
     return take_while(|i| !is_space_or_question(i))(input);
 }
 
+type Params<'a> = Vec<(&'a [u8], &'a [u8])>;
+type Headers<'a> = Vec<(&'a [u8], Vec<&'a [u8]>)>;
+
 #[inline]
-fn parse_params(input: &[u8]) -> IResult<&[u8], Vec<Vec<(&[u8], &[u8])>>> {
+fn parse_params(input: &[u8]) -> IResult<&[u8], Vec<Params<'_>>> {
     return many_m_n(
         0,
         1,
@@ -130,11 +130,12 @@ fn parse_protocol(input: &[u8]) -> IResult<&[u8], Protocol> {
 
 // TODO: Special case for Set-Cookie since it is permitted to have newlines
 #[inline]
-fn parse_headers(input: &[u8]) -> IResult<&[u8], Vec<(&[u8], Vec<&[u8]>)>> {
+fn parse_headers(input: &[u8]) -> IResult<&[u8], Headers<'_>> {
     return separated_list1(
         alt((tag("\r\n"), tag("\n"))),
+        // Only spaces, a blank line ends the headers and starts the body.
         preceded(
-            multispace0,
+            space0,
             separated_pair(get_string_non_semicolon, tag(":"), parse_header_value),
         ),
     )(input);
@@ -146,19 +147,20 @@ fn parse_header_value(input: &[u8]) -> IResult<&[u8], Vec<&[u8]>> {
 }
 
 #[inline]
-pub fn parse_request(req: &[u8]) -> Result<HttpRequest, nom::Err<nom::error::Error<&[u8]>>> {
+pub fn parse_request(req: &[u8]) -> Result<HttpRequest<'_>, nom::Err<nom::error::Error<&[u8]>>> {
     return match (
         parse_method,
         parse_path,
         parse_params,
         multispace0,
         parse_protocol,
+        line_ending,
         parse_headers,
         preceded(tag("\r\n\r\n"), multispace0),
     )
         .parse(req)
     {
-        Ok((body, (method, path, mut params, _, protocol, headers, _))) => Ok(HttpRequest {
+        Ok((body, (method, path, mut params, _, protocol, _, headers, _))) => Ok(HttpRequest {
             method,
             path,
             params: params.pop(),
@@ -170,6 +172,38 @@ pub fn parse_request(req: &[u8]) -> Result<HttpRequest, nom::Err<nom::error::Err
     };
 }
 
+#[inline]
+fn hex_value(i: u8) -> Option<u8> {
+    return match i {
+        b'0'..=b'9' => Some(i - b'0'),
+        b'a'..=b'f' => Some(i - b'a' + 10),
+        b'A'..=b'F' => Some(i - b'A' + 10),
+        _ => None,
+    };
+}
+
+// Decodes an application/x-www-form-urlencoded value. Malformed escapes are copied as is.
+#[inline]
+pub fn url_decode(input: &[u8], output: &mut BytesMut) {
+    let mut i = 0;
+    while i < input.len() {
+        match input[i] {
+            b'+' => output.put_u8(b' '),
+            b'%' if i + 2 < input.len() => {
+                match (hex_value(input[i + 1]), hex_value(input[i + 2])) {
+                    (Some(hi), Some(lo)) => {
+                        output.put_u8(hi << 4 | lo);
+                        i += 2;
+                    }
+                    _ => output.put_u8(b'%'),
+                }
+            }
+            c => output.put_u8(c),
+        }
+        i += 1;
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -196,7 +230,7 @@ mod test {
     #[test]
     fn test_parse_params() {
         let res = parse_params(b"?one=1&two=2");
-        let expected: IResult<&[u8], Vec<Vec<(&[u8], &[u8])>>> =
+        let expected: IResult<&[u8], Vec<Params>> =
             Ok((b"", vec![vec![(b"one", b"1"), (b"two", b"2")]]));
         assert_eq!(res, expected);
     }
@@ -204,7 +238,7 @@ mod test {
     #[test]
     fn test_parse_headers() {
         let input = b"Content-Length: length\r\nAccept-Language: en-us, en-gb";
-        let expected: IResult<&[u8], Vec<(&[u8], Vec<&[u8]>)>> = Ok((
+        let expected: IResult<&[u8], Headers> = Ok((
             b"",
             vec![
                 (b"Content-Length", vec![b"length"]),
@@ -221,6 +255,13 @@ mod test {
         assert_eq!(parse_header_value(input), expected)
     }
 
+    #[test]
+    fn test_url_decode() {
+        let mut output = BytesMut::new();
+        url_decode(b"al%3Adog+and+%28al:cat%29%2", &mut output);
+        assert_eq!(&output[..], b"al:dog and (al:cat)%2");
+    }
+
     #[test]
     fn test_parse_request() {
         let example_text: Vec<&[u8]> = vec![
@@ -266,6 +307,20 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_parse_json_body() {
+        let req = b"POST /search HTTP/1.1\r\nHost: localhost:8080\r\nContent-Length: 25\r\n\r\n{\"term\": {\"al\": \"dog\"}}";
+        let res = parse_request(req).unwrap();
+        assert_eq!(
+            res.headers,
+            vec![
+                (&b"Host"[..], vec![&b"localhost:8080"[..]]),
+                (b"Content-Length", vec![b"25"]),
+            ]
+        );
+        assert_eq!(res.body, b"{\"term\": {\"al\": \"dog\"}}");
+    }
+
     #[test]
     fn test_parse_firefox_request() {
         let example_text: Vec<&[u8]> = vec![
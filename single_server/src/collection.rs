@@ -0,0 +1,671 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::Bound;
+use std::path::{Component, Path};
+use std::time::Instant;
+
+use serde::Deserialize;
+use serde_json::Value;
+
+use finne_parser::query_parser::{GeoPoint, NodeType, QueryNode, TermType};
+use finne_parser::query_rewriter::Synonyms;
+use storage::analysis::{Analyzer, TokenFilter};
+use storage::indexes::Number;
+use storage::scoring::Bm25;
+use storage::segment::FieldValue;
+use storage::CollectionBuf;
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IndexType {
+    Integer,
+    Real,
+    Text,
+    // A latitude,longitude pair in degrees.
+    GeoPoint,
+}
+
+#[derive(Deserialize)]
+pub struct CreateRequest {
+    pub name: String,
+    pub indexes: HashMap<String, IndexType>,
+    // Text fields searched by terms without a field, optionally boosted as in "title^3".
+    #[serde(default)]
+    pub default_fields: Vec<String>,
+    // Relevance scoring parameters, each defaults to the usual value when left out.
+    #[serde(default)]
+    pub bm25: Bm25Config,
+    // How text fields are split into terms, the standard analyzer when left out.
+    #[serde(default)]
+    pub analyzers: HashMap<String, AnalyzerConfig>,
+    // Sets of words searched for each other, each comma separated as in "couch, sofa, settee".
+    // Entries of several words are searched as phrases. A create request may also name a file
+    // in the server's synonyms directory with "synonyms_file", its sets are read once and
+    // stored here, see read_synonyms_file.
+    #[serde(default)]
+    pub synonyms: Vec<String>,
+}
+
+// A named analyzer, "standard" or "english", or a list of token filters applied in order:
+// "lowercase", "ascii_folding", "stopwords" and "stemmer".
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum AnalyzerConfig {
+    Name(String),
+    Filters(Vec<String>),
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(default)]
+pub struct Bm25Config {
+    pub k1: f32,
+    pub b: f32,
+}
+
+impl Default for Bm25Config {
+    #[inline]
+    fn default() -> Self {
+        let Bm25 { k1, b } = Bm25::default();
+        return Bm25Config { k1, b };
+    }
+}
+
+pub struct Collection {
+    pub definition: CreateRequest,
+    pub default_fields: Vec<(String, f32)>,
+    pub bm25: Bm25,
+    // The text fields with another than the standard analyzer, see field_analyzer.
+    pub analyzers: HashMap<String, Analyzer>,
+    // The synonym sets keyed by the terms of every analyzer of the text fields, see synonyms.
+    pub synonyms: Vec<(Analyzer, Synonyms)>,
+    pub data: CollectionBuf,
+    // When the first document not yet flushed from the in-memory segment was written.
+    pub unflushed_since: Option<Instant>,
+}
+
+static STANDARD_ANALYZER: Analyzer = Analyzer::STANDARD;
+
+#[inline]
+pub fn field_analyzer<'a>(analyzers: &'a HashMap<String, Analyzer>, field: &str) -> &'a Analyzer {
+    return analyzers.get(field).unwrap_or(&STANDARD_ANALYZER);
+}
+
+impl Collection {
+    #[inline]
+    pub fn new(definition: CreateRequest, data: CollectionBuf) -> Result<Collection, SchemaError> {
+        let mut default_fields = Vec::with_capacity(definition.default_fields.len());
+        for default_field in &definition.default_fields {
+            let (field, boost) = match default_field.split_once('^') {
+                Some((field, boost)) => match boost.parse::<f32>() {
+                    Ok(boost) if boost.is_finite() && boost > 0.0 => (field, boost),
+                    _ => return Err(SchemaError::new(field, "invalid default field boost")),
+                },
+                None => (default_field.as_str(), 1.0),
+            };
+            match definition.indexes.get(field) {
+                Some(IndexType::Text) => default_fields.push((field.to_owned(), boost)),
+                Some(_) => return Err(SchemaError::new(field, "default fields must be text fields")),
+                None => return Err(SchemaError::new(field, "unknown field")),
+            }
+        }
+        let Bm25Config { k1, b } = definition.bm25;
+        if !k1.is_finite() || k1 < 0.0 {
+            return Err(SchemaError::new("bm25", "k1 must be a finite number of at least 0"));
+        }
+        if !(0.0..=1.0).contains(&b) {
+            return Err(SchemaError::new("bm25", "b must be between 0 and 1"));
+        }
+        let mut analyzers = HashMap::with_capacity(definition.analyzers.len());
+        for (field, config) in &definition.analyzers {
+            match definition.indexes.get(field) {
+                Some(IndexType::Text) => {}
+                Some(_) => return Err(SchemaError::new(field, "analyzers are only for text fields")),
+                None => return Err(SchemaError::new(field, "unknown field")),
+            }
+            let analyzer = match config {
+                AnalyzerConfig::Name(name) => match name.as_str() {
+                    "standard" => Analyzer::STANDARD,
+                    "english" => Analyzer::ENGLISH,
+                    _ => return Err(SchemaError::new(field, "unknown analyzer, expected standard or english")),
+                },
+                AnalyzerConfig::Filters(names) => {
+                    let mut filters = Vec::with_capacity(names.len());
+                    for name in names {
+                        filters.push(match name.as_str() {
+                            "lowercase" => TokenFilter::Lowercase,
+                            "ascii_folding" => TokenFilter::AsciiFolding,
+                            "stopwords" => TokenFilter::EnglishStopwords,
+                            "stemmer" => TokenFilter::EnglishStemmer,
+                            _ => return Err(SchemaError::new(field, "unknown token filter")),
+                        });
+                    }
+                    Analyzer::new(filters)
+                }
+            };
+            if analyzer != Analyzer::STANDARD {
+                analyzers.insert(field.clone(), analyzer);
+            }
+        }
+        let synonyms = load_synonyms(&definition, &analyzers)?;
+        return Ok(Collection {
+            definition,
+            default_fields,
+            bm25: Bm25 { k1, b },
+            analyzers,
+            synonyms,
+            data,
+            unflushed_since: None,
+        });
+    }
+
+    // The synonyms of a word in a field, a word finds the sets of the entries the field's
+    // analyzer makes the same terms of. Only text fields have synonyms.
+    pub fn synonyms(&self, field: &str, word: &str) -> Vec<&str> {
+        if self.synonyms.is_empty() || self.definition.indexes.get(field) != Some(&IndexType::Text) {
+            return Vec::new();
+        }
+        let analyzer = field_analyzer(&self.analyzers, field);
+        return match self.synonyms.iter().find(|(a, _)| a == analyzer) {
+            Some((_, synonyms)) => synonyms.lookup(&synonym_key(analyzer, word)),
+            None => Vec::new(),
+        };
+    }
+}
+
+// The terms of a synonym or searched word, separated by spaces.
+#[inline]
+fn synonym_key(analyzer: &Analyzer, text: &str) -> String {
+    let terms: Vec<String> = analyzer.analyze(text).map(|(_, term)| term).collect();
+    return terms.join(" ");
+}
+
+fn load_synonyms(
+    definition: &CreateRequest,
+    analyzers: &HashMap<String, Analyzer>,
+) -> Result<Vec<(Analyzer, Synonyms)>, SchemaError> {
+    let mut checked = Synonyms::default();
+    for set in &definition.synonyms {
+        if !checked.add_set(set, |entry| synonym_key(&Analyzer::STANDARD, entry)) {
+            return Err(SchemaError::new("synonyms", "synonym sets need at least two words"));
+        }
+    }
+    let mut keyed: Vec<(Analyzer, Synonyms)> = Vec::new();
+    if checked.is_empty() {
+        return Ok(keyed);
+    }
+    for (field, index_type) in &definition.indexes {
+        let analyzer = field_analyzer(analyzers, field);
+        if *index_type != IndexType::Text || keyed.iter().any(|(a, _)| a == analyzer) {
+            continue;
+        }
+        let mut synonyms = Synonyms::default();
+        for set in &definition.synonyms {
+            // A set whose entries are the same terms to the analyzer, like "lamp, lamps" when
+            // stemming, is left out.
+            synonyms.add_set(set, |entry| synonym_key(analyzer, entry));
+        }
+        keyed.push((analyzer.clone(), synonyms));
+    }
+    return Ok(keyed);
+}
+
+// The sets of a synonyms file, one per line, lines starting with # are comments. Clients only
+// name the file, it is read from `dir`, the synonyms directory of the server, so a request
+// cannot read other files.
+pub fn read_synonyms_file(dir: Option<&Path>, name: &str) -> Result<Vec<String>, SchemaError> {
+    let dir = match dir {
+        Some(dir) => dir,
+        None => return Err(SchemaError::new("synonyms_file", "the server has no synonyms directory")),
+    };
+    let mut components = Path::new(name).components();
+    if !matches!((components.next(), components.next()), (Some(Component::Normal(_)), None)) {
+        return Err(SchemaError::new("synonyms_file", "expected a file name without a directory"));
+    }
+    let path = dir.join(name);
+    let file = match std::fs::read_to_string(&path) {
+        Ok(file) => file,
+        Err(e) => {
+            println!("Could not read synonyms file {}: {}", path.display(), e);
+            return Err(SchemaError::new("synonyms_file", "could not read the synonyms file"));
+        }
+    };
+    let lines = file.lines().map(str::trim).filter(|line| !line.is_empty() && !line.starts_with('#'));
+    return Ok(lines.map(str::to_owned).collect());
+}
+
+#[derive(Debug, PartialEq)]
+pub struct SchemaError {
+    pub field: String,
+    pub message: &'static str,
+}
+
+impl fmt::Display for SchemaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.field.is_empty() {
+            return write!(f, "{}", self.message);
+        }
+        return write!(f, "field '{}': {}", self.field, self.message);
+    }
+}
+
+impl SchemaError {
+    #[inline]
+    fn new(field: &str, message: &'static str) -> SchemaError {
+        return SchemaError {
+            field: field.to_owned(),
+            message,
+        };
+    }
+}
+
+// Checks every term against the collection's indexes and rewrites numeric
+// literals into their canonical form so they compare equal to the indexed values.
+pub fn validate_query(
+    indexes: &HashMap<String, IndexType>,
+    query_nodes: &mut [QueryNode<'_>],
+) -> Result<(), SchemaError> {
+    for node in query_nodes.iter_mut() {
+        if node.node_type != NodeType::Term {
+            continue;
+        }
+        let term = &mut node.term;
+        if term.field.is_empty() {
+            return Err(SchemaError::new("", "terms without a field need default fields"));
+        }
+        let index_type = match indexes.get(term.field.as_ref()) {
+            Some(index_type) => *index_type,
+            None => return Err(SchemaError::new(&term.field, "unknown field")),
+        };
+        // The JSON query and default field boosts can overflow to an infinite boost.
+        if !term.term_boost.is_finite() {
+            return Err(SchemaError::new(&term.field, "boost must be a finite number"));
+        }
+        match (index_type, &mut term.term_type) {
+            (IndexType::GeoPoint, TermType::GeoBox(lower, upper)) => {
+                if !is_valid_point(lower) || !is_valid_point(upper) || lower.lat > upper.lat {
+                    return Err(SchemaError::new(&term.field, "invalid bounding box"));
+                }
+            }
+            (IndexType::GeoPoint, TermType::GeoDistance(center, meters)) => {
+                if !is_valid_point(center) || !meters.is_finite() || *meters <= 0.0 {
+                    return Err(SchemaError::new(&term.field, "invalid distance query"));
+                }
+            }
+            (IndexType::GeoPoint, _) => {
+                return Err(SchemaError::new(&term.field, "geo fields need a bounding box or distance query"));
+            }
+            (_, TermType::GeoBox(_, _) | TermType::GeoDistance(_, _)) => {
+                return Err(SchemaError::new(&term.field, "geo queries need a geo point field"));
+            }
+            (IndexType::Text, TermType::Range(_, _)) => {
+                return Err(SchemaError::new(&term.field, "range queries need a numeric field"));
+            }
+            (IndexType::Text, _) => {}
+            (_, TermType::Word | TermType::Boosted) => {
+                let value = coerce_number(index_type, &term.field, &term.value)?;
+                if value != term.value {
+                    term.value = Cow::Owned(value);
+                }
+            }
+            (_, TermType::Range(lower, upper)) => {
+                coerce_bound(index_type, &term.field, lower)?;
+                coerce_bound(index_type, &term.field, upper)?;
+            }
+            (_, TermType::Fuzzy(_)) => {
+                return Err(SchemaError::new(&term.field, "fuzzy queries need a text field"));
+            }
+            (_, TermType::Wildcard) => {
+                return Err(SchemaError::new(&term.field, "wildcard queries need a text field"));
+            }
+            (_, TermType::Phrase | TermType::Proximity(_)) => {
+                return Err(SchemaError::new(&term.field, "phrase queries need a text field"));
+            }
+        }
+    }
+    return Ok(());
+}
+
+// Collects the values of the indexed fields of a document, fields that are missing or null
+// are not indexed.
+pub fn document_fields<'a>(
+    indexes: &'a HashMap<String, IndexType>,
+    analyzers: &'a HashMap<String, Analyzer>,
+    doc: &'a Value,
+    fields: &mut Vec<(&'a str, FieldValue<'a>)>,
+) -> Result<(), SchemaError> {
+    let doc = match doc.as_object() {
+        Some(doc) => doc,
+        None => return Err(SchemaError::new("", "documents must be JSON objects")),
+    };
+    for (field, index_type) in indexes {
+        let value = match doc.get(field) {
+            Some(Value::Null) | None => continue,
+            Some(value) => value,
+        };
+        let value = match index_type {
+            IndexType::Text => match value.as_str() {
+                Some(text) => FieldValue::Text(text, field_analyzer(analyzers, field)),
+                None => return Err(SchemaError::new(field, "expected a string")),
+            },
+            IndexType::Integer => match value.as_i64() {
+                Some(v) => FieldValue::Number(Number::Integer(v)),
+                None => return Err(SchemaError::new(field, "expected an integer")),
+            },
+            IndexType::Real => match value.as_f64() {
+                Some(v) => FieldValue::Number(Number::Real(v)),
+                None => return Err(SchemaError::new(field, "expected a real number")),
+            },
+            IndexType::GeoPoint => match geo_point(value) {
+                Some(point) => FieldValue::GeoPoint(point.lat, point.lon),
+                None => return Err(SchemaError::new(field, "expected a point as \"lat,lon\" or {\"lat\",\"lon\"}")),
+            },
+        };
+        fields.push((field, value));
+    }
+    return Ok(());
+}
+
+#[inline]
+fn geo_point(value: &Value) -> Option<GeoPoint> {
+    let point = match value {
+        Value::String(point) => {
+            let (lat, lon) = point.split_once(',')?;
+            GeoPoint {
+                lat: lat.trim().parse().ok()?,
+                lon: lon.trim().parse().ok()?,
+            }
+        }
+        Value::Object(point) => GeoPoint {
+            lat: point.get("lat")?.as_f64()?,
+            lon: point.get("lon")?.as_f64()?,
+        },
+        _ => return None,
+    };
+    if !is_valid_point(&point) {
+        return None;
+    }
+    return Some(point);
+}
+
+// The lower left corner of a bounding box may have a larger longitude when the box crosses
+// the antimeridian, so only the ranges are checked here.
+#[inline]
+fn is_valid_point(point: &GeoPoint) -> bool {
+    return (-90.0..=90.0).contains(&point.lat) && (-180.0..=180.0).contains(&point.lon);
+}
+
+#[inline]
+fn coerce_bound(index_type: IndexType, field: &str, bound: &mut Bound<Cow<'_, str>>) -> Result<(), SchemaError> {
+    if let Bound::Included(value) | Bound::Excluded(value) = bound {
+        let canonical = coerce_number(index_type, field, value)?;
+        if canonical != *value {
+            *value = Cow::Owned(canonical);
+        }
+    }
+    return Ok(());
+}
+
+#[inline]
+fn coerce_number(index_type: IndexType, field: &str, value: &str) -> Result<String, SchemaError> {
+    return match index_type {
+        IndexType::Integer => match value.parse::<i64>() {
+            Ok(v) => Ok(v.to_string()),
+            Err(_) => Err(SchemaError::new(field, "expected an integer")),
+        },
+        IndexType::Real => match value.parse::<f64>() {
+            Ok(v) if v.is_finite() => Ok(v.to_string()),
+            _ => Err(SchemaError::new(field, "expected a real number")),
+        },
+        IndexType::Text | IndexType::GeoPoint => Ok(value.to_owned()),
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use finne_parser::query_parser::{parse_query, to_query_string};
+
+    fn indexes() -> HashMap<String, IndexType> {
+        return HashMap::from([
+            ("title".to_owned(), IndexType::Text),
+            ("count".to_owned(), IndexType::Integer),
+            ("price".to_owned(), IndexType::Real),
+            ("loc".to_owned(), IndexType::GeoPoint),
+        ]);
+    }
+
+    fn validate(query: &str) -> Result<String, SchemaError> {
+        let mut nodes = Vec::new();
+        let root = parse_query(query.as_bytes(), &mut nodes).unwrap();
+        validate_query(&indexes(), &mut nodes)?;
+        return Ok(to_query_string(&nodes, root));
+    }
+
+    #[test]
+    fn test_valid_queries() {
+        assert_eq!(
+            validate("title:\"quick fox\" and title:fo* and title:fox~1"),
+            Ok("title:\"quick fox\" and title:fo* and title:fox~1".to_owned())
+        );
+        assert_eq!(validate("count:010 or count:-3^2"), Ok("count:10 or count:-3^2".to_owned()));
+        assert_eq!(
+            validate("price:[1.50 TO 2e1} and count:{* TO 007]"),
+            Ok("price:[1.5 TO 20} and count:[* TO 7]".to_owned())
+        );
+        assert_eq!(
+            validate("loc:[10,170 TO 20,-170] or loc:52.3,4.9~2km"),
+            Ok("loc:[10,170 TO 20,-170] or loc:52.3,4.9~2000m".to_owned())
+        );
+    }
+
+    #[test]
+    fn test_document_fields() {
+        let indexes = indexes();
+        let analyzers = HashMap::new();
+        let fields = |doc: &str| -> Result<Vec<String>, SchemaError> {
+            let doc: Value = serde_json::from_str(doc).unwrap();
+            let mut fields = Vec::new();
+            document_fields(&indexes, &analyzers, &doc, &mut fields)?;
+            let mut fields: Vec<String> = fields.iter().map(|(f, v)| format!("{}={:?}", f, v)).collect();
+            fields.sort();
+            return Ok(fields);
+        };
+        assert_eq!(
+            fields(r#"{"title":"fox","count":3,"price":2,"loc":"52.3, 4.9","other":1}"#),
+            Ok(vec![
+                "count=Number(Integer(3))".to_owned(),
+                "loc=GeoPoint(52.3, 4.9)".to_owned(),
+                "price=Number(Real(2.0))".to_owned(),
+                "title=Text(\"fox\", Analyzer { filters: [Lowercase] })".to_owned(),
+            ])
+        );
+        assert_eq!(
+            fields(r#"{"title":null,"loc":{"lat":-10,"lon":170.5}}"#),
+            Ok(vec!["loc=GeoPoint(-10.0, 170.5)".to_owned()])
+        );
+        assert_eq!(fields("[1]"), Err(SchemaError::new("", "documents must be JSON objects")));
+        assert_eq!(fields(r#"{"title":1}"#), Err(SchemaError::new("title", "expected a string")));
+        assert_eq!(fields(r#"{"count":1.5}"#), Err(SchemaError::new("count", "expected an integer")));
+        assert_eq!(fields(r#"{"loc":"91,0"}"#).err().map(|e| e.field), Some("loc".to_owned()));
+    }
+
+    fn new_collection(default_fields: &[&str]) -> Result<Collection, SchemaError> {
+        let data = CollectionBuf::new("test".to_owned(), bytes::Bytes::new());
+        return Collection::new(definition(default_fields), data);
+    }
+
+    fn definition(default_fields: &[&str]) -> CreateRequest {
+        return CreateRequest {
+            name: "test".to_owned(),
+            indexes: indexes(),
+            default_fields: default_fields.iter().map(|f| f.to_string()).collect(),
+            bm25: Bm25Config::default(),
+            analyzers: HashMap::new(),
+            synonyms: Vec::new(),
+        };
+    }
+
+    #[test]
+    fn test_default_fields() {
+        let collection = new_collection(&["title^3", "title^0.5", "title"]).unwrap();
+        assert_eq!(
+            collection.default_fields,
+            vec![("title".to_owned(), 3.0), ("title".to_owned(), 0.5), ("title".to_owned(), 1.0)]
+        );
+        assert_eq!(
+            new_collection(&["count"]).err(),
+            Some(SchemaError::new("count", "default fields must be text fields"))
+        );
+        assert_eq!(
+            new_collection(&["body"]).err(),
+            Some(SchemaError::new("body", "unknown field"))
+        );
+        assert_eq!(
+            new_collection(&["title^-1"]).err(),
+            Some(SchemaError::new("title", "invalid default field boost"))
+        );
+    }
+
+    #[test]
+    fn test_bm25_parameters() {
+        let parse = |body: &str| -> Result<Bm25, SchemaError> {
+            let definition: CreateRequest = serde_json::from_str(body).unwrap();
+            let data = CollectionBuf::new("test".to_owned(), bytes::Bytes::new());
+            return Collection::new(definition, data).map(|collection| collection.bm25);
+        };
+        assert_eq!(parse(r#"{"name":"a","indexes":{}}"#), Ok(Bm25 { k1: 1.2, b: 0.75 }));
+        assert_eq!(parse(r#"{"name":"a","indexes":{},"bm25":{"k1":2}}"#), Ok(Bm25 { k1: 2.0, b: 0.75 }));
+        assert_eq!(
+            parse(r#"{"name":"a","indexes":{},"bm25":{"k1":-1}}"#),
+            Err(SchemaError::new("bm25", "k1 must be a finite number of at least 0"))
+        );
+        assert_eq!(
+            parse(r#"{"name":"a","indexes":{},"bm25":{"b":1.5}}"#),
+            Err(SchemaError::new("bm25", "b must be between 0 and 1"))
+        );
+    }
+
+    #[test]
+    fn test_analyzers() {
+        let parse = |analyzers: &str| -> Result<HashMap<String, Analyzer>, SchemaError> {
+            let body = format!(r#"{{"name":"a","indexes":{{"title":"Text","count":"Integer"}},"analyzers":{}}}"#, analyzers);
+            let definition: CreateRequest = serde_json::from_str(&body).unwrap();
+            let data = CollectionBuf::new("test".to_owned(), bytes::Bytes::new());
+            return Collection::new(definition, data).map(|collection| collection.analyzers);
+        };
+        assert_eq!(parse(r#"{"title":"english"}"#), Ok(HashMap::from([("title".to_owned(), Analyzer::ENGLISH)])));
+        assert_eq!(parse(r#"{"title":"standard"}"#), Ok(HashMap::new()));
+        let filters = vec![TokenFilter::AsciiFolding, TokenFilter::Lowercase];
+        assert_eq!(
+            parse(r#"{"title":["ascii_folding","lowercase"]}"#),
+            Ok(HashMap::from([("title".to_owned(), Analyzer::new(filters))]))
+        );
+        assert_eq!(
+            parse(r#"{"title":"french"}"#),
+            Err(SchemaError::new("title", "unknown analyzer, expected standard or english"))
+        );
+        assert_eq!(parse(r#"{"title":["snowball"]}"#), Err(SchemaError::new("title", "unknown token filter")));
+        assert_eq!(
+            parse(r#"{"count":"english"}"#),
+            Err(SchemaError::new("count", "analyzers are only for text fields"))
+        );
+        assert_eq!(parse(r#"{"body":"english"}"#), Err(SchemaError::new("body", "unknown field")));
+    }
+
+    #[test]
+    fn test_synonyms() {
+        let collection = |synonyms: &[&str]| -> Result<Collection, SchemaError> {
+            let mut definition = definition(&[]);
+            definition.synonyms = synonyms.iter().map(|set| set.to_string()).collect();
+            definition.analyzers = HashMap::from([("title".to_owned(), AnalyzerConfig::Name("english".to_owned()))]);
+            definition.indexes.insert("body".to_owned(), IndexType::Text);
+            return Collection::new(definition, CollectionBuf::new("test".to_owned(), bytes::Bytes::new()));
+        };
+        let c = collection(&["Couch, sofa, settee", "tv, television set", "lamp, lamps"]).unwrap();
+        assert_eq!(c.synonyms.len(), 2);
+        // Words are matched in the analyzed form of the field.
+        assert_eq!(c.synonyms("title", "Couches"), vec!["sofa", "settee"]);
+        assert_eq!(c.synonyms("body", "Couch"), vec!["sofa", "settee"]);
+        assert_eq!(c.synonyms("body", "couches"), Vec::<&str>::new());
+        assert_eq!(c.synonyms("title", "television sets"), vec!["tv"]);
+        assert_eq!(c.synonyms("body", "lamps"), vec!["lamp"]);
+        assert_eq!(c.synonyms("title", "lamps"), Vec::<&str>::new());
+        assert_eq!(c.synonyms("count", "1"), Vec::<&str>::new());
+        assert_eq!(
+            collection(&["sofa", "tv, tv"]).err(),
+            Some(SchemaError::new("synonyms", "synonym sets need at least two words"))
+        );
+        assert!(collection(&[]).unwrap().synonyms.is_empty());
+    }
+
+    #[test]
+    fn test_read_synonyms_file() {
+        let dir = std::env::temp_dir();
+        let name = format!("finne_synonyms_test_{}.txt", std::process::id());
+        std::fs::write(dir.join(&name), "# Furniture\ncouch, sofa, settee\n\n  tv, television set\n").unwrap();
+        assert_eq!(
+            read_synonyms_file(Some(&dir), &name),
+            Ok(vec!["couch, sofa, settee".to_owned(), "tv, television set".to_owned()])
+        );
+        assert_eq!(
+            read_synonyms_file(None, &name),
+            Err(SchemaError::new("synonyms_file", "the server has no synonyms directory"))
+        );
+        for name in ["/etc/passwd", "../passwd", "a/b", "", "."] {
+            assert_eq!(
+                read_synonyms_file(Some(&dir), name).err(),
+                Some(SchemaError::new("synonyms_file", "expected a file name without a directory")),
+                "{}",
+                name
+            );
+        }
+        std::fs::remove_file(dir.join(&name)).unwrap();
+        assert_eq!(
+            read_synonyms_file(Some(&dir), &name),
+            Err(SchemaError::new("synonyms_file", "could not read the synonyms file"))
+        );
+    }
+
+    #[test]
+    fn test_invalid_queries() {
+        assert_eq!(
+            validate("fox"),
+            Err(SchemaError::new("", "terms without a field need default fields"))
+        );
+        assert_eq!(validate("body:fox"), Err(SchemaError::new("body", "unknown field")));
+        assert_eq!(
+            validate("title:[a TO b]"),
+            Err(SchemaError::new("title", "range queries need a numeric field"))
+        );
+        assert_eq!(
+            validate("count:10~1"),
+            Err(SchemaError::new("count", "fuzzy queries need a text field"))
+        );
+        assert_eq!(validate("count:1.5"), Err(SchemaError::new("count", "expected an integer")));
+        assert_eq!(
+            validate("price:[one TO 2]"),
+            Err(SchemaError::new("price", "expected a real number"))
+        );
+        assert_eq!(validate("price:inf"), Err(SchemaError::new("price", "expected a real number")));
+        assert_eq!(
+            validate("loc:52,4"),
+            Err(SchemaError::new("loc", "geo fields need a bounding box or distance query"))
+        );
+        assert_eq!(
+            validate("title:52,4~1km"),
+            Err(SchemaError::new("title", "geo queries need a geo point field"))
+        );
+        assert_eq!(
+            validate("loc:[20,0 TO 10,1]"),
+            Err(SchemaError::new("loc", "invalid bounding box"))
+        );
+        assert_eq!(validate("loc:91,0~1km"), Err(SchemaError::new("loc", "invalid distance query")));
+        let mut nodes = Vec::new();
+        parse_query(b"title:fox^3e38", &mut nodes).unwrap();
+        nodes[0].term.term_boost *= 3.0;
+        assert_eq!(
+            validate_query(&indexes(), &mut nodes),
+            Err(SchemaError::new("title", "boost must be a finite number"))
+        );
+    }
+}
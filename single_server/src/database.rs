@@ -0,0 +1,525 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use serde_json::Value;
+
+use storage::segment::TieredMergePolicy;
+use storage::wal::{FsyncPolicy, Wal, WalRecord};
+use storage::{save_collections, CollectionBuf, MemoryBuf, SegmentFileWriter, StorageError};
+
+use crate::collection::{document_fields, read_synonyms_file, Collection, CreateRequest};
+use crate::Error;
+
+// Logged updates and deletes after which everything is saved and the log is emptied.
+const MAX_UNSAVED_CHANGES: usize = 10_000;
+// Documents, postings or index records merged per event loop iteration.
+const MERGE_STEP_BUDGET: usize = 2_000;
+// Bytes of merged segment files written per event loop iteration.
+const MERGE_WRITE_BUDGET: usize = 1 << 20;
+// The in-memory segment of a collection is flushed once it holds REFRESH_DOCS documents or its
+// first document waited REFRESH_INTERVAL. Flushing before every search would turn every write
+// followed by a search into a tiny segment.
+const REFRESH_DOCS: u32 = 1_000;
+const REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+
+// The collections served from memory, the database file they were loaded from and the
+// write-ahead log of the changes made since it was saved.
+pub struct Database {
+    pub path: PathBuf,
+    pub collections: HashMap<String, Collection>,
+    pub merge_policy: TieredMergePolicy,
+    // Where the synonyms files named by create requests are read from, none when unset.
+    pub synonyms_dir: Option<PathBuf>,
+    next_segment_id: u64,
+    // Merged segments being written to their files.
+    segment_writes: Vec<SegmentFileWriter>,
+    wal: Wal,
+    unsaved_changes: usize,
+}
+
+impl Database {
+    // Loads the database file, a missing file is an empty database, and replays the log on top.
+    pub fn open(path: &Path, mmap: bool, policy: FsyncPolicy) -> Result<Database, String> {
+        let db = if mmap {
+            MemoryBuf::from_file_mmap(path)
+        } else {
+            MemoryBuf::from_file(path)
+        };
+        let db = match db {
+            Ok(db) => db,
+            Err(StorageError::Io(e)) if e.kind() == io::ErrorKind::NotFound => MemoryBuf::default(),
+            Err(e) => return Err(e.to_string()),
+        };
+        let next_segment_id = db.next_segment_id;
+        let mut collections = HashMap::with_capacity(db.collections.len());
+        for data in db.collections {
+            let name = data.name.clone();
+            match new_collection(data) {
+                Ok(collection) => collections.insert(name, collection),
+                Err(e) => return Err(format!("invalid definition of collection '{}': {}", name, e)),
+            };
+        }
+
+        let mut wal_path = path.as_os_str().to_owned();
+        wal_path.push(".wal");
+        let wal_path = PathBuf::from(wal_path);
+        let replayed = Wal::replay(&wal_path, |record| replay(&mut collections, record));
+        let replayed = replayed.map_err(|e| format!("could not replay {}: {}", wal_path.display(), e))?;
+        let wal = match Wal::open(&wal_path, policy) {
+            Ok(wal) => wal,
+            Err(e) => return Err(format!("could not open {}: {}", wal_path.display(), e)),
+        };
+        println!(
+            "Loaded {} collections from {}, replayed {} log records",
+            collections.len(),
+            path.display(),
+            replayed
+        );
+        let mut db = Database {
+            path: path.to_owned(),
+            collections,
+            merge_policy: TieredMergePolicy::default(),
+            synonyms_dir: None,
+            next_segment_id,
+            segment_writes: Vec::new(),
+            wal,
+            unsaved_changes: 0,
+        };
+        if replayed > 0 {
+            db.checkpoint().map_err(|e| format!("could not save {}: {}", path.display(), e))?;
+        }
+        return Ok(db);
+    }
+
+    pub fn create(&mut self, body: &[u8]) -> Result<(), Error> {
+        let name = match serde_json::from_slice::<CreateRequest>(body) {
+            Ok(req) => req.name,
+            Err(e) => {
+                println!("Error parsing request: {:?}", e);
+                return Err(Error::InvalidRequest);
+            }
+        };
+        if self.collections.contains_key(&name) {
+            println!("Collection already exists: {}", name);
+            return Err(Error::InvalidRequest);
+        }
+        let collection = inline_synonyms_file(body, self.synonyms_dir.as_deref())
+            .and_then(|definition| new_collection(CollectionBuf::new(name.clone(), definition)));
+        let collection = match collection {
+            Ok(collection) => collection,
+            Err(e) => {
+                println!("Invalid collection definition: {}", e);
+                return Err(e);
+            }
+        };
+        let record = WalRecord::Create {
+            collection: &name,
+            definition: &collection.data.definition,
+        };
+        self.wal.append(&record).map_err(|e| Error::Storage(StorageError::Io(e)))?;
+        self.collections.insert(name, collection);
+        // Collections are rarely created, save them right away instead of on the next start.
+        if let Err(e) = self.checkpoint() {
+            println!("Error saving database, the change stays in the log: {}", e);
+        }
+        return Ok(());
+    }
+
+    // Stores and indexes the document, replacing the document with the same id. It is searchable
+    // after the next refresh. Returns whether a document was replaced.
+    pub fn update(&mut self, collection: &str, id: &str, doc: &[u8]) -> Result<bool, Error> {
+        let c = match self.collections.get_mut(collection) {
+            Some(c) => c,
+            None => return Err(Error::UnknownCollection),
+        };
+        // Checked before logging, so a logged document can always be indexed.
+        let doc_value = parse_document(doc)?;
+        let mut fields = Vec::new();
+        document_fields(&c.definition.indexes, &c.analyzers, &doc_value, &mut fields).map_err(Error::Schema)?;
+        let record = WalRecord::Update { collection, id, doc };
+        self.wal.append(&record).map_err(|e| Error::Storage(StorageError::Io(e)))?;
+        let existed = c.data.update(id, doc, &fields);
+        c.unflushed_since.get_or_insert_with(Instant::now);
+        self.logged_change();
+        return Ok(existed);
+    }
+
+    // Returns whether the document existed.
+    pub fn delete(&mut self, collection: &str, id: &str) -> Result<bool, Error> {
+        let c = match self.collections.get_mut(collection) {
+            Some(c) => c,
+            None => return Err(Error::UnknownCollection),
+        };
+        if !c.data.contains(id) {
+            return Ok(false);
+        }
+        let record = WalRecord::Delete { collection, id };
+        self.wal.append(&record).map_err(|e| Error::Storage(StorageError::Io(e)))?;
+        c.data.delete(id);
+        self.logged_change();
+        return Ok(true);
+    }
+
+    // Replaces the synonym sets of the collection definition with the JSON array of sets, also
+    // those read from a synonyms file when it was created.
+    pub fn set_synonyms(&mut self, collection: &str, body: &[u8]) -> Result<(), Error> {
+        let c = match self.collections.get_mut(collection) {
+            Some(c) => c,
+            None => return Err(Error::UnknownCollection),
+        };
+        let synonyms: Vec<String> = match serde_json::from_slice(body) {
+            Ok(synonyms) => synonyms,
+            Err(e) => {
+                println!("Error parsing synonyms: {}", e);
+                return Err(Error::InvalidRequest);
+            }
+        };
+        let mut definition: Value = serde_json::from_slice(&c.data.definition).map_err(|_| Error::InvalidRequest)?;
+        match definition.as_object_mut() {
+            Some(definition) => definition.insert("synonyms".to_owned(), Value::from(synonyms)),
+            None => return Err(Error::InvalidRequest),
+        };
+        // Definitions are JSON values, serializing cannot fail.
+        let definition = serde_json::to_vec(&definition).unwrap();
+        // Checked before logging, like documents.
+        let new = new_collection(CollectionBuf::new(collection.to_owned(), Bytes::from(definition)))?;
+        let record = WalRecord::Define {
+            collection,
+            definition: &new.data.definition,
+        };
+        self.wal.append(&record).map_err(|e| Error::Storage(StorageError::Io(e)))?;
+        redefine(c, new);
+        self.logged_change();
+        return Ok(());
+    }
+
+    // Flushes the in-memory segments so searches see every stored document.
+    pub fn refresh(&mut self) {
+        for collection in self.collections.values_mut() {
+            flush(collection, &mut self.next_segment_id);
+        }
+    }
+
+    // Flushes the in-memory segment of the collection when it is due, see REFRESH_INTERVAL.
+    pub fn refresh_if_due(&mut self, collection: &str) {
+        if let Some(collection) = self.collections.get_mut(collection) {
+            flush_if_due(collection, Instant::now(), &mut self.next_segment_id);
+        }
+    }
+
+    // Called from the event loop, flushes the in-memory segments that are due.
+    pub fn refresh_due(&mut self) {
+        let now = Instant::now();
+        for collection in self.collections.values_mut() {
+            flush_if_due(collection, now, &mut self.next_segment_id);
+        }
+    }
+
+    // Called from the event loop, runs a slice of the running merges and of the writes of the
+    // merged segments, and saves once a merged segment file is complete. A failed write is
+    // dropped, the next save writes the segment.
+    pub fn merge_step(&mut self) {
+        for collection in self.collections.values_mut() {
+            let merged = collection.data.merge_step(&self.merge_policy, MERGE_STEP_BUDGET, &mut self.next_segment_id);
+            if let Some(merged) = merged {
+                let id = merged.id;
+                match SegmentFileWriter::create(&self.path, merged) {
+                    Ok(writer) => self.segment_writes.push(writer),
+                    Err(e) => println!("Error writing merged segment {}: {}", id, e),
+                }
+            }
+        }
+        let mut written = false;
+        self.segment_writes.retain_mut(|writer| match writer.step(MERGE_WRITE_BUDGET) {
+            Ok(done) => {
+                written |= done;
+                !done
+            }
+            Err(e) => {
+                println!("Error writing merged segment {}: {}", writer.segment_id(), e);
+                false
+            }
+        });
+        if written {
+            if let Err(e) = self.checkpoint() {
+                println!("Error saving merged segments: {}", e);
+            }
+        }
+    }
+
+    // Called from the event loop for the batched fsync policy.
+    #[inline]
+    pub fn sync_if_due(&mut self) {
+        if let Err(e) = self.wal.sync_if_due() {
+            println!("Error syncing {}: {}", self.wal.path().display(), e);
+        }
+    }
+
+    // The change is already logged, so a failed save does not fail the request, the log keeps
+    // growing until a later save works.
+    #[inline]
+    fn logged_change(&mut self) {
+        self.unsaved_changes += 1;
+        if self.unsaved_changes >= MAX_UNSAVED_CHANGES {
+            if let Err(e) = self.checkpoint() {
+                println!("Error saving database, the changes stay in the log: {}", e);
+            }
+        }
+    }
+
+    // Flushes and saves all collections, after which the log is no longer needed.
+    fn checkpoint(&mut self) -> Result<(), StorageError> {
+        self.refresh();
+        save_collections(&self.path, self.collections.values_mut().map(|c| &mut c.data))?;
+        self.wal.reset()?;
+        self.unsaved_changes = 0;
+        return Ok(());
+    }
+}
+
+#[inline]
+fn flush(collection: &mut Collection, next_segment_id: &mut u64) {
+    collection.data.flush(next_segment_id);
+    collection.unflushed_since = None;
+}
+
+#[inline]
+fn flush_if_due(collection: &mut Collection, now: Instant, next_segment_id: &mut u64) {
+    let due = match collection.unflushed_since {
+        Some(since) => collection.data.writer.len() >= REFRESH_DOCS || now.duration_since(since) >= REFRESH_INTERVAL,
+        None => false,
+    };
+    if due {
+        flush(collection, next_segment_id);
+    }
+}
+
+// Replaces the synonyms_file of a create request with the sets read from it, stored
+// definitions never refer to a file so loading them cannot fail on one.
+fn inline_synonyms_file(body: &[u8], synonyms_dir: Option<&Path>) -> Result<Bytes, Error> {
+    let mut definition: Value = serde_json::from_slice(body).map_err(|_| Error::InvalidRequest)?;
+    let fields = definition.as_object_mut().ok_or(Error::InvalidRequest)?;
+    let file = match fields.remove("synonyms_file") {
+        None | Some(Value::Null) => return Ok(Bytes::copy_from_slice(body)),
+        Some(Value::String(file)) => file,
+        Some(_) => return Err(Error::InvalidRequest),
+    };
+    let sets = read_synonyms_file(synonyms_dir, &file).map_err(Error::Schema)?;
+    match fields.entry("synonyms").or_insert_with(|| Value::Array(Vec::new())) {
+        Value::Array(synonyms) => synonyms.extend(sets.into_iter().map(Value::from)),
+        _ => return Err(Error::InvalidRequest),
+    }
+    // Definitions are JSON values, serializing cannot fail.
+    return Ok(Bytes::from(serde_json::to_vec(&definition).unwrap()));
+}
+
+#[inline]
+fn new_collection(data: CollectionBuf) -> Result<Collection, Error> {
+    let definition = match serde_json::from_slice::<CreateRequest>(&data.definition) {
+        Ok(definition) => definition,
+        Err(_) => return Err(Error::InvalidRequest),
+    };
+    return Collection::new(definition, data).map_err(Error::Schema);
+}
+
+// Moves the documents of a collection over to its new definition. Only the synonyms are
+// changed this way, the indexed fields stay the same.
+#[inline]
+fn redefine(collection: &mut Collection, new: Collection) {
+    let definition = new.data.definition.clone();
+    let old = std::mem::replace(collection, new);
+    collection.data = old.data;
+    collection.data.definition = definition;
+}
+
+#[inline]
+fn parse_document(doc: &[u8]) -> Result<Value, Error> {
+    return match serde_json::from_slice(doc) {
+        Ok(doc) => Ok(doc),
+        Err(e) => {
+            println!("Document is not valid JSON: {}", e);
+            Err(Error::InvalidRequest)
+        }
+    };
+}
+
+fn replay_update(collection: &mut Collection, id: &str, doc: &[u8]) -> Result<(), Error> {
+    let doc_value = parse_document(doc)?;
+    let mut fields = Vec::new();
+    let (indexes, analyzers) = (&collection.definition.indexes, &collection.analyzers);
+    document_fields(indexes, analyzers, &doc_value, &mut fields).map_err(Error::Schema)?;
+    collection.data.update(id, doc, &fields);
+    collection.unflushed_since.get_or_insert_with(Instant::now);
+    return Ok(());
+}
+
+// Records are only logged after they were checked, so failing to apply one means the log
+// does not belong to this database file.
+fn replay(collections: &mut HashMap<String, Collection>, record: WalRecord<'_>) {
+    match record {
+        WalRecord::Create { collection, definition } => {
+            if collections.contains_key(collection) {
+                println!("Skipping logged create of existing collection {}", collection);
+                return;
+            }
+            let data = CollectionBuf::new(collection.to_owned(), Bytes::copy_from_slice(definition));
+            match new_collection(data) {
+                Ok(new) => {
+                    collections.insert(collection.to_owned(), new);
+                }
+                Err(e) => println!("Skipping logged create of collection {}: {}", collection, e),
+            }
+        }
+        WalRecord::Update { collection, id, doc } => match collections.get_mut(collection) {
+            Some(c) => {
+                if let Err(e) = replay_update(c, id, doc) {
+                    println!("Skipping logged update of document {} in {}: {}", id, collection, e);
+                }
+            }
+            None => println!("Skipping logged update of unknown collection {}", collection),
+        },
+        WalRecord::Delete { collection, id } => match collections.get_mut(collection) {
+            Some(c) => {
+                c.data.delete(id);
+            }
+            None => println!("Skipping logged delete of unknown collection {}", collection),
+        },
+        WalRecord::Define { collection, definition } => match collections.get_mut(collection) {
+            Some(c) => {
+                let data = CollectionBuf::new(collection.to_owned(), Bytes::copy_from_slice(definition));
+                match new_collection(data) {
+                    Ok(new) => redefine(c, new),
+                    Err(e) => println!("Skipping logged definition of collection {}: {}", collection, e),
+                }
+            }
+            None => println!("Skipping logged definition of unknown collection {}", collection),
+        },
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn test_path(name: &str) -> PathBuf {
+        return std::env::temp_dir().join(format!("finne_{}_test_{}.db", name, std::process::id()));
+    }
+
+    // Removes the database file, its write-ahead log and its segments.
+    fn remove_database(path: &Path) {
+        let prefix = path.file_name().unwrap().to_str().unwrap();
+        for entry in std::fs::read_dir(path.parent().unwrap()).unwrap() {
+            let entry = entry.unwrap();
+            if entry.file_name().to_str().is_some_and(|name| name.starts_with(prefix)) {
+                std::fs::remove_file(entry.path()).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn test_replay_after_restart() {
+        let path = test_path("replay");
+        let mut db = Database::open(&path, false, FsyncPolicy::Never).unwrap();
+        assert!(db.create(br#"{"name":"pets","indexes":{"al":"Text"}}"#).is_ok());
+        assert_eq!(db.update("pets", "1", br#"{"al":"cat"}"#).ok(), Some(false));
+        assert_eq!(db.update("pets", "1", br#"{"al":"dog"}"#).ok(), Some(true));
+        assert_eq!(db.update("pets", "0", br#"{"al":"cow"}"#).ok(), Some(false));
+        assert_eq!(db.delete("pets", "0").ok(), Some(true));
+        assert_eq!(db.delete("pets", "0").ok(), Some(false));
+        assert!(db.update("pets", "2", br#"{"al":3}"#).is_err());
+        assert!(db.update("birds", "1", b"{}").is_err());
+        assert!(db.delete("birds", "1").is_err());
+        assert!(db.set_synonyms("pets", br#"["cat, kitten"]"#).is_ok());
+        assert!(db.set_synonyms("pets", br#"["cat"]"#).is_err());
+        assert!(db.set_synonyms("birds", br#"[]"#).is_err());
+        drop(db);
+
+        // The changes were only logged, opening replays them and saves them in a segment.
+        let db = Database::open(&path, true, FsyncPolicy::Never).unwrap();
+        let pets = &db.collections["pets"].data;
+        assert_eq!(pets.get("1"), Some(&br#"{"al":"dog"}"#[..]));
+        assert_eq!(pets.get("0"), None);
+        assert_eq!(pets.segments.len(), 1);
+        assert_eq!(db.collections["pets"].synonyms("al", "Kitten"), vec!["cat"]);
+        let mut wal_path = path.as_os_str().to_owned();
+        wal_path.push(".wal");
+        assert_eq!(std::fs::metadata(&wal_path).unwrap().len(), 0);
+        drop(db);
+        remove_database(&path);
+    }
+
+    #[test]
+    fn test_merge_steps() {
+        let path = test_path("merge");
+        let mut db = Database::open(&path, false, FsyncPolicy::Never).unwrap();
+        assert!(db.create(br#"{"name":"pets","indexes":{"al":"Text"}}"#).is_ok());
+
+        // Every refresh adds a segment, the merges run in steps until one segment is left.
+        db.merge_policy.segments_per_tier = 2;
+        for id in 2..6 {
+            assert!(db.update("pets", &id.to_string(), br#"{"al":"cat"}"#).is_ok());
+            db.refresh();
+        }
+        let mut steps = 0;
+        let merging = |db: &Database| db.collections["pets"].data.is_merging() || !db.segment_writes.is_empty();
+        while db.collections["pets"].data.segments.len() > 1 || merging(&db) {
+            db.merge_step();
+            steps += 1;
+        }
+        assert!(steps > 1);
+        drop(db);
+
+        let db = Database::open(&path, false, FsyncPolicy::Never).unwrap();
+        let pets = &db.collections["pets"].data;
+        assert_eq!(pets.segments.len(), 1);
+        assert_eq!(pets.segments[0].indexes["al"].lookup("cat").unwrap().doc_count(), 4);
+        drop(db);
+        remove_database(&path);
+    }
+
+    #[test]
+    fn test_refresh_when_due() {
+        let path = test_path("refresh");
+        let mut db = Database::open(&path, false, FsyncPolicy::Never).unwrap();
+        assert!(db.create(br#"{"name":"pets","indexes":{"al":"Text"}}"#).is_ok());
+
+        // Writes are flushed once the first of them waited long enough, not on every search.
+        assert!(db.update("pets", "6", br#"{"al":"cow"}"#).is_ok());
+        db.refresh_if_due("pets");
+        db.refresh_due();
+        assert_eq!(db.collections["pets"].data.segments.len(), 0);
+        let pets = db.collections.get_mut("pets").unwrap();
+        pets.unflushed_since = pets.unflushed_since.map(|since| since - REFRESH_INTERVAL);
+        db.refresh_if_due("pets");
+        assert_eq!(db.collections["pets"].data.segments.len(), 1);
+        assert_eq!(db.collections["pets"].unflushed_since, None);
+        drop(db);
+        remove_database(&path);
+    }
+
+    #[test]
+    fn test_synonyms_file() {
+        let path = test_path("synonyms");
+        let mut db = Database::open(&path, false, FsyncPolicy::Never).unwrap();
+
+        // A synonyms file is read from the synonyms directory once, its sets are stored.
+        let synonyms_path = path.with_extension("synonyms");
+        std::fs::write(&synonyms_path, "owl, hooter\n").unwrap();
+        let synonyms_file = synonyms_path.file_name().unwrap().to_str().unwrap();
+        let birds = format!(r#"{{"name":"birds","indexes":{{"al":"Text"}},"synonyms_file":"{}"}}"#, synonyms_file);
+        assert!(db.create(birds.as_bytes()).is_err());
+        db.synonyms_dir = Some(std::env::temp_dir());
+        assert!(db.create(br#"{"name":"birds","indexes":{},"synonyms_file":"../passwd"}"#).is_err());
+        assert!(db.create(birds.as_bytes()).is_ok());
+        std::fs::remove_file(&synonyms_path).unwrap();
+        drop(db);
+
+        let db = Database::open(&path, false, FsyncPolicy::Never).unwrap();
+        assert_eq!(db.collections["birds"].synonyms("al", "owl"), vec!["hooter"]);
+        drop(db);
+        remove_database(&path);
+    }
+}
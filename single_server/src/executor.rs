@@ -0,0 +1,664 @@
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
+use std::ops::Bound;
+use std::rc::Rc;
+
+use finne_parser::query_parser::{NodeType, QueryNode, Term, TermType};
+use finne_parser::query_rewriter::Rewrite;
+use storage::doc_ids::{
+    competitive, AllDocs, DocIdIterator, Difference, Intersection, Phrase, PostingsIterator, SortedDocIds, Union,
+    NO_MORE_DOCS,
+};
+use storage::analysis::Analyzer;
+use storage::indexes::{Index, Number};
+use storage::scoring::{Bm25, Bm25Scorer};
+use storage::segment::Segment;
+
+use crate::collection::{field_analyzer, IndexType};
+
+/*
+ * Runs a rewritten query on every segment of a collection. Each Term opens an iterator on the
+ * field's index: words and phrases read posting lists, wildcard and fuzzy terms the postings
+ * of every term they match, numbers, ranges and geo queries collect the doc ids of a scan.
+ * Words and phrases are analyzed into terms with the field's analyzer, as its text was, and
+ * wildcard patterns and fuzzy words are only lowercased or folded as the analyzer does.
+ * And nodes become an Intersection of their operands minus a Union of their negated operands,
+ * Or nodes a Union and a lone Not the Difference with all documents of the segment.
+ *
+ * Words and phrases are scored with BM25 using the field statistics of all segments, so
+ * scores of different segments compare. The term boost, which already holds the field boost of
+ * a default field, multiplies the score. Terms read from a scan score their boost.
+ *
+ * Only the best hits up to the limit are kept, in a heap with the worst of them on top. Once it
+ * is full the worst score is the min score a document has to beat: a segment whose iterators
+ * cannot score more is left early and the unions of the query skip the documents whose terms
+ * cannot add up to more. Documents are visited in segment order and a later document with the
+ * same score as the worst hit does not replace it, so the hits are exactly the first ones of
+ * all matching documents sorted by score.
+ *
+ * The query must have passed validate_query, numeric values are in their canonical form.
+ */
+
+// A matching document: the position of its segment in the collection and its doc id there.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Hit {
+    pub segment: usize,
+    pub doc_id: u32,
+    pub score: f32,
+}
+
+// The number of matching documents seen, a lower bound once documents that could not make
+// the hits were skipped.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Total {
+    pub count: usize,
+    pub exact: bool,
+}
+
+// The schema and segments of the collection a query runs on.
+pub struct Searcher<'a> {
+    pub indexes: &'a HashMap<String, IndexType>,
+    pub analyzers: &'a HashMap<String, Analyzer>,
+    pub bm25: Bm25,
+    pub segments: &'a [Rc<Segment>],
+}
+
+// Appends the best `limit` hits of the query, highest score first and otherwise in segment order.
+pub fn execute(
+    searcher: &Searcher<'_>,
+    nodes: &[QueryNode<'_>],
+    rewrite: Rewrite,
+    limit: usize,
+    hits: &mut Vec<Hit>,
+) -> Total {
+    let mut top = TopHits {
+        heap: BinaryHeap::new(),
+        limit,
+    };
+    let mut total = Total { count: 0, exact: true };
+    for (i, segment) in searcher.segments.iter().enumerate() {
+        let mut doc_ids = match rewrite {
+            Rewrite::NeverMatches => break,
+            Rewrite::MatchesAll => Box::new(AllDocs::new(segment.len())),
+            Rewrite::Root(root) => searcher.node_doc_ids(segment, nodes, root),
+        };
+        let mut min_score = None;
+        let mut doc_id = doc_ids.doc_id();
+        while doc_id != NO_MORE_DOCS {
+            if top.min_score() != min_score {
+                min_score = top.min_score();
+                let min_score = min_score.unwrap();
+                total.exact = false;
+                if !competitive(doc_ids.max_score(), min_score) {
+                    break;
+                }
+                doc_ids.set_min_score(min_score);
+            }
+            if !segment.deletes.is_deleted(doc_id) {
+                total.count += 1;
+                top.insert(Hit {
+                    segment: i,
+                    doc_id,
+                    score: doc_ids.score(),
+                });
+            }
+            doc_id = doc_ids.next();
+        }
+    }
+    hits.extend(top.heap.into_sorted_vec().into_iter().map(|ranked| ranked.0));
+    return total;
+}
+
+struct TopHits {
+    heap: BinaryHeap<Ranked>,
+    limit: usize,
+}
+
+impl TopHits {
+    // The score to beat once there are `limit` hits.
+    #[inline]
+    fn min_score(&self) -> Option<f32> {
+        if self.heap.len() < self.limit {
+            return None;
+        }
+        return Some(self.heap.peek().map_or(f32::INFINITY, |worst| worst.0.score));
+    }
+
+    // Hits are inserted in segment order, so one that ties with the worst hit is worse.
+    #[inline]
+    fn insert(&mut self, hit: Hit) {
+        if self.heap.len() < self.limit {
+            self.heap.push(Ranked(hit));
+        } else if let Some(mut worst) = self.heap.peek_mut() {
+            if hit.score > worst.0.score {
+                *worst = Ranked(hit);
+            }
+        }
+    }
+}
+
+// Orders hits from best to worst, the greatest is the worst one.
+struct Ranked(Hit);
+
+impl Ord for Ranked {
+    #[inline]
+    fn cmp(&self, other: &Self) -> Ordering {
+        let (hit, other) = (&self.0, &other.0);
+        return other
+            .score
+            .total_cmp(&hit.score)
+            .then_with(|| (hit.segment, hit.doc_id).cmp(&(other.segment, other.doc_id)));
+    }
+}
+
+impl PartialOrd for Ranked {
+    #[inline]
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        return Some(self.cmp(other));
+    }
+}
+
+impl PartialEq for Ranked {
+    #[inline]
+    fn eq(&self, other: &Self) -> bool {
+        return self.cmp(other) == Ordering::Equal;
+    }
+}
+
+impl Eq for Ranked {}
+
+impl<'a> Searcher<'a> {
+    fn node_doc_ids(&self, segment: &'a Segment, nodes: &[QueryNode<'_>], idx: usize) -> Box<dyn DocIdIterator + 'a> {
+        let node = &nodes[idx];
+        return match node.node_type {
+            NodeType::Term => self.term_doc_ids(segment, &node.term),
+            NodeType::Group => self.node_doc_ids(segment, nodes, node.left.unwrap()),
+            NodeType::Not => Box::new(Difference::new(
+                Box::new(AllDocs::new(segment.len())),
+                self.node_doc_ids(segment, nodes, node.left.unwrap()),
+            )),
+            NodeType::And => {
+                let mut operands = Vec::new();
+                collect_operands(nodes, idx, NodeType::And, &mut operands);
+                let mut include = Vec::new();
+                let mut exclude = Vec::new();
+                for operand in operands {
+                    match nodes[operand].node_type {
+                        NodeType::Not => exclude.push(self.node_doc_ids(segment, nodes, nodes[operand].left.unwrap())),
+                        _ => include.push(self.node_doc_ids(segment, nodes, operand)),
+                    }
+                }
+                let included: Box<dyn DocIdIterator> = match include.len() {
+                    0 => Box::new(AllDocs::new(segment.len())),
+                    1 => include.pop().unwrap(),
+                    _ => Box::new(Intersection::new(include)),
+                };
+                match exclude.len() {
+                    0 => included,
+                    1 => Box::new(Difference::new(included, exclude.pop().unwrap())),
+                    _ => Box::new(Difference::new(included, Box::new(Union::new(exclude)))),
+                }
+            }
+            NodeType::Or => {
+                let mut operands = Vec::new();
+                collect_operands(nodes, idx, NodeType::Or, &mut operands);
+                let operands = operands
+                    .into_iter()
+                    .map(|operand| self.node_doc_ids(segment, nodes, operand))
+                    .collect();
+                Box::new(Union::new(operands))
+            }
+        };
+    }
+
+    fn term_doc_ids(&self, segment: &'a Segment, term: &Term<'_>) -> Box<dyn DocIdIterator + 'a> {
+        let field = term.field.as_ref();
+        let (index_type, index) = match (self.indexes.get(field), segment.indexes.get(field)) {
+            (Some(index_type), Some(index)) => (*index_type, index),
+            // No document of the segment has the field.
+            _ => return no_doc_ids(),
+        };
+        let boost = term.term_boost;
+        return match (index_type, &term.term_type) {
+            (IndexType::Text, TermType::Word | TermType::Boosted | TermType::Phrase) => {
+                self.phrase_doc_ids(field, index, &term.value, 0, boost)
+            }
+            (IndexType::Text, TermType::Proximity(slop)) => self.phrase_doc_ids(field, index, &term.value, *slop, boost),
+            (IndexType::Text, TermType::Wildcard) => {
+                let pattern: Vec<char> = field_analyzer(self.analyzers, field).normalize(&term.value).chars().collect();
+                let prefix: String = pattern.iter().take_while(|c| **c != '*' && **c != '?').collect();
+                expanded_doc_ids(index, prefix.as_bytes(), |word| wildcard_match(&pattern, word), boost)
+            }
+            (IndexType::Text, TermType::Fuzzy(distance)) => {
+                let word: Vec<char> = field_analyzer(self.analyzers, field).normalize(&term.value).chars().collect();
+                let matches = |candidate: &str| within_edit_distance(&word, candidate, *distance as usize);
+                expanded_doc_ids(index, b"", matches, boost)
+            }
+            (IndexType::Integer | IndexType::Real, TermType::Word | TermType::Boosted) => {
+                match parse_number(index_type, &term.value) {
+                    Some(value) => Box::new(SortedDocIds::new(index.equal(value).collect()).with_score(boost)),
+                    None => no_doc_ids(),
+                }
+            }
+            (IndexType::Integer | IndexType::Real, TermType::Range(lower, upper)) => {
+                match (parse_bound(index_type, lower), parse_bound(index_type, upper)) {
+                    (Some(lower), Some(upper)) => {
+                        Box::new(SortedDocIds::new(index.range(lower, upper).collect()).with_score(boost))
+                    }
+                    _ => no_doc_ids(),
+                }
+            }
+            (IndexType::GeoPoint, TermType::GeoBox(lower, upper)) => {
+                let mut doc_ids = Vec::new();
+                index.within_box(lower.lat, lower.lon, upper.lat, upper.lon, &mut doc_ids);
+                Box::new(SortedDocIds::new(doc_ids).with_score(boost))
+            }
+            (IndexType::GeoPoint, TermType::GeoDistance(center, meters)) => {
+                let mut doc_ids = Vec::new();
+                index.within_distance(center.lat, center.lon, *meters, &mut doc_ids);
+                Box::new(SortedDocIds::new(doc_ids).with_score(boost))
+            }
+            // Rejected by validate_query.
+            _ => no_doc_ids(),
+        };
+    }
+
+    // A word the analyzer splits, like "e-mail", is searched as a phrase of its terms. A
+    // phrase weighs as much as its terms together, the stopwords left out keep their place.
+    fn phrase_doc_ids(&self, field: &str, index: &'a Index, text: &str, slop: u32, boost: f32) -> Box<dyn DocIdIterator + 'a> {
+        let (positions, tokens): (Vec<u32>, Vec<String>) = field_analyzer(self.analyzers, field).analyze(text).unzip();
+        let mut words = Vec::with_capacity(tokens.len());
+        for (position, token) in positions.iter().zip(&tokens) {
+            match index.lookup(token) {
+                Some(postings) => words.push((PostingsIterator::new(postings), position - positions[0])),
+                None => return no_doc_ids(),
+            }
+        }
+        let scorer = self.scorer(field, index, &tokens, boost);
+        return match words.len() {
+            0 => no_doc_ids(),
+            1 => Box::new(words.pop().unwrap().0.scored(scorer)),
+            _ => Box::new(Phrase::new(words, slop).scored(scorer)),
+        };
+    }
+
+    // BM25 of the words in the field of one segment with the statistics of all segments.
+    fn scorer(&self, field: &str, index: &'a Index, words: &[String], boost: f32) -> Bm25Scorer<'a> {
+        let (mut doc_count, mut total_length) = (0, 0);
+        let mut doc_freqs = vec![0; words.len()];
+        for segment in self.segments {
+            if let Some(index) = segment.indexes.get(field) {
+                let stats = index.field_stats();
+                doc_count += stats.doc_count as u64;
+                total_length += stats.total_length;
+                for (word, doc_freq) in words.iter().zip(doc_freqs.iter_mut()) {
+                    *doc_freq += index.lookup(word).map_or(0, |postings| postings.doc_count() as u64);
+                }
+            }
+        }
+        let idf: f32 = doc_freqs.iter().map(|doc_freq| Bm25::idf(doc_count, *doc_freq)).sum();
+        let avg_length = total_length as f32 / doc_count.max(1) as f32;
+        return self.bm25.scorer(boost * idf, avg_length, index);
+    }
+}
+
+// Flattens a chain of nodes of one type, the rewriter emits them left deep.
+fn collect_operands(nodes: &[QueryNode<'_>], idx: usize, node_type: NodeType, operands: &mut Vec<usize>) {
+    let node = &nodes[idx];
+    let node = match node.node_type {
+        NodeType::Group => return collect_operands(nodes, node.left.unwrap(), node_type, operands),
+        t if t == node_type => node,
+        _ => {
+            operands.push(idx);
+            return;
+        }
+    };
+    collect_operands(nodes, node.left.unwrap(), node_type, operands);
+    collect_operands(nodes, node.right.unwrap(), node_type, operands);
+}
+
+#[inline]
+fn no_doc_ids<'a>() -> Box<dyn DocIdIterator + 'a> {
+    return Box::new(SortedDocIds::new(Vec::new()));
+}
+
+// The documents of every term starting with `prefix` that `matches` accepts, all scored
+// with the boost.
+fn expanded_doc_ids<'a>(
+    index: &'a Index,
+    prefix: &[u8],
+    matches: impl Fn(&str) -> bool,
+    boost: f32,
+) -> Box<dyn DocIdIterator + 'a> {
+    let mut doc_ids = Vec::new();
+    for i in index.terms_with_prefix(prefix) {
+        let (term, postings) = index.term_at(i);
+        if std::str::from_utf8(term).is_ok_and(&matches) {
+            doc_ids.extend(postings.map(|posting| posting.doc_id));
+        }
+    }
+    return Box::new(SortedDocIds::new(doc_ids).with_score(boost));
+}
+
+// `*` matches any number of characters and `?` exactly one.
+fn wildcard_match(pattern: &[char], word: &str) -> bool {
+    let word: Vec<char> = word.chars().collect();
+    let (mut p, mut w) = (0, 0);
+    // Position of the last star and the word position it was tried at.
+    let mut star: Option<(usize, usize)> = None;
+    while w < word.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == word[w]) {
+            p += 1;
+            w += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, w));
+            p += 1;
+        } else if let Some((star_p, star_w)) = star {
+            // Let the star take one more character.
+            p = star_p + 1;
+            w = star_w + 1;
+            star = Some((star_p, star_w + 1));
+        } else {
+            return false;
+        }
+    }
+    return pattern[p..].iter().all(|c| *c == '*');
+}
+
+// Levenshtein distance of at most `max`, computed row by row and given up once a whole row
+// is over the maximum.
+fn within_edit_distance(word: &[char], candidate: &str, max: usize) -> bool {
+    let candidate: Vec<char> = candidate.chars().collect();
+    if word.len().abs_diff(candidate.len()) > max {
+        return false;
+    }
+    let mut previous: Vec<usize> = (0..=candidate.len()).collect();
+    let mut current = vec![0; candidate.len() + 1];
+    for (i, a) in word.iter().enumerate() {
+        current[0] = i + 1;
+        for (j, b) in candidate.iter().enumerate() {
+            let substitution = previous[j] + (a != b) as usize;
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        if current.iter().all(|distance| *distance > max) {
+            return false;
+        }
+        std::mem::swap(&mut previous, &mut current);
+    }
+    return previous[candidate.len()] <= max;
+}
+
+#[inline]
+fn parse_number(index_type: IndexType, value: &str) -> Option<Number> {
+    return match index_type {
+        IndexType::Integer => value.parse().ok().map(Number::Integer),
+        IndexType::Real => value.parse().ok().map(Number::Real),
+        IndexType::Text | IndexType::GeoPoint => None,
+    };
+}
+
+#[inline]
+fn parse_bound(index_type: IndexType, bound: &Bound<Cow<'_, str>>) -> Option<Bound<Number>> {
+    return match bound {
+        Bound::Included(value) => parse_number(index_type, value).map(Bound::Included),
+        Bound::Excluded(value) => parse_number(index_type, value).map(Bound::Excluded),
+        Bound::Unbounded => Some(Bound::Unbounded),
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use finne_parser::query_parser::{parse_query, MAX_NESTING, MAX_QUERY_NODES};
+    use finne_parser::query_rewriter::rewrite_query;
+    use storage::segment::{FieldValue, SegmentWriter};
+
+    use crate::collection::validate_query;
+
+    fn indexes() -> HashMap<String, IndexType> {
+        return HashMap::from([
+            ("al".to_owned(), IndexType::Text),
+            ("count".to_owned(), IndexType::Integer),
+            ("loc".to_owned(), IndexType::GeoPoint),
+            ("en".to_owned(), IndexType::Text),
+        ]);
+    }
+
+    // The same text as al in en, analyzed for English.
+    fn analyzers() -> HashMap<String, Analyzer> {
+        return HashMap::from([("en".to_owned(), Analyzer::ENGLISH)]);
+    }
+
+    // Two segments, so doc ids restart and hits carry the segment.
+    fn segments() -> Vec<Rc<Segment>> {
+        let docs: [(&str, i64, (f64, f64)); 6] = [
+            ("the quick brown fox", 1, (52.37, 4.89)),
+            ("the lazy dog", 2, (48.85, 2.35)),
+            ("a brown dog and a cat", 3, (51.51, -0.13)),
+            ("quick quick e-mail", 4, (40.71, -74.0)),
+            ("the cat sat", 5, (52.52, 13.40)),
+            ("fox and dog", 6, (35.68, 139.69)),
+        ];
+        let mut segments = Vec::new();
+        for (id, chunk) in docs.chunks(3).enumerate() {
+            let mut writer = SegmentWriter::default();
+            for (text, count, (lat, lon)) in chunk {
+                let fields = [
+                    ("al", FieldValue::Text(text, &Analyzer::STANDARD)),
+                    ("count", FieldValue::Number(Number::Integer(*count))),
+                    ("loc", FieldValue::GeoPoint(*lat, *lon)),
+                    ("en", FieldValue::Text(text, &Analyzer::ENGLISH)),
+                ];
+                writer.add_document(&count.to_string(), b"{}", &fields);
+            }
+            segments.push(Rc::new(writer.finish(id as u64)));
+        }
+        return segments;
+    }
+
+    fn top_hits(segments: &[Rc<Segment>], bm25: Bm25, query: &str, limit: usize) -> (Vec<Hit>, Total) {
+        let mut parsed = Vec::new();
+        let root = parse_query(query.as_bytes(), &mut parsed).unwrap();
+        validate_query(&indexes(), &mut parsed).unwrap();
+        let mut rewritten = Vec::new();
+        let rewrite = rewrite_query(&parsed, root, &mut rewritten);
+        let searcher = Searcher {
+            indexes: &indexes(),
+            analyzers: &analyzers(),
+            bm25,
+            segments,
+        };
+        let mut hits = Vec::new();
+        let total = execute(&searcher, &rewritten, rewrite, limit, &mut hits);
+        return (hits, total);
+    }
+
+    // The counts of the matching documents, which are 1 to 6 in insertion order.
+    fn search(segments: &[Rc<Segment>], query: &str) -> Vec<u32> {
+        let (hits, _) = top_hits(segments, Bm25::default(), query, usize::MAX);
+        let mut counts: Vec<u32> = hits.iter().map(|hit| hit.segment as u32 * 3 + hit.doc_id + 1).collect();
+        counts.sort();
+        return counts;
+    }
+
+    // The counts of the matching documents by descending score, with the scores.
+    fn ranked(segments: &[Rc<Segment>], bm25: Bm25, query: &str) -> Vec<(u32, f32)> {
+        let (hits, _) = top_hits(segments, bm25, query, usize::MAX);
+        return hits.iter().map(|hit| (hit.segment as u32 * 3 + hit.doc_id + 1, hit.score)).collect();
+    }
+
+    fn ranking(segments: &[Rc<Segment>], bm25: Bm25, query: &str) -> Vec<u32> {
+        return ranked(segments, bm25, query).iter().map(|(count, _)| *count).collect();
+    }
+
+    #[test]
+    fn test_terms() {
+        let segments = segments();
+        assert_eq!(search(&segments, "al:dog"), vec![2, 3, 6]);
+        assert_eq!(search(&segments, "al:Quick"), vec![1, 4]);
+        assert_eq!(search(&segments, "al:bird"), Vec::<u32>::new());
+        assert_eq!(search(&segments, "al:\"brown fox\""), vec![1]);
+        assert_eq!(search(&segments, "al:\"fox brown\""), Vec::<u32>::new());
+        assert_eq!(search(&segments, "al:\"brown cat\"~3"), vec![3]);
+        assert_eq!(search(&segments, "al:e-mail"), vec![4]);
+        assert_eq!(search(&segments, "al:d*"), vec![2, 3, 6]);
+        assert_eq!(search(&segments, "al:?a*"), vec![2, 3, 4, 5]);
+        assert_eq!(search(&segments, "al:dig~1"), vec![2, 3, 6]);
+        assert_eq!(search(&segments, "al:quack~1"), vec![1, 4]);
+        assert_eq!(search(&segments, "count:4"), vec![4]);
+        assert_eq!(search(&segments, "count:[2 TO 4}"), vec![2, 3]);
+        assert_eq!(search(&segments, "count:{4 TO *]"), vec![5, 6]);
+        assert_eq!(search(&segments, "loc:[48,-1 TO 53,14]"), vec![1, 2, 3, 5]);
+        assert_eq!(search(&segments, "loc:52.37,4.89~100km"), vec![1]);
+    }
+
+    #[test]
+    fn test_boolean_nodes() {
+        let segments = segments();
+        assert_eq!(search(&segments, "al:dog and al:the"), vec![2]);
+        assert_eq!(search(&segments, "al:dog or al:cat"), vec![2, 3, 5, 6]);
+        assert_eq!(search(&segments, "al:dog and not al:cat"), vec![2, 6]);
+        assert_eq!(search(&segments, "not al:the"), vec![3, 4, 6]);
+        assert_eq!(search(&segments, "not (al:the or al:dog)"), vec![4]);
+        assert_eq!(search(&segments, "(al:fox or al:cat) and count:[3 TO *]"), vec![3, 5, 6]);
+        assert_eq!(search(&segments, "al:dog and not al:dog"), Vec::<u32>::new());
+        assert_eq!(search(&segments, "al:dog or not al:dog"), vec![1, 2, 3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn test_analyzed_fields() {
+        let segments = segments();
+        assert_eq!(search(&segments, "al:Dogs"), Vec::<u32>::new());
+        assert_eq!(search(&segments, "en:Dogs"), vec![2, 3, 6]);
+        assert_eq!(search(&segments, "en:\"lazy dogs\""), vec![2]);
+        // The stopword keeps its place in the phrase.
+        assert_eq!(search(&segments, "en:\"a brown dog\""), vec![3]);
+        assert_eq!(search(&segments, "en:\"brown a dog\""), Vec::<u32>::new());
+        assert_eq!(search(&segments, "en:the"), Vec::<u32>::new());
+        assert_eq!(search(&segments, "en:Fo* and en:QUIK~1"), vec![1]);
+    }
+
+    #[test]
+    fn test_scoring() {
+        let segments = segments();
+        let bm25 = Bm25::default();
+        // Shorter fields first, documents 2 and 6 have the same length and keep their order.
+        assert_eq!(ranking(&segments, bm25, "al:dog"), vec![2, 6, 3]);
+        // Two occurrences in a field of three words beat one.
+        assert_eq!(ranking(&segments, bm25, "al:quick"), vec![4, 1]);
+        // The rare cat outweighs the common dog, a boost turns that around.
+        assert_eq!(ranking(&segments, bm25, "al:dog or al:cat"), vec![3, 5, 2, 6]);
+        assert_eq!(ranking(&segments, bm25, "al:dog^4 or al:cat"), vec![3, 2, 6, 5]);
+        // A phrase scores as the sum of its words.
+        let phrase = ranked(&segments, bm25, "al:\"brown fox\"");
+        let words = ranked(&segments, bm25, "al:brown and al:fox");
+        assert_eq!(phrase[0].0, 1);
+        assert!((phrase[0].1 - words[0].1).abs() < 1e-6);
+        // Without length normalization the dog documents score the same.
+        let scores: Vec<f32> = ranked(&segments, Bm25 { k1: 1.2, b: 0.0 }, "al:dog").iter().map(|hit| hit.1).collect();
+        assert!(scores.iter().all(|score| *score == scores[0]));
+        // Scans score their boost, between the cat in a short and in a long field.
+        assert_eq!(ranked(&segments, bm25, "count:[5 TO *]^2"), vec![(5, 2.0), (6, 2.0)]);
+        assert_eq!(ranking(&segments, bm25, "count:2 or al:cat"), vec![5, 2, 3]);
+    }
+
+    // Three segments of random words, w0 in about every other document and w49 in few.
+    fn corpus() -> Vec<Rc<Segment>> {
+        let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+        let mut random = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            return (state >> 11) as f64 / (1u64 << 53) as f64;
+        };
+        let mut segments = Vec::new();
+        for id in 0..3 {
+            let mut writer = SegmentWriter::default();
+            for doc_id in 0..400 {
+                let words = 2 + (random() * 20.0) as usize;
+                let text: Vec<String> = (0..words).map(|_| format!("w{}", 50f64.powf(random()) as u32 - 1)).collect();
+                let text = text.join(" ");
+                let count = doc_id % 30;
+                let fields = [
+                    ("al", FieldValue::Text(&text, &Analyzer::STANDARD)),
+                    ("count", FieldValue::Number(Number::Integer(count))),
+                ];
+                writer.add_document(&format!("{}-{}", id, doc_id), b"{}", &fields);
+            }
+            let segment = writer.finish(id);
+            for doc_id in (0..400).step_by(7) {
+                segment.deletes.delete(doc_id);
+            }
+            segments.push(Rc::new(segment));
+        }
+        return segments;
+    }
+
+    #[test]
+    fn test_top_hits() {
+        let segments = corpus();
+        let bm25 = Bm25::default();
+        let queries = [
+            "al:w0 or al:w3 or al:w49",
+            "al:w1 or al:w30^3 or al:w2^0.5",
+            "al:w0 or al:\"w1 w2\" or al:\"w5 w9\"~4",
+            "al:w3 and (al:w5 or al:w9 or al:w40)",
+            "(al:w4 or al:w12 or al:w20) and not al:w0",
+            "al:w1 or count:[10 TO 12]^2",
+            "al:w2 and count:[0 TO 15]",
+            "count:[0 TO 15]",
+            // Negative boosts lower the scores of scans and expanded patterns.
+            "count:[0 TO 15]^-5 or al:w1",
+            "al:w0*^-3 or al:w3",
+        ];
+        let mut skipped = false;
+        for query in queries {
+            let (all, total) = top_hits(&segments, bm25, query, usize::MAX);
+            assert!(total.exact && total.count == all.len() && all.len() > 50, "{}", query);
+            for limit in [0, 1, 3, 10, 50, all.len(), all.len() + 1] {
+                let (hits, top_total) = top_hits(&segments, bm25, query, limit);
+                // The same hits with bit for bit the same scores.
+                assert_eq!(hits, all[..limit.min(all.len())], "{} limit {}", query, limit);
+                assert!(top_total.count <= total.count);
+                assert!(!top_total.exact || top_total.count == total.count);
+                if limit != all.len() {
+                    assert_eq!(top_total.exact, limit > all.len());
+                }
+                skipped |= top_total.count < total.count;
+            }
+        }
+        assert!(skipped);
+    }
+
+    #[test]
+    fn test_deleted_documents() {
+        let segments = segments();
+        segments[0].deletes.delete(1);
+        assert_eq!(search(&segments, "al:dog"), vec![3, 6]);
+        assert_eq!(search(&segments, "not al:fox"), vec![3, 4, 5]);
+    }
+
+    #[test]
+    fn test_largest_queries() {
+        let segments = segments();
+        let mut chain: Vec<String> = (1..MAX_QUERY_NODES / 2).map(|i| format!("al:x{}", i)).collect();
+        chain.push("al:dog".to_owned());
+        assert_eq!(search(&segments, &chain.join(" or ")), search(&segments, "al:dog"));
+        let nested = "not (".repeat(MAX_NESTING / 2) + "al:dog" + &")".repeat(MAX_NESTING / 2);
+        assert_eq!(search(&segments, &nested), search(&segments, "al:dog"));
+    }
+
+    #[test]
+    fn test_patterns() {
+        let pattern = |p: &str| p.chars().collect::<Vec<_>>();
+        assert!(wildcard_match(&pattern("f*x"), "fox"));
+        assert!(wildcard_match(&pattern("f*x"), "fx"));
+        assert!(wildcard_match(&pattern("*o*"), "dog"));
+        assert!(!wildcard_match(&pattern("f?x"), "fx"));
+        assert!(!wildcard_match(&pattern("f*x"), "foxy"));
+        assert!(within_edit_distance(&pattern("kitten"), "sitting", 3));
+        assert!(!within_edit_distance(&pattern("kitten"), "sitting", 2));
+        assert!(within_edit_distance(&pattern("fox"), "fox", 0));
+    }
+}
@@ -1,22 +1,41 @@
+#![allow(clippy::needless_return)]
+
+mod collection;
+mod database;
+mod executor;
+
 use std::collections::HashMap;
+use std::fmt;
 use std::io;
 use std::io::{Read, Write};
 use std::ops::DerefMut;
+use std::rc::Rc;
 use std::time::Duration;
 
 use bytes::{BufMut, BytesMut};
-use clap::Parser;
+use clap::{Parser, ValueEnum};
 use mio::net::{TcpListener, TcpStream};
 use mio::{Events, Interest, Poll, Token};
 use object_pool::{Pool, Reusable};
-use serde::Deserialize;
-use serde_json;
+use serde::Serialize;
+use serde_json::Value;
 use slab::Slab;
 
-// use finne_parser::request_parser::HttpRequest;
-use finne_parser::request_parser::Method;
+use finne_parser::query_dsl::{parse_json_query, JsonQueryError};
+use finne_parser::query_parser::{parse_query, recycle_buffer, QueryNode, QuerySyntaxError};
+use finne_parser::query_rewriter::{expand_default_fields, expand_synonyms, rewrite_query};
+use finne_parser::request_parser::{url_decode, HttpRequest, Method};
+use storage::segment::Segment;
+use storage::wal::FsyncPolicy;
+use storage::StorageError;
+
+use crate::collection::{validate_query, Collection, SchemaError};
+use crate::database::Database;
+use crate::executor::{execute, Hit, Searcher};
 
 const BUF_EXPANSION: usize = 1024;
+// Hits returned by a search without a limit parameter.
+const DEFAULT_SEARCH_LIMIT: usize = 10;
 
 #[derive(Parser)]
 struct Cli {
@@ -26,11 +45,55 @@ struct Cli {
     port: String,
     #[arg(default_value = "3000")]
     management_port: String,
+    // Map the database file instead of reading it, servers on one host share its pages.
+    #[arg(long)]
+    mmap: bool,
+    // When the write-ahead log is flushed to disk, see FsyncPolicy.
+    #[arg(long, value_enum, default_value = "interval")]
+    fsync: Fsync,
+    #[arg(long, default_value = "100")]
+    fsync_interval_ms: u64,
+    // The directory synonyms files named in create requests are read from, without it
+    // collections cannot use one.
+    #[arg(long)]
+    synonyms_dir: Option<std::path::PathBuf>,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Fsync {
+    Always,
+    Interval,
+    Never,
+}
+
+// Query nodes borrow from the request, between requests the buffers are kept empty
+// with a 'static lifetime and handed out again with recycle. Only parsing into them is free
+// of allocations, synonym lookups, the rewrite and the executor still allocate per query.
+#[derive(Default)]
+struct QueryBuffers<'a> {
+    parsed: Vec<QueryNode<'a>>,
+    expanded: Vec<QueryNode<'a>>,
+    synonyms: Vec<QueryNode<'a>>,
+    rewritten: Vec<QueryNode<'a>>,
+}
+
+impl QueryBuffers<'_> {
+    #[inline]
+    fn recycle<'b>(self) -> QueryBuffers<'b> {
+        return QueryBuffers {
+            parsed: recycle_buffer(self.parsed),
+            expanded: recycle_buffer(self.expanded),
+            synonyms: recycle_buffer(self.synonyms),
+            rewritten: recycle_buffer(self.rewritten),
+        };
+    }
 }
 
 struct RequestBuffers {
     parse_buf: BytesMut,
     resp_buf: BytesMut,
+    query_buf: BytesMut,
+    query_nodes: QueryBuffers<'static>,
     is_management: bool,
 }
 
@@ -39,6 +102,7 @@ impl RequestBuffers {
     fn clear(&mut self) {
         self.parse_buf.clear();
         self.resp_buf.clear();
+        self.query_buf.clear();
     }
 }
 
@@ -48,6 +112,8 @@ impl Default for RequestBuffers {
         return RequestBuffers {
             parse_buf: BytesMut::new(),
             resp_buf: BytesMut::new(),
+            query_buf: BytesMut::new(),
+            query_nodes: QueryBuffers::default(),
             is_management: false,
         };
     }
@@ -59,7 +125,7 @@ struct ConnectionData<'a> {
 }
 
 #[inline(always)]
-fn pull_or_create(pool: &Pool<RequestBuffers>, is_management: bool) -> Reusable<RequestBuffers> {
+fn pull_or_create(pool: &Pool<RequestBuffers>, is_management: bool) -> Reusable<'_, RequestBuffers> {
     let mut buf =  pool.pull(|| {
         println!("Miss object pool allocation!");
         return RequestBuffers::default();
@@ -94,6 +160,19 @@ fn main() -> io::Result<()> {
 
     let mut events = Events::with_capacity(BUF_EXPANSION);
     let mut buffer = [0_u8; BUF_EXPANSION];
+    let fsync_policy = match args.fsync {
+        Fsync::Always => FsyncPolicy::Always,
+        Fsync::Interval => FsyncPolicy::Interval(Duration::from_millis(args.fsync_interval_ms)),
+        Fsync::Never => FsyncPolicy::Never,
+    };
+    let mut db = match Database::open(&args.path, args.mmap, fsync_policy) {
+        Ok(db) => db,
+        Err(e) => {
+            eprintln!("Could not load database {}: {}", args.path.display(), e);
+            std::process::exit(1);
+        }
+    };
+    db.synonyms_dir = args.synonyms_dir;
     {
         let mut sockets: Slab<ConnectionData> = Slab::new();
         loop {
@@ -103,6 +182,9 @@ fn main() -> io::Result<()> {
                 }
                 return Err(err);
             }
+            db.sync_if_due();
+            db.refresh_due();
+            db.merge_step();
 
             // Get requests
             for event in events.iter() {
@@ -153,6 +235,7 @@ fn main() -> io::Result<()> {
                             &mut sockets,
                             &mut poll,
                             &mut buffer,
+                            &mut db,
                         );
                         // pending_requests.push(request_number);
                     }
@@ -184,6 +267,7 @@ fn receive_request(
     sockets: &mut Slab<ConnectionData>,
     poll: &mut Poll,
     buffer: &mut [u8],
+    db: &mut Database,
 ) {
     let conn = sockets.get_mut(token).unwrap();
     conn.buffers.clear();
@@ -195,20 +279,20 @@ fn receive_request(
                 break;
             }
             Ok(n) => conn.buffers.parse_buf.put(&buffer[0..n]),
-            Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
+            Err(ref e) if would_block(e) => break,
             Err(_) => break,
         }
     }
 
     if let Some(conn) = sockets.get_mut(token) {
-        process_request(conn.buffers.deref_mut());
+        process_request(conn.buffers.deref_mut(), db);
         poll.registry()
             .reregister(&mut conn.socket, Token(token + 2), Interest::WRITABLE)
             .unwrap();
     }
 }
 
-fn process_request(req: &mut RequestBuffers) {
+fn process_request(req: &mut RequestBuffers, db: &mut Database) {
     let http_req = match finne_parser::request_parser::parse_request(&req.parse_buf) {
         Ok(req) => req,
         Err(e) => {
@@ -217,34 +301,63 @@ fn process_request(req: &mut RequestBuffers) {
             return;
         }
     };
-    let (status_code, body): (&[u8], &str) = match (http_req.path, http_req.method, http_req.body) {
+    let resp_buf = &mut req.resp_buf;
+    let (status_code, body): (&[u8], &str) = match (http_req.path, &http_req.method, http_req.body) {
         (b"/", Method::Get, _) => (OK, "index\n"),
-        (b"/c" | b"/create", Method::Post, req_body) => match create(req_body) {
+        (b"/c" | b"/create", Method::Post, req_body) => match db.create(req_body) {
             Ok(_) => (OK, "search\n"),
+            Err(Error::InvalidRequest) => (BAD_REQUEST, "bad request\n"),
+            Err(e @ Error::Schema(_)) => return error_response(resp_buf, BAD_REQUEST_JSON, &e),
+            Err(e @ Error::Storage(_)) => return error_response(resp_buf, SERVER_ERROR_JSON, &e),
             Err(_) => (SERVER_ERROR, "search\n"),
         },
-        (b"/u" | b"/update", Method::Post | Method::Put, _req_body) => match update() {
-            Ok(_) => (OK, "search\n"),
-            Err(_) => (SERVER_ERROR, "search\n"),
+        (b"/u" | b"/update", Method::Post | Method::Put, _) => match update(&http_req, db, resp_buf) {
+            Ok(_) => return,
+            Err(Error::InvalidRequest) => (BAD_REQUEST, "bad request\n"),
+            Err(e @ Error::Schema(_)) => return error_response(resp_buf, BAD_REQUEST_JSON, &e),
+            Err(e @ Error::UnknownCollection) => return error_response(resp_buf, MISSING_JSON, &e),
+            Err(e) => return error_response(resp_buf, SERVER_ERROR_JSON, &e),
         },
-        (b"/d" | b"/delete", Method::Delete, _) => match delete() {
-            Ok(_) => (OK, "search\n"),
-            Err(_) => (SERVER_ERROR, "search\n"),
+        (b"/d" | b"/delete", Method::Delete, _) => match delete(&http_req, db, resp_buf) {
+            Ok(_) => return,
+            Err(Error::InvalidRequest) => (BAD_REQUEST, "bad request\n"),
+            Err(e @ Error::UnknownCollection) => return error_response(resp_buf, MISSING_JSON, &e),
+            Err(e) => return error_response(resp_buf, SERVER_ERROR_JSON, &e),
         },
-        (b"/s" | b"/search", Method::Get, _) => match search() {
-            Ok(_) => (OK, "search\n"),
-            Err(_) => (SERVER_ERROR, "search\n"),
+        (b"/synonyms", Method::Post | Method::Put, _) => match set_synonyms(&http_req, db) {
+            Ok(_) => (OK, "synonyms\n"),
+            Err(Error::InvalidRequest) => (BAD_REQUEST, "bad request\n"),
+            Err(e @ Error::Schema(_)) => return error_response(resp_buf, BAD_REQUEST_JSON, &e),
+            Err(e @ Error::UnknownCollection) => return error_response(resp_buf, MISSING_JSON, &e),
+            Err(e) => return error_response(resp_buf, SERVER_ERROR_JSON, &e),
         },
+        (b"/s" | b"/search", Method::Get | Method::Post, _) => {
+            match search(&http_req, &mut req.query_buf, &mut req.query_nodes, db, resp_buf) {
+                Ok(_) => return,
+                Err(Error::InvalidRequest) => (BAD_REQUEST, "bad request\n"),
+                Err(e @ Error::UnknownCollection) => return error_response(resp_buf, MISSING_JSON, &e),
+                Err(e @ (Error::Schema(_) | Error::JsonQuery(_))) => {
+                    return error_response(resp_buf, BAD_REQUEST_JSON, &e)
+                }
+                Err(Error::Query(e)) => return create_query_error_response(resp_buf, &e),
+                Err(_) => (SERVER_ERROR, "search\n"),
+            }
+        }
         _ => (MISSING, "404\n"),
     };
 
-    create_html_response(&mut req.resp_buf, status_code, body.as_bytes());
+    create_html_response(resp_buf, status_code, body.as_bytes());
 }
 
 static OK: &[u8] =
     b"HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nConnection: keep-alive\r\nContent-Length: ";
+static OK_JSON: &[u8] = b"HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nConnection: keep-alive\r\nContent-Length: ";
 static SERVER_ERROR: &[u8] = b"HTTP/1.1 500 Internal Server Error\r\nContent-Type: text/html\r\nConnection: keep-alive\r\nContent-Length: ";
+static SERVER_ERROR_JSON: &[u8] = b"HTTP/1.1 500 Internal Server Error\r\nContent-Type: application/json\r\nConnection: keep-alive\r\nContent-Length: ";
+static BAD_REQUEST: &[u8] = b"HTTP/1.1 400 Bad Request\r\nContent-Type: text/html\r\nConnection: keep-alive\r\nContent-Length: ";
+static BAD_REQUEST_JSON: &[u8] = b"HTTP/1.1 400 Bad Request\r\nContent-Type: application/json\r\nConnection: keep-alive\r\nContent-Length: ";
 static MISSING: &[u8] = b"HTTP/1.1 404 Not Found\r\nContent-Type: text/html\r\nConnection: keep-alive\r\nContent-Length: ";
+static MISSING_JSON: &[u8] = b"HTTP/1.1 404 Not Found\r\nContent-Type: application/json\r\nConnection: keep-alive\r\nContent-Length: ";
 static BODY_DELIM: &[u8] = b"\r\n\r\n";
 
 #[inline]
@@ -255,49 +368,260 @@ fn create_html_response(resp_buf: &mut BytesMut, status_code: &[u8], body: &[u8]
     resp_buf.put_slice(body);
 }
 
+// A JSON body with the error message, like {"error":"unknown collection"}.
+#[inline]
+fn error_response(resp_buf: &mut BytesMut, status_code: &[u8], e: &Error) {
+    let body = ErrorResponse { error: e.to_string() };
+    create_json_response(resp_buf, status_code, &body);
+}
+
+#[inline]
+fn create_json_response<T: Serialize>(resp_buf: &mut BytesMut, status_code: &[u8], body: &T) {
+    // Response bodies are plain structs of strings and numbers, serializing cannot fail.
+    let body = serde_json::to_vec(body).unwrap();
+    create_html_response(resp_buf, status_code, &body);
+}
+
+#[derive(Serialize)]
+struct ErrorResponse {
+    error: String,
+}
+
+#[derive(Serialize)]
+struct QueryErrorResponse<'a> {
+    error: String,
+    offset: usize,
+    expected: &'a str,
+    snippet: &'a str,
+}
+
+#[inline]
+fn create_query_error_response(resp_buf: &mut BytesMut, err: &QuerySyntaxError) {
+    let body = QueryErrorResponse {
+        error: format!("expected {} at offset {}", err.expected, err.offset),
+        offset: err.offset,
+        expected: err.expected,
+        snippet: &err.snippet,
+    };
+    create_json_response(resp_buf, BAD_REQUEST_JSON, &body);
+}
+
 enum Error {
     InvalidRequest,
+    Query(QuerySyntaxError),
+    JsonQuery(JsonQueryError),
+    Schema(SchemaError),
+    UnknownCollection,
+    Storage(StorageError),
     _Data,
 }
 
-#[derive(Deserialize)]
-enum IndexType {
-    Integer,
-    Real,
-    Text,
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return match self {
+            Error::InvalidRequest => write!(f, "invalid request"),
+            Error::Query(e) => write!(f, "{}", e),
+            Error::JsonQuery(e) => write!(f, "{}", e),
+            Error::Schema(e) => write!(f, "{}", e),
+            Error::UnknownCollection => write!(f, "unknown collection"),
+            Error::Storage(e) => write!(f, "{}", e),
+            Error::_Data => write!(f, "data error"),
+        };
+    }
+}
+
+#[derive(Serialize)]
+struct UpdateResponse<'a> {
+    id: &'a str,
+    result: &'static str,
 }
 
-#[derive(Deserialize)]
-struct CreateRequest {
-    _name: String,
-    _indexes: HashMap<String, IndexType>,
+// The body is the JSON document, the c parameter names the collection and id the document.
+// A document with the same id is replaced.
+#[inline]
+fn update(http_req: &HttpRequest, db: &mut Database, resp_buf: &mut BytesMut) -> Result<(), Error> {
+    let collection = find_collection_name(http_req, &db.collections)?;
+    let mut id = BytesMut::new();
+    let id = document_id(http_req, &mut id)?;
+    let existed = db.update(&collection, id, http_req.body)?;
+    let body = UpdateResponse {
+        id,
+        result: if existed { "updated" } else { "created" },
+    };
+    create_json_response(resp_buf, OK_JSON, &body);
+    return Ok(());
 }
 
+// The c parameter names the collection and id the document, a missing document is a 404.
 #[inline]
-fn create(body: &[u8]) -> Result<bool, Error> {
-    match serde_json::from_slice::<CreateRequest>(body) {
-        Ok(_req) => {
-            return Ok(true);
-        }
+fn delete(http_req: &HttpRequest, db: &mut Database, resp_buf: &mut BytesMut) -> Result<(), Error> {
+    let collection = find_collection_name(http_req, &db.collections)?;
+    let mut id = BytesMut::new();
+    let id = document_id(http_req, &mut id)?;
+    let (status_code, result) = match db.delete(&collection, id)? {
+        true => (OK_JSON, "deleted"),
+        false => (MISSING_JSON, "not_found"),
+    };
+    create_json_response(resp_buf, status_code, &UpdateResponse { id, result });
+    return Ok(());
+}
+
+// The body is a JSON array of synonym sets like ["couch, sofa, settee"] replacing all sets of
+// the collection named by the c parameter.
+#[inline]
+fn set_synonyms(http_req: &HttpRequest, db: &mut Database) -> Result<(), Error> {
+    let collection = find_collection_name(http_req, &db.collections)?;
+    return db.set_synonyms(&collection, http_req.body);
+}
+
+#[inline]
+fn document_id<'a>(http_req: &HttpRequest, id_buf: &'a mut BytesMut) -> Result<&'a str, Error> {
+    match http_req.get_parameter("id") {
+        Some(raw_id) if !raw_id.is_empty() => url_decode(raw_id, id_buf),
+        _ => return Err(Error::InvalidRequest),
+    }
+    return std::str::from_utf8(id_buf).map_err(|_| Error::InvalidRequest);
+}
+
+// GET takes the query string syntax in the q parameter, POST a JSON query body.
+#[inline]
+fn parse_search_query<'a>(
+    http_req: &HttpRequest,
+    query_buf: &'a mut BytesMut,
+    query_nodes: &mut Vec<QueryNode<'a>>,
+) -> Result<usize, Error> {
+    if http_req.method == Method::Post {
+        return match parse_json_query(http_req.body, query_nodes) {
+            Ok(root) => Ok(root),
+            Err(e) => {
+                println!("Error parsing json query: {}", e);
+                Err(Error::JsonQuery(e))
+            }
+        };
+    }
+    let raw_query = match http_req.get_parameter("q") {
+        Some(q) => q,
+        None => return Err(Error::InvalidRequest),
+    };
+    url_decode(raw_query, query_buf);
+    return match parse_query(query_buf, query_nodes) {
+        Ok(root) => Ok(root),
         Err(e) => {
-            println!("Error parsing request: {:?}", e);
-            println!("Request: {:?}", body);
-            return Err(Error::InvalidRequest);
+            println!("Error parsing query: {}", e);
+            Err(Error::Query(e))
         }
     };
 }
 
 #[inline]
-fn update() -> Result<bool, Error> {
-    return Ok(true);
+fn find_collection_name(http_req: &HttpRequest, collections: &HashMap<String, Collection>) -> Result<String, Error> {
+    return find_collection(http_req, collections).map(|c| c.definition.name.clone());
+}
+
+// The c parameter names the collection, it may be left out when there is only one.
+#[inline]
+fn find_collection<'a>(
+    http_req: &HttpRequest,
+    collections: &'a HashMap<String, Collection>,
+) -> Result<&'a Collection, Error> {
+    let collection = match http_req.get_parameter("c") {
+        Some(name) => std::str::from_utf8(name).ok().and_then(|name| collections.get(name)),
+        None if collections.len() == 1 => collections.values().next(),
+        None => None,
+    };
+    return collection.ok_or(Error::UnknownCollection);
+}
+
+#[derive(Serialize)]
+struct SearchResponse<'a> {
+    total: usize,
+    // False when documents that could not make the hits were skipped and not counted.
+    total_exact: bool,
+    hits: Vec<SearchHit<'a>>,
+}
+
+#[derive(Serialize)]
+struct SearchHit<'a> {
+    id: &'a str,
+    score: f32,
+    doc: Value,
+}
+
+#[inline]
+fn search(
+    http_req: &HttpRequest,
+    query_buf: &mut BytesMut,
+    query_nodes: &mut QueryBuffers<'static>,
+    db: &mut Database,
+    resp_buf: &mut BytesMut,
+) -> Result<(), Error> {
+    // Searches run on the segments, documents still in memory are flushed when they are due.
+    let collection = find_collection_name(http_req, &db.collections)?;
+    db.refresh_if_due(&collection);
+    let mut nodes = std::mem::take(query_nodes).recycle();
+    let res = run_search(http_req, query_buf, &db.collections, &mut nodes, resp_buf);
+    *query_nodes = nodes.recycle();
+    return res;
+}
+
+// Responds with the number of matching documents and the best of them up to the limit
+// parameter, highest score first.
+#[inline]
+fn run_search<'a>(
+    http_req: &HttpRequest,
+    query_buf: &'a mut BytesMut,
+    collections: &'a HashMap<String, Collection>,
+    nodes: &mut QueryBuffers<'a>,
+    resp_buf: &mut BytesMut,
+) -> Result<(), Error> {
+    let collection = find_collection(http_req, collections)?;
+    let limit = search_limit(http_req)?;
+    let root = parse_search_query(http_req, query_buf, &mut nodes.parsed)?;
+    let indexes = &collection.definition.indexes;
+    // Synonyms depend on the analyzer of the field, so terms get their default fields first.
+    let root = expand_default_fields(&nodes.parsed, root, &collection.default_fields, &mut nodes.expanded);
+    let lookup = |field: &str, word: &str| collection.synonyms(field, word);
+    let root = expand_synonyms(&nodes.expanded, root, &lookup, &mut nodes.synonyms);
+    if let Err(e) = validate_query(indexes, &mut nodes.synonyms) {
+        println!("Invalid query: {}", e);
+        return Err(Error::Schema(e));
+    }
+    let rewrite = rewrite_query(&nodes.synonyms, root, &mut nodes.rewritten);
+    let mut hits = Vec::new();
+    let segments = &collection.data.segments;
+    let searcher = Searcher {
+        indexes: &collection.definition.indexes,
+        analyzers: &collection.analyzers,
+        bm25: collection.bm25,
+        segments,
+    };
+    let total = execute(&searcher, &nodes.rewritten, rewrite, limit, &mut hits);
+    let body = SearchResponse {
+        total: total.count,
+        total_exact: total.exact,
+        hits: hits.iter().filter_map(|hit| search_hit(segments, hit)).collect(),
+    };
+    create_json_response(resp_buf, OK_JSON, &body);
+    return Ok(());
 }
 
 #[inline]
-fn search() -> Result<bool, Error> {
-    return Ok(true);
+fn search_hit<'a>(segments: &'a [Rc<Segment>], hit: &Hit) -> Option<SearchHit<'a>> {
+    let docs = &segments[hit.segment].docs;
+    return Some(SearchHit {
+        id: docs.external_id(hit.doc_id)?,
+        score: hit.score,
+        doc: serde_json::from_slice(docs.get(hit.doc_id)?).unwrap_or(Value::Null),
+    });
 }
 
 #[inline]
-fn delete() -> Result<bool, Error> {
-    return Ok(true);
+fn search_limit(http_req: &HttpRequest) -> Result<usize, Error> {
+    return match http_req.get_parameter("limit") {
+        Some(limit) => std::str::from_utf8(limit)
+            .ok()
+            .and_then(|limit| limit.parse().ok())
+            .ok_or(Error::InvalidRequest),
+        None => Ok(DEFAULT_SEARCH_LIMIT),
+    };
 }
@@ -0,0 +1,229 @@
+#![allow(clippy::needless_return)]
+
+// Builds a reverse index over a synthetic corpus with a skewed term distribution and compares
+// the size and full decode speed of the compressed posting lists with the uncompressed layout:
+// doc id, frequency and positions as u32 each. Then times an And of a frequent and a rare term
+// skipping through the frequent term's blocks against walking both lists fully, and the top 10
+// of a scored Or skipping documents that cannot make them against scoring every document.
+use std::hint::black_box;
+use std::time::Instant;
+
+use bytes::{Buf, BufMut, BytesMut};
+
+use storage::analysis::Analyzer;
+use storage::doc_ids::{DocIdIterator, Intersection, PostingsIterator, Union, NO_MORE_DOCS};
+use storage::indexes::{Index, ReverseIndexBuilder};
+use storage::scoring::Bm25;
+
+const DOCS: u32 = 50_000;
+const WORDS_PER_DOC: usize = 100;
+const VOCABULARY: f64 = 20_000.0;
+const ROUNDS: usize = 5;
+
+// Deterministic xorshift, so every run measures the same corpus.
+struct Rng(u64);
+
+impl Rng {
+    #[inline]
+    fn next_f64(&mut self) -> f64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        return (self.0 >> 11) as f64 / (1u64 << 53) as f64;
+    }
+}
+
+fn build_index() -> Index {
+    let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+    let mut builder = ReverseIndexBuilder::default();
+    let mut text = String::new();
+    for doc_id in 0..DOCS {
+        text.clear();
+        for _ in 0..WORDS_PER_DOC {
+            // Log-uniform ranks, a few terms are in most documents and most terms are rare.
+            let rank = VOCABULARY.powf(rng.next_f64()) as u32;
+            text.push_str(&format!("w{} ", rank));
+        }
+        builder.add_document(doc_id, &text, &Analyzer::STANDARD);
+    }
+    return builder.build("body".to_owned());
+}
+
+// The posting lists in the uncompressed layout, with the offset of every term's list.
+fn uncompressed(index: &Index) -> (BytesMut, Vec<(usize, usize)>) {
+    let mut data = BytesMut::new();
+    let mut terms = Vec::with_capacity(index.term_count());
+    for i in 0..index.term_count() {
+        let start = data.len();
+        for posting in index.term_at(i).1 {
+            data.put_u32(posting.doc_id);
+            data.put_u32(posting.frequency);
+            for position in posting.positions() {
+                data.put_u32(position);
+            }
+        }
+        terms.push((start, data.len()));
+    }
+    return (data, terms);
+}
+
+fn decode_compressed(index: &Index) -> (u64, u64) {
+    let (mut doc_sum, mut position_sum) = (0, 0);
+    for i in 0..index.term_count() {
+        for posting in index.term_at(i).1 {
+            doc_sum += posting.doc_id as u64;
+            position_sum += posting.positions().map(|p| p as u64).sum::<u64>();
+        }
+    }
+    return (doc_sum, position_sum);
+}
+
+fn decode_uncompressed(data: &[u8], terms: &[(usize, usize)]) -> (u64, u64) {
+    let (mut doc_sum, mut position_sum) = (0, 0);
+    for (start, end) in terms {
+        let mut postings = &data[*start..*end];
+        while !postings.is_empty() {
+            doc_sum += postings.get_u32() as u64;
+            let frequency = postings.get_u32();
+            for _ in 0..frequency {
+                position_sum += postings.get_u32() as u64;
+            }
+        }
+    }
+    return (doc_sum, position_sum);
+}
+
+fn intersect_skipping(index: &Index, terms: &[&str]) -> u64 {
+    let iterators = terms
+        .iter()
+        .map(|term| Box::new(PostingsIterator::new(index.lookup(term).unwrap())) as Box<dyn DocIdIterator>)
+        .collect();
+    let mut intersection = Intersection::new(iterators);
+    let (mut doc_id, mut sum) = (intersection.doc_id(), 0);
+    while doc_id != NO_MORE_DOCS {
+        sum += doc_id as u64;
+        doc_id = intersection.next();
+    }
+    return sum;
+}
+
+fn intersect_walking(index: &Index, terms: &[&str]) -> u64 {
+    let mut left = index.lookup(terms[0]).unwrap().map(|posting| posting.doc_id).peekable();
+    let mut sum = 0;
+    for doc_id in index.lookup(terms[1]).unwrap().map(|posting| posting.doc_id) {
+        while left.next_if(|left_id| *left_id < doc_id).is_some() {}
+        if left.peek() == Some(&doc_id) {
+            sum += doc_id as u64;
+        }
+    }
+    return sum;
+}
+
+// The `limit` best documents of an Or of the terms with their scores, best first.
+fn top_scores(index: &Index, terms: &[&str], limit: usize, prune: bool) -> Vec<(u32, f32)> {
+    let stats = index.field_stats();
+    let avg_length = stats.total_length as f32 / stats.doc_count as f32;
+    let iterators = terms
+        .iter()
+        .map(|term| {
+            let postings = index.lookup(term).unwrap();
+            let weight = Bm25::idf(stats.doc_count as u64, postings.doc_count() as u64);
+            let scorer = Bm25::default().scorer(weight, avg_length, index);
+            Box::new(PostingsIterator::new(postings).scored(scorer)) as Box<dyn DocIdIterator>
+        })
+        .collect();
+    let mut union = Union::new(iterators);
+    let mut top: Vec<(u32, f32)> = Vec::with_capacity(limit);
+    let mut doc_id = union.doc_id();
+    while doc_id != NO_MORE_DOCS {
+        let score = union.score();
+        if top.len() < limit {
+            top.push((doc_id, score));
+        } else {
+            let worst = (0..limit).min_by(|a, b| top[*a].1.total_cmp(&top[*b].1)).unwrap();
+            if score > top[worst].1 {
+                top[worst] = (doc_id, score);
+            }
+        }
+        if prune && top.len() == limit {
+            union.set_min_score(top.iter().map(|(_, score)| *score).fold(f32::INFINITY, f32::min));
+        }
+        doc_id = union.next();
+    }
+    top.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+    return top;
+}
+
+fn time<T>(mut run: impl FnMut() -> T) -> (T, f64) {
+    let mut result = run();
+    let start = Instant::now();
+    for _ in 0..ROUNDS {
+        result = black_box(run());
+    }
+    return (result, start.elapsed().as_nanos() as f64 / ROUNDS as f64);
+}
+
+fn main() {
+    let index = build_index();
+    let mut compressed = BytesMut::new();
+    index.write_bytes(&mut compressed);
+    let (data, terms) = uncompressed(&index);
+    let term_bytes: usize = (0..index.term_count()).map(|i| index.term_at(i).0.len()).sum();
+    // The same records, terms and field lengths as the compressed index.
+    let uncompressed_len = 13 + index.term_count() * 16 + term_bytes + 12 + DOCS as usize * 4 + data.len();
+    let postings: u64 = (0..index.term_count()).map(|i| index.term_at(i).1.doc_count() as u64).sum();
+
+    let (compressed_sums, compressed_ns) = time(|| decode_compressed(&index));
+    let (uncompressed_sums, uncompressed_ns) = time(|| decode_uncompressed(&data, &terms));
+    assert_eq!(compressed_sums, uncompressed_sums);
+
+    println!(
+        "postings: {} docs, {} terms, {} postings, {} positions",
+        DOCS,
+        index.term_count(),
+        postings,
+        DOCS as usize * WORDS_PER_DOC
+    );
+    println!(
+        "uncompressed: {:.1} MB, {:.2} ns/posting",
+        uncompressed_len as f64 / 1e6,
+        uncompressed_ns / postings as f64
+    );
+    println!(
+        "compressed:   {:.1} MB ({:.0}% of uncompressed), {:.2} ns/posting",
+        compressed.len() as f64 / 1e6,
+        compressed.len() as f64 * 100.0 / uncompressed_len as f64,
+        compressed_ns / postings as f64
+    );
+    assert!(compressed.len() < uncompressed_len);
+
+    // w1 is in almost every document, w5000 in fewer than a hundred.
+    let terms = ["w1", "w5000"];
+    let (skipping_sum, skipping_ns) = time(|| intersect_skipping(&index, &terms));
+    let (walking_sum, walking_ns) = time(|| intersect_walking(&index, &terms));
+    assert_eq!(skipping_sum, walking_sum);
+    println!(
+        "{} and {} ({} and {} docs): {:.1} us skipping, {:.1} us walking both lists ({:.1}x)",
+        terms[0],
+        terms[1],
+        index.lookup(terms[0]).unwrap().doc_count(),
+        index.lookup(terms[1]).unwrap().doc_count(),
+        skipping_ns / 1e3,
+        walking_ns / 1e3,
+        walking_ns / skipping_ns
+    );
+
+    let terms = ["w1", "w2", "w5000"];
+    let (pruned, pruned_ns) = time(|| top_scores(&index, &terms, 10, true));
+    let (exhaustive, exhaustive_ns) = time(|| top_scores(&index, &terms, 10, false));
+    assert_eq!(pruned, exhaustive);
+    println!(
+        "top 10 of {} or {} or {}: {:.1} us skipping, {:.1} us scoring every document ({:.1}x)",
+        terms[0],
+        terms[1],
+        terms[2],
+        pruned_ns / 1e3,
+        exhaustive_ns / 1e3,
+        exhaustive_ns / pruned_ns
+    );
+}
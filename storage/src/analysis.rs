@@ -0,0 +1,183 @@
+use std::borrow::Cow;
+
+use rust_stemmers::{Algorithm, Stemmer};
+use unicode_normalization::char::{decompose_canonical, is_combining_mark};
+use unicode_segmentation::UnicodeSegmentation;
+
+// Turns the text of a field into the terms it is indexed by, and the words and phrases of a
+// query on the field into the terms it is searched for, so that both meet: with stemming
+// "Dogs" finds "dog". The text is split into words at the Unicode word boundaries (UAX #29),
+// then the filters of the analyzer are applied to every word in order. A filter may drop a
+// word, its position stays taken so a phrase still needs the words around it in place.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenFilter {
+    Lowercase,
+    // Latin letters with diacritics to their ASCII letter, "Ærøskøbing" to "AEroskobing".
+    AsciiFolding,
+    // Drops common English words, "the" or "and".
+    EnglishStopwords,
+    // The Snowball English (Porter 2) stemmer, "running" to "run". Expects lowercase words.
+    EnglishStemmer,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Analyzer {
+    filters: Cow<'static, [TokenFilter]>,
+}
+
+// Sorted for binary search.
+const ENGLISH_STOPWORDS: [&str; 33] = [
+    "a", "an", "and", "are", "as", "at", "be", "but", "by", "for", "if", "in", "into", "is", "it", "no", "not", "of",
+    "on", "or", "such", "that", "the", "their", "then", "there", "these", "they", "this", "to", "was", "will", "with",
+];
+
+impl Default for Analyzer {
+    #[inline]
+    fn default() -> Self {
+        return Analyzer::STANDARD;
+    }
+}
+
+impl Analyzer {
+    // Lowercase words.
+    pub const STANDARD: Analyzer = Analyzer {
+        filters: Cow::Borrowed(&[TokenFilter::Lowercase]),
+    };
+
+    // Lowercase words folded to ASCII and stemmed, without stopwords.
+    pub const ENGLISH: Analyzer = Analyzer {
+        filters: Cow::Borrowed(&[
+            TokenFilter::Lowercase,
+            TokenFilter::AsciiFolding,
+            TokenFilter::EnglishStopwords,
+            TokenFilter::EnglishStemmer,
+        ]),
+    };
+
+    #[inline]
+    pub fn new(filters: Vec<TokenFilter>) -> Analyzer {
+        return Analyzer {
+            filters: Cow::Owned(filters),
+        };
+    }
+
+    #[inline]
+    pub fn filters(&self) -> &[TokenFilter] {
+        return &self.filters;
+    }
+
+    // The terms of the text with their word positions.
+    pub fn analyze<'a>(&'a self, text: &'a str) -> impl Iterator<Item = (u32, String)> + 'a {
+        let stemmer = Stemmer::create(Algorithm::English);
+        return text.unicode_words().enumerate().filter_map(move |(position, word)| {
+            let mut term = Cow::Borrowed(word);
+            for filter in self.filters.iter() {
+                term = match filter {
+                    TokenFilter::Lowercase => lowercase(term),
+                    TokenFilter::AsciiFolding => fold_to_ascii(term),
+                    TokenFilter::EnglishStopwords if ENGLISH_STOPWORDS.binary_search(&term.as_ref()).is_ok() => {
+                        return None;
+                    }
+                    TokenFilter::EnglishStopwords => term,
+                    TokenFilter::EnglishStemmer => Cow::Owned(stemmer.stem(&term).into_owned()),
+                };
+            }
+            return Some((position as u32, term.into_owned()));
+        });
+    }
+
+    // Applies the filters that work on single letters to a word that is not split into words
+    // or stemmed, like the pattern of a wildcard query.
+    pub fn normalize<'a>(&self, word: &'a str) -> Cow<'a, str> {
+        let mut word = Cow::Borrowed(word);
+        for filter in self.filters.iter() {
+            word = match filter {
+                TokenFilter::Lowercase => lowercase(word),
+                TokenFilter::AsciiFolding => fold_to_ascii(word),
+                TokenFilter::EnglishStopwords | TokenFilter::EnglishStemmer => word,
+            };
+        }
+        return word;
+    }
+}
+
+#[inline]
+fn lowercase(word: Cow<'_, str>) -> Cow<'_, str> {
+    if word.bytes().all(|b| b.is_ascii_lowercase() || !b.is_ascii_alphabetic() && b.is_ascii()) {
+        return word;
+    }
+    return Cow::Owned(word.to_lowercase());
+}
+
+// A letter is folded when its canonical decomposition is an ASCII letter with combining marks,
+// the Latin letters without one are mapped by hand. Other scripts stay as they are.
+fn fold_to_ascii(word: Cow<'_, str>) -> Cow<'_, str> {
+    if word.is_ascii() {
+        return word;
+    }
+    let mut folded = String::with_capacity(word.len());
+    for c in word.chars() {
+        if c.is_ascii() {
+            folded.push(c);
+            continue;
+        }
+        let (mut base, mut marks_only) = (None, true);
+        decompose_canonical(c, |part| match base {
+            None => base = Some(part),
+            Some(_) => marks_only &= is_combining_mark(part),
+        });
+        match (base, c) {
+            (Some(base), _) if base.is_ascii() && marks_only => folded.push(base),
+            (_, 'ß') => folded.push_str("ss"),
+            (_, 'æ') => folded.push_str("ae"),
+            (_, 'Æ') => folded.push_str("AE"),
+            (_, 'œ') => folded.push_str("oe"),
+            (_, 'Œ') => folded.push_str("OE"),
+            (_, 'þ') => folded.push_str("th"),
+            (_, 'Þ') => folded.push_str("TH"),
+            (_, 'ø') => folded.push('o'),
+            (_, 'Ø') => folded.push('O'),
+            (_, 'đ' | 'ð') => folded.push('d'),
+            (_, 'Đ' | 'Ð') => folded.push('D'),
+            (_, 'ł') => folded.push('l'),
+            (_, 'Ł') => folded.push('L'),
+            (_, 'ı') => folded.push('i'),
+            _ => folded.push(c),
+        }
+    }
+    return Cow::Owned(folded);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn terms(analyzer: &Analyzer, text: &str) -> Vec<(u32, String)> {
+        return analyzer.analyze(text).collect();
+    }
+
+    #[test]
+    fn test_analyzers() {
+        let standard = Analyzer::default();
+        let words = |words: &[&str]| -> Vec<(u32, String)> {
+            return words.iter().enumerate().map(|(i, word)| (i as u32, word.to_string())).collect();
+        };
+        assert_eq!(terms(&standard, "The quick-brown FOX's e-mail, 3.5 Café"), words(&[
+            "the", "quick", "brown", "fox's", "e", "mail", "3.5", "café"
+        ]));
+        assert_eq!(terms(&standard, "北京 Straße"), words(&["北", "京", "straße"]));
+
+        let english = Analyzer::ENGLISH;
+        assert_eq!(terms(&english, "Dogs"), words(&["dog"]));
+        // Stopwords leave a gap.
+        assert_eq!(terms(&english, "The running of the Dogs"), vec![(1, "run".to_owned()), (4, "dog".to_owned())]);
+        assert_eq!(terms(&english, "Crème Brûlée"), words(&["creme", "brule"]));
+        assert_eq!(terms(&english, "the and of"), Vec::new());
+
+        let folding = Analyzer::new(vec![TokenFilter::AsciiFolding]);
+        assert_eq!(terms(&folding, "Ærøskøbing Straße Łódź Ελλάδα"), words(&["AEroskobing", "Strasse", "Lodz", "Ελλάδα"]));
+        assert_eq!(english.normalize("Brûl*"), "brul*");
+        assert_eq!(standard.normalize("dogs"), "dogs");
+        assert!(ENGLISH_STOPWORDS.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+}
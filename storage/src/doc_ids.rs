@@ -0,0 +1,773 @@
+use crate::postings::{Posting, Postings};
+use crate::scoring::Bm25Scorer;
+
+// Iterators over the matching doc ids of a segment in ascending order. The query executor
+// builds one per term and combines them with Intersection, Union and Difference for the And,
+// Or and Not nodes of a query. An iterator is positioned on its first document when created
+// and on NO_MORE_DOCS once it is exhausted, where it stays. Iterators of scored terms also
+// give the relevance of the current document, combinations add up the scores of their parts.
+// For top-k search every iterator bounds its scores, and once the k best hits so far are known
+// a union skips the documents whose terms cannot add up to more than the worst of them.
+pub const NO_MORE_DOCS: u32 = u32::MAX;
+
+// Scores added up in a different order may differ in their last bits, a bound is compared with
+// some room to spare so no document that could make the results is skipped.
+#[inline]
+pub fn competitive(max_score: f32, min_score: f32) -> bool {
+    return max_score + max_score.abs() * 1e-5 > min_score;
+}
+
+pub trait DocIdIterator {
+    fn doc_id(&self) -> u32;
+
+    // Moves to the next document and returns its id.
+    fn next(&mut self) -> u32;
+
+    // Moves to the first document at or after `target` and returns its id, an iterator already
+    // at or past the target stays where it is.
+    fn advance_to(&mut self, target: u32) -> u32;
+
+    // Upper bound of the number of documents, an intersection leads with its cheapest iterator.
+    fn cost(&self) -> u32;
+
+    // Relevance of the current document, 0 for iterators that only match.
+    fn score(&self) -> f32;
+
+    // Upper bound of the score of every document.
+    fn max_score(&self) -> f32;
+
+    // Documents scoring no more than `min_score` no longer make the results and may be skipped
+    // from here on. The min score only grows.
+    #[inline]
+    fn set_min_score(&mut self, _min_score: f32) {}
+}
+
+// The documents of a term, with the posting of the current one for its frequency and positions.
+pub struct PostingsIterator<'a> {
+    postings: Postings<'a>,
+    posting: Option<Posting<'a>>,
+    scorer: Option<Bm25Scorer<'a>>,
+}
+
+impl<'a> PostingsIterator<'a> {
+    pub fn new(mut postings: Postings<'a>) -> PostingsIterator<'a> {
+        let posting = postings.next();
+        return PostingsIterator {
+            postings,
+            posting,
+            scorer: None,
+        };
+    }
+
+    // Scores the documents by the term frequency.
+    #[inline]
+    pub fn scored(mut self, scorer: Bm25Scorer<'a>) -> PostingsIterator<'a> {
+        self.scorer = Some(scorer);
+        return self;
+    }
+
+    #[inline]
+    pub fn posting(&self) -> Option<&Posting<'a>> {
+        return self.posting.as_ref();
+    }
+}
+
+impl DocIdIterator for PostingsIterator<'_> {
+    #[inline]
+    fn doc_id(&self) -> u32 {
+        return self.posting.as_ref().map_or(NO_MORE_DOCS, |posting| posting.doc_id);
+    }
+
+    #[inline]
+    fn next(&mut self) -> u32 {
+        self.posting = self.postings.next();
+        return self.doc_id();
+    }
+
+    #[inline]
+    fn advance_to(&mut self, target: u32) -> u32 {
+        if self.doc_id() < target {
+            self.posting = self.postings.advance_to(target);
+        }
+        return self.doc_id();
+    }
+
+    #[inline]
+    fn cost(&self) -> u32 {
+        return self.postings.doc_count();
+    }
+
+    #[inline]
+    fn score(&self) -> f32 {
+        return match (&self.scorer, &self.posting) {
+            (Some(scorer), Some(posting)) => scorer.score(posting.doc_id, posting.frequency),
+            _ => 0.0,
+        };
+    }
+
+    #[inline]
+    fn max_score(&self) -> f32 {
+        return self.scorer.as_ref().map_or(0.0, |scorer| {
+            scorer.max_score(self.postings.max_frequency(), self.postings.min_field_length())
+        });
+    }
+}
+
+// Every doc id of a segment, for negations without a positive side.
+pub struct AllDocs {
+    doc_id: u32,
+    len: u32,
+}
+
+impl AllDocs {
+    #[inline]
+    pub fn new(len: u32) -> AllDocs {
+        return AllDocs {
+            doc_id: if len == 0 { NO_MORE_DOCS } else { 0 },
+            len,
+        };
+    }
+}
+
+impl DocIdIterator for AllDocs {
+    #[inline]
+    fn doc_id(&self) -> u32 {
+        return self.doc_id;
+    }
+
+    #[inline]
+    fn next(&mut self) -> u32 {
+        return self.advance_to(self.doc_id.saturating_add(1));
+    }
+
+    #[inline]
+    fn advance_to(&mut self, target: u32) -> u32 {
+        if self.doc_id < target {
+            self.doc_id = if target < self.len { target } else { NO_MORE_DOCS };
+        }
+        return self.doc_id;
+    }
+
+    #[inline]
+    fn cost(&self) -> u32 {
+        return self.len;
+    }
+
+    #[inline]
+    fn score(&self) -> f32 {
+        return 0.0;
+    }
+
+    #[inline]
+    fn max_score(&self) -> f32 {
+        return 0.0;
+    }
+}
+
+// Doc ids collected up front, from range and geo scans or the terms a wildcard expands to.
+// All of them have the same score.
+pub struct SortedDocIds {
+    doc_ids: Vec<u32>,
+    pos: usize,
+    score: f32,
+}
+
+impl SortedDocIds {
+    // Sorts the doc ids and removes duplicates.
+    pub fn new(mut doc_ids: Vec<u32>) -> SortedDocIds {
+        doc_ids.sort_unstable();
+        doc_ids.dedup();
+        return SortedDocIds {
+            doc_ids,
+            pos: 0,
+            score: 0.0,
+        };
+    }
+
+    #[inline]
+    pub fn with_score(mut self, score: f32) -> SortedDocIds {
+        self.score = score;
+        return self;
+    }
+}
+
+impl DocIdIterator for SortedDocIds {
+    #[inline]
+    fn doc_id(&self) -> u32 {
+        return self.doc_ids.get(self.pos).copied().unwrap_or(NO_MORE_DOCS);
+    }
+
+    #[inline]
+    fn next(&mut self) -> u32 {
+        self.pos = (self.pos + 1).min(self.doc_ids.len());
+        return self.doc_id();
+    }
+
+    #[inline]
+    fn advance_to(&mut self, target: u32) -> u32 {
+        if self.doc_id() < target {
+            self.pos += self.doc_ids[self.pos..].partition_point(|doc_id| *doc_id < target);
+        }
+        return self.doc_id();
+    }
+
+    #[inline]
+    fn cost(&self) -> u32 {
+        return self.doc_ids.len() as u32;
+    }
+
+    #[inline]
+    fn score(&self) -> f32 {
+        return self.score;
+    }
+
+    // A negative score only lowers the sum, its bound is 0 as for Bm25Scorer::max_score.
+    #[inline]
+    fn max_score(&self) -> f32 {
+        return self.score.max(0.0);
+    }
+}
+
+// Documents with all words of a phrase near each other. Each word's positions are shifted
+// back by its offset in the phrase, a document matches when one shifted position of every
+// word lies in a window of `slop` positions, so a slop of 0 is the exact phrase. A scored
+// phrase counts its windows that share no position as its frequency.
+pub struct Phrase<'a> {
+    // Sorted by cost with the offset of the word in the phrase, the first one leads.
+    words: Vec<(PostingsIterator<'a>, u32)>,
+    slop: u32,
+    doc_id: u32,
+    frequency: u32,
+    scorer: Option<Bm25Scorer<'a>>,
+    // Reused for the shifted positions of every word and the window's position in each.
+    positions: Vec<Vec<i64>>,
+    window: Vec<usize>,
+}
+
+impl<'a> Phrase<'a> {
+    pub fn new(mut words: Vec<(PostingsIterator<'a>, u32)>, slop: u32) -> Phrase<'a> {
+        words.sort_by_key(|(word, _)| word.cost());
+        let mut phrase = Phrase {
+            positions: vec![Vec::new(); words.len()],
+            window: vec![0; words.len()],
+            words,
+            slop,
+            doc_id: NO_MORE_DOCS,
+            frequency: 0,
+            scorer: None,
+        };
+        if let Some((lead, _)) = phrase.words.first() {
+            let candidate = lead.doc_id();
+            phrase.doc_id = phrase.find(candidate);
+        }
+        return phrase;
+    }
+
+    #[inline]
+    pub fn scored(mut self, scorer: Bm25Scorer<'a>) -> Phrase<'a> {
+        self.scorer = Some(scorer);
+        return self;
+    }
+
+    // The first document at or after the lead's document `candidate` that has the phrase.
+    fn find(&mut self, mut candidate: u32) -> u32 {
+        'candidates: while candidate != NO_MORE_DOCS {
+            let (lead, others) = self.words.split_first_mut().unwrap();
+            for (word, _) in others.iter_mut() {
+                let doc_id = word.advance_to(candidate);
+                if doc_id != candidate {
+                    candidate = lead.0.advance_to(doc_id);
+                    continue 'candidates;
+                }
+            }
+            self.frequency = self.phrase_frequency();
+            if self.frequency > 0 {
+                return candidate;
+            }
+            candidate = self.words[0].0.next();
+        }
+        return NO_MORE_DOCS;
+    }
+
+    // All words are on the same document, looks for windows holding one shifted position of
+    // each word by repeatedly moving past the smallest position.
+    fn phrase_frequency(&mut self) -> u32 {
+        for ((word, offset), positions) in self.words.iter().zip(self.positions.iter_mut()) {
+            positions.clear();
+            if let Some(posting) = word.posting() {
+                positions.extend(posting.positions().map(|position| position as i64 - *offset as i64));
+            }
+        }
+        self.window.fill(0);
+        let mut frequency = 0;
+        loop {
+            let (mut min, mut max, mut min_word) = (i64::MAX, i64::MIN, 0);
+            for (i, positions) in self.positions.iter().enumerate() {
+                let position = match positions.get(self.window[i]) {
+                    Some(position) => *position,
+                    None => return frequency,
+                };
+                if position < min {
+                    (min, min_word) = (position, i);
+                }
+                max = max.max(position);
+            }
+            if max - min <= self.slop as i64 {
+                frequency += 1;
+                if self.scorer.is_none() {
+                    return frequency;
+                }
+                self.window.iter_mut().for_each(|next| *next += 1);
+            } else {
+                self.window[min_word] += 1;
+            }
+        }
+    }
+}
+
+impl DocIdIterator for Phrase<'_> {
+    #[inline]
+    fn doc_id(&self) -> u32 {
+        return self.doc_id;
+    }
+
+    fn next(&mut self) -> u32 {
+        if self.doc_id != NO_MORE_DOCS {
+            let candidate = self.words[0].0.next();
+            self.doc_id = self.find(candidate);
+        }
+        return self.doc_id;
+    }
+
+    fn advance_to(&mut self, target: u32) -> u32 {
+        if self.doc_id < target {
+            let candidate = self.words[0].0.advance_to(target);
+            self.doc_id = self.find(candidate);
+        }
+        return self.doc_id;
+    }
+
+    #[inline]
+    fn cost(&self) -> u32 {
+        return self.words.first().map_or(0, |(lead, _)| lead.cost());
+    }
+
+    #[inline]
+    fn score(&self) -> f32 {
+        return match &self.scorer {
+            Some(scorer) if self.doc_id != NO_MORE_DOCS => scorer.score(self.doc_id, self.frequency),
+            _ => 0.0,
+        };
+    }
+
+    // A phrase is no more frequent than any of its words, in fields at least as long as theirs.
+    fn max_score(&self) -> f32 {
+        let postings = self.words.iter().map(|(word, _)| &word.postings);
+        let max_frequency = postings.clone().map(|postings| postings.max_frequency()).min().unwrap_or(0);
+        let min_field_length = postings.map(|postings| postings.min_field_length()).max().unwrap_or(0);
+        return self.scorer.as_ref().map_or(0.0, |scorer| scorer.max_score(max_frequency, min_field_length));
+    }
+}
+
+// Documents in all iterators. The cheapest one leads and the others are advanced to its
+// document, whenever one of them overshoots the lead is advanced to that document instead, so
+// a rare term skips through the blocks of a frequent one.
+pub struct Intersection<'a> {
+    iterators: Vec<Box<dyn DocIdIterator + 'a>>,
+    doc_id: u32,
+}
+
+impl<'a> Intersection<'a> {
+    pub fn new(mut iterators: Vec<Box<dyn DocIdIterator + 'a>>) -> Intersection<'a> {
+        iterators.sort_by_key(|iterator| iterator.cost());
+        let mut intersection = Intersection {
+            iterators,
+            doc_id: NO_MORE_DOCS,
+        };
+        if let Some(lead) = intersection.iterators.first() {
+            let candidate = lead.doc_id();
+            intersection.doc_id = intersection.align(candidate);
+        }
+        return intersection;
+    }
+
+    // The first document at or after the lead's document `candidate` that all iterators have.
+    fn align(&mut self, mut candidate: u32) -> u32 {
+        let (lead, others) = match self.iterators.split_first_mut() {
+            Some(split) => split,
+            None => return NO_MORE_DOCS,
+        };
+        'candidates: while candidate != NO_MORE_DOCS {
+            for iterator in others.iter_mut() {
+                let doc_id = iterator.advance_to(candidate);
+                if doc_id != candidate {
+                    candidate = lead.advance_to(doc_id);
+                    continue 'candidates;
+                }
+            }
+            return candidate;
+        }
+        return NO_MORE_DOCS;
+    }
+}
+
+impl DocIdIterator for Intersection<'_> {
+    #[inline]
+    fn doc_id(&self) -> u32 {
+        return self.doc_id;
+    }
+
+    fn next(&mut self) -> u32 {
+        if self.doc_id != NO_MORE_DOCS {
+            let candidate = self.iterators[0].next();
+            self.doc_id = self.align(candidate);
+        }
+        return self.doc_id;
+    }
+
+    fn advance_to(&mut self, target: u32) -> u32 {
+        if self.doc_id < target {
+            let candidate = self.iterators[0].advance_to(target);
+            self.doc_id = self.align(candidate);
+        }
+        return self.doc_id;
+    }
+
+    #[inline]
+    fn cost(&self) -> u32 {
+        return self.iterators.first().map_or(0, |lead| lead.cost());
+    }
+
+    #[inline]
+    fn score(&self) -> f32 {
+        return self.iterators.iter().map(|iterator| iterator.score()).sum();
+    }
+
+    #[inline]
+    fn max_score(&self) -> f32 {
+        return self.iterators.iter().map(|iterator| iterator.max_score()).sum();
+    }
+}
+
+// Documents in any of the iterators. Once a min score is set the union is a weak And: with
+// the iterators ordered by their documents, the pivot is the first one whose bound together
+// with those of the iterators before it is competitive. No document before the pivot's can be,
+// so the iterators before it are advanced to the pivot's document, skipping whole blocks of
+// frequent terms until a rare one is on the same document.
+pub struct Union<'a> {
+    iterators: Vec<Box<dyn DocIdIterator + 'a>>,
+    doc_id: u32,
+    min_score: f32,
+    // The positions of the iterators by document. The iterators themselves keep their order,
+    // so a document's score adds up the same with and without pruning.
+    order: Vec<usize>,
+}
+
+impl<'a> Union<'a> {
+    pub fn new(iterators: Vec<Box<dyn DocIdIterator + 'a>>) -> Union<'a> {
+        let mut union = Union {
+            order: (0..iterators.len()).collect(),
+            iterators,
+            doc_id: NO_MORE_DOCS,
+            min_score: f32::NEG_INFINITY,
+        };
+        union.doc_id = union.min_doc_id();
+        return union;
+    }
+
+    #[inline]
+    fn min_doc_id(&self) -> u32 {
+        return self.iterators.iter().map(|iterator| iterator.doc_id()).min().unwrap_or(NO_MORE_DOCS);
+    }
+
+    // The first document at or after those of the iterators that may be competitive.
+    fn next_doc_id(&mut self) -> u32 {
+        if self.min_score == f32::NEG_INFINITY {
+            return self.min_doc_id();
+        }
+        loop {
+            let iterators = &self.iterators;
+            self.order.sort_by_key(|i| iterators[*i].doc_id());
+            let mut max_score = 0.0;
+            let pivot = self.order.iter().position(|i| {
+                max_score += iterators[*i].max_score();
+                return competitive(max_score, self.min_score);
+            });
+            let pivot = match pivot {
+                Some(pivot) => pivot,
+                None => return NO_MORE_DOCS,
+            };
+            let doc_id = iterators[self.order[pivot]].doc_id();
+            if doc_id == NO_MORE_DOCS || iterators[self.order[0]].doc_id() == doc_id {
+                return doc_id;
+            }
+            for i in &self.order[..pivot] {
+                self.iterators[*i].advance_to(doc_id);
+            }
+        }
+    }
+}
+
+impl DocIdIterator for Union<'_> {
+    #[inline]
+    fn doc_id(&self) -> u32 {
+        return self.doc_id;
+    }
+
+    fn next(&mut self) -> u32 {
+        if self.doc_id != NO_MORE_DOCS {
+            for iterator in self.iterators.iter_mut() {
+                if iterator.doc_id() == self.doc_id {
+                    iterator.next();
+                }
+            }
+            self.doc_id = self.next_doc_id();
+        }
+        return self.doc_id;
+    }
+
+    fn advance_to(&mut self, target: u32) -> u32 {
+        if self.doc_id < target {
+            for iterator in self.iterators.iter_mut() {
+                iterator.advance_to(target);
+            }
+            self.doc_id = self.next_doc_id();
+        }
+        return self.doc_id;
+    }
+
+    #[inline]
+    fn cost(&self) -> u32 {
+        return self.iterators.iter().fold(0u32, |sum, iterator| sum.saturating_add(iterator.cost()));
+    }
+
+    // The iterators on the current document add up.
+    #[inline]
+    fn score(&self) -> f32 {
+        return self
+            .iterators
+            .iter()
+            .filter(|iterator| iterator.doc_id() == self.doc_id)
+            .map(|iterator| iterator.score())
+            .sum();
+    }
+
+    #[inline]
+    fn max_score(&self) -> f32 {
+        return self.iterators.iter().map(|iterator| iterator.max_score()).sum();
+    }
+
+    #[inline]
+    fn set_min_score(&mut self, min_score: f32) {
+        self.min_score = self.min_score.max(min_score);
+    }
+}
+
+// Documents of `include` that are not in `exclude`, exclude is only advanced to the documents
+// of include.
+pub struct Difference<'a> {
+    include: Box<dyn DocIdIterator + 'a>,
+    exclude: Box<dyn DocIdIterator + 'a>,
+}
+
+impl<'a> Difference<'a> {
+    pub fn new(include: Box<dyn DocIdIterator + 'a>, exclude: Box<dyn DocIdIterator + 'a>) -> Difference<'a> {
+        let mut difference = Difference { include, exclude };
+        difference.skip_excluded();
+        return difference;
+    }
+
+    fn skip_excluded(&mut self) -> u32 {
+        let mut doc_id = self.include.doc_id();
+        while doc_id != NO_MORE_DOCS && self.exclude.advance_to(doc_id) == doc_id {
+            doc_id = self.include.next();
+        }
+        return doc_id;
+    }
+}
+
+impl DocIdIterator for Difference<'_> {
+    #[inline]
+    fn doc_id(&self) -> u32 {
+        return self.include.doc_id();
+    }
+
+    fn next(&mut self) -> u32 {
+        self.include.next();
+        return self.skip_excluded();
+    }
+
+    fn advance_to(&mut self, target: u32) -> u32 {
+        self.include.advance_to(target);
+        return self.skip_excluded();
+    }
+
+    #[inline]
+    fn cost(&self) -> u32 {
+        return self.include.cost();
+    }
+
+    #[inline]
+    fn score(&self) -> f32 {
+        return self.include.score();
+    }
+
+    #[inline]
+    fn max_score(&self) -> f32 {
+        return self.include.max_score();
+    }
+
+    #[inline]
+    fn set_min_score(&mut self, min_score: f32) {
+        self.include.set_min_score(min_score);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::analysis::Analyzer;
+    use crate::indexes::{Index, ReverseIndexBuilder};
+    use crate::scoring::Bm25;
+
+    // Doc i contains "all", "even" when i is even, "three" when it is a multiple of three
+    // and "rare" when it is a multiple of 500, several blocks each.
+    fn build() -> Index {
+        let mut builder = ReverseIndexBuilder::default();
+        for doc_id in 0..2000 {
+            let mut text = "all".to_owned();
+            if doc_id % 2 == 0 {
+                text.push_str(" even");
+            }
+            if doc_id % 3 == 0 {
+                text.push_str(" three");
+            }
+            if doc_id % 500 == 0 {
+                text.push_str(" rare");
+            }
+            builder.add_document(doc_id, &text, &Analyzer::STANDARD);
+        }
+        return builder.build("body".to_owned());
+    }
+
+    fn term<'a>(index: &'a Index, term: &str) -> Box<dyn DocIdIterator + 'a> {
+        return Box::new(PostingsIterator::new(index.lookup(term).unwrap()));
+    }
+
+    fn collect(mut iterator: impl DocIdIterator) -> Vec<u32> {
+        let mut doc_ids = Vec::new();
+        let mut doc_id = iterator.doc_id();
+        while doc_id != NO_MORE_DOCS {
+            doc_ids.push(doc_id);
+            doc_id = iterator.next();
+        }
+        assert_eq!(iterator.next(), NO_MORE_DOCS);
+        return doc_ids;
+    }
+
+    fn expected(pred: impl Fn(u32) -> bool) -> Vec<u32> {
+        return (0..2000).filter(|doc_id| pred(*doc_id)).collect();
+    }
+
+    #[test]
+    fn test_intersection() {
+        let index = build();
+        let both = Intersection::new(vec![term(&index, "even"), term(&index, "three")]);
+        assert_eq!(both.cost(), 667);
+        assert_eq!(collect(both), expected(|i| i % 6 == 0));
+        let rare = Intersection::new(vec![term(&index, "all"), term(&index, "even"), term(&index, "rare")]);
+        assert_eq!(collect(rare), vec![0, 500, 1000, 1500]);
+
+        let mut skipping = Intersection::new(vec![term(&index, "even"), term(&index, "three")]);
+        assert_eq!(skipping.advance_to(1000), 1002);
+        assert_eq!(skipping.advance_to(1001), 1002);
+        assert_eq!(skipping.next(), 1008);
+        assert_eq!(skipping.advance_to(1999), NO_MORE_DOCS);
+        assert_eq!(collect(Intersection::new(Vec::new())), Vec::<u32>::new());
+    }
+
+    #[test]
+    fn test_union_and_difference() {
+        let index = build();
+        let either = Union::new(vec![term(&index, "even"), term(&index, "three")]);
+        assert_eq!(collect(either), expected(|i| i % 2 == 0 || i % 3 == 0));
+
+        let odd = Difference::new(term(&index, "all"), term(&index, "even"));
+        assert_eq!(collect(odd), expected(|i| i % 2 == 1));
+
+        // (even or three) and not (all and rare)
+        let tree = Difference::new(
+            Box::new(Union::new(vec![term(&index, "even"), term(&index, "three")])),
+            Box::new(Intersection::new(vec![term(&index, "all"), term(&index, "rare")])),
+        );
+        assert_eq!(collect(tree), expected(|i| (i % 2 == 0 || i % 3 == 0) && i % 500 != 0));
+
+        let mut skipping = Difference::new(term(&index, "three"), term(&index, "even"));
+        assert_eq!(skipping.advance_to(100), 105);
+        assert_eq!(skipping.next(), 111);
+
+        let not_even = Difference::new(Box::new(AllDocs::new(2000)), term(&index, "even"));
+        assert_eq!(collect(not_even), expected(|i| i % 2 == 1));
+        let mut sorted = SortedDocIds::new(vec![7, 3, 7, 1]);
+        assert_eq!(sorted.advance_to(2), 3);
+        assert_eq!(collect(sorted), vec![3, 7]);
+        assert_eq!(collect(AllDocs::new(0)), Vec::<u32>::new());
+    }
+
+    #[test]
+    fn test_union_min_score() {
+        let index = build();
+        let stats = index.field_stats();
+        let avg_length = stats.total_length as f32 / stats.doc_count as f32;
+        let scored = |word: &str| -> Box<dyn DocIdIterator + '_> {
+            let postings = index.lookup(word).unwrap();
+            let weight = Bm25::idf(stats.doc_count as u64, postings.doc_count() as u64);
+            let scorer = Bm25::default().scorer(weight, avg_length, &index);
+            return Box::new(PostingsIterator::new(postings).scored(scorer));
+        };
+        let scores = |min_score: f32| -> Vec<(u32, f32)> {
+            let mut union = Union::new(vec![scored("even"), scored("three"), scored("rare")]);
+            union.set_min_score(min_score);
+            let mut scores = Vec::new();
+            let mut doc_id = union.doc_id();
+            while doc_id != NO_MORE_DOCS {
+                scores.push((doc_id, union.score()));
+                doc_id = union.next();
+            }
+            return scores;
+        };
+        let all = scores(f32::NEG_INFINITY);
+        assert_eq!(all.len(), expected(|i| i % 2 == 0 || i % 3 == 0).len());
+        // Only documents with the rare term score more than the other two can.
+        let min_score = (scored("even").max_score() + scored("three").max_score()) * 1.01;
+        let competitive: Vec<(u32, f32)> = all.iter().copied().filter(|(_, score)| *score > min_score).collect();
+        assert_eq!(competitive.iter().map(|(doc_id, _)| *doc_id).collect::<Vec<_>>(), vec![0, 500, 1000, 1500]);
+        assert_eq!(scores(min_score), competitive);
+        assert_eq!(scores(f32::MAX), vec![all[0]]);
+    }
+
+    #[test]
+    fn test_phrase() {
+        let mut builder = ReverseIndexBuilder::default();
+        builder.add_document(0, "the quick brown fox", &Analyzer::STANDARD);
+        builder.add_document(1, "the brown quick fox", &Analyzer::STANDARD);
+        builder.add_document(2, "quick fox and a brown dog", &Analyzer::STANDARD);
+        builder.add_document(3, "quick quick brown", &Analyzer::STANDARD);
+        let index = builder.build("body".to_owned());
+        let phrase = |words: &[&str], slop: u32| -> Vec<u32> {
+            let words = words
+                .iter()
+                .enumerate()
+                .map(|(offset, word)| (PostingsIterator::new(index.lookup(word).unwrap()), offset as u32))
+                .collect();
+            return collect(Phrase::new(words, slop));
+        };
+        assert_eq!(phrase(&["quick", "brown"], 0), vec![0, 3]);
+        assert_eq!(phrase(&["quick", "fox"], 0), vec![1, 2]);
+        assert_eq!(phrase(&["quick", "brown", "fox"], 0), vec![0]);
+        // Swapped words are two positions apart, words in between one each.
+        assert_eq!(phrase(&["quick", "brown"], 1), vec![0, 3]);
+        assert_eq!(phrase(&["quick", "brown"], 2), vec![0, 1, 3]);
+        assert_eq!(phrase(&["quick", "brown"], 3), vec![0, 1, 2, 3]);
+    }
+}
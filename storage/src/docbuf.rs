@@ -0,0 +1,224 @@
+use std::collections::HashMap;
+use std::str;
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+
+// Stored documents by dense internal doc id, starting at 0 in insertion order.
+//
+// data_buf holds for each document its external id directly followed by the JSON document.
+// rec_buf holds one record per document: data offset (u64), id length (u32), document length (u32).
+//
+// Loaded documents stay in the immutable buffers, which may point into a memory mapped file,
+// appended documents go to the new_* buffers. Offsets of appended documents continue after
+// the end of data_buf, as if both were one buffer.
+//
+// An external id maps to its last document, earlier documents with the same id were replaced
+// and are only kept until their segment is merged.
+pub struct DocBuf {
+    data_buf: Bytes,
+    rec_buf: Bytes,
+    new_data_buf: BytesMut,
+    new_rec_buf: BytesMut,
+    ids: HashMap<String, u32>,
+}
+
+const DOC_REC_LEN: usize = 16;
+
+impl Default for DocBuf {
+    #[inline]
+    fn default() -> Self {
+        return DocBuf {
+            data_buf: Bytes::new(),
+            rec_buf: Bytes::new(),
+            new_data_buf: BytesMut::new(),
+            new_rec_buf: BytesMut::new(),
+            ids: HashMap::new(),
+        };
+    }
+}
+
+impl DocBuf {
+    pub fn from_bytes(mut bytes: Bytes) -> Option<DocBuf> {
+        if bytes.remaining() < 16 {
+            tracing::warn!("Document store is truncated");
+            return None;
+        }
+        let rec_length = bytes.get_u64() as usize;
+        let data_length = bytes.get_u64() as usize;
+        let lengths_match = rec_length.checked_add(data_length) == Some(bytes.remaining());
+        if !lengths_match || !rec_length.is_multiple_of(DOC_REC_LEN) {
+            tracing::warn!("Invalid document store lengths: {} + {}", rec_length, data_length);
+            return None;
+        }
+        let mut docbuf = DocBuf {
+            rec_buf: bytes.split_to(rec_length),
+            data_buf: bytes,
+            new_data_buf: BytesMut::new(),
+            new_rec_buf: BytesMut::new(),
+            ids: HashMap::with_capacity(rec_length / DOC_REC_LEN),
+        };
+        for doc_id in 0..docbuf.len() {
+            let (offset, id_len, doc_len) = docbuf.record(doc_id);
+            if offset.checked_add(id_len + doc_len).is_none_or(|end| end > data_length) {
+                tracing::warn!("Document {} is out of bounds", doc_id);
+                return None;
+            }
+            let external_id = match str::from_utf8(docbuf.data(offset, id_len)) {
+                Ok(external_id) => external_id.to_owned(),
+                Err(_) => {
+                    tracing::warn!("Document {} has an invalid id", doc_id);
+                    return None;
+                }
+            };
+            docbuf.ids.insert(external_id, doc_id);
+        }
+        return Some(docbuf);
+    }
+
+    pub fn write_bytes(&self, output: &mut BytesMut) {
+        let rec_length = self.rec_buf.len() + self.new_rec_buf.len();
+        let data_length = self.data_buf.len() + self.new_data_buf.len();
+        output.reserve(16 + rec_length + data_length);
+        output.put_u64(rec_length as u64);
+        output.put_u64(data_length as u64);
+        output.put_slice(&self.rec_buf);
+        output.put_slice(&self.new_rec_buf);
+        output.put_slice(&self.data_buf);
+        output.put_slice(&self.new_data_buf);
+    }
+
+    // Stores the document and returns its internal id, None if the external id is taken.
+    pub fn append(&mut self, external_id: &str, doc: &[u8]) -> Option<u32> {
+        if self.ids.contains_key(external_id) {
+            return None;
+        }
+        let doc_id = self.len();
+        self.new_rec_buf.put_u64((self.data_buf.len() + self.new_data_buf.len()) as u64);
+        self.new_rec_buf.put_u32(external_id.len() as u32);
+        self.new_rec_buf.put_u32(doc.len() as u32);
+        self.new_data_buf.put_slice(external_id.as_bytes());
+        self.new_data_buf.put_slice(doc);
+        self.ids.insert(external_id.to_owned(), doc_id);
+        return Some(doc_id);
+    }
+
+    // Frees the external id for a new document, the stored document keeps it.
+    #[inline]
+    pub fn remove_id(&mut self, external_id: &str) -> Option<u32> {
+        return self.ids.remove(external_id);
+    }
+
+    #[inline]
+    pub fn get(&self, doc_id: u32) -> Option<&[u8]> {
+        if doc_id >= self.len() {
+            return None;
+        }
+        let (offset, id_len, doc_len) = self.record(doc_id);
+        return Some(self.data(offset + id_len, doc_len));
+    }
+
+    #[inline]
+    pub fn external_id(&self, doc_id: u32) -> Option<&str> {
+        if doc_id >= self.len() {
+            return None;
+        }
+        let (offset, id_len, _) = self.record(doc_id);
+        // Ids are only stored from a &str or checked when loading.
+        return str::from_utf8(self.data(offset, id_len)).ok();
+    }
+
+    #[inline]
+    pub fn internal_id(&self, external_id: &str) -> Option<u32> {
+        return self.ids.get(external_id).copied();
+    }
+
+    #[inline]
+    pub fn len(&self) -> u32 {
+        return ((self.rec_buf.len() + self.new_rec_buf.len()) / DOC_REC_LEN) as u32;
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        return self.rec_buf.is_empty() && self.new_rec_buf.is_empty();
+    }
+
+    #[inline]
+    fn record(&self, doc_id: u32) -> (usize, usize, usize) {
+        let start = doc_id as usize * DOC_REC_LEN;
+        let mut rec = match start.checked_sub(self.rec_buf.len()) {
+            Some(start) => &self.new_rec_buf[start..start + DOC_REC_LEN],
+            None => &self.rec_buf[start..start + DOC_REC_LEN],
+        };
+        return (rec.get_u64() as usize, rec.get_u32() as usize, rec.get_u32() as usize);
+    }
+
+    // A document never spans both buffers.
+    #[inline]
+    fn data(&self, offset: usize, len: usize) -> &[u8] {
+        return match offset.checked_sub(self.data_buf.len()) {
+            Some(offset) => &self.new_data_buf[offset..offset + len],
+            None => &self.data_buf[offset..offset + len],
+        };
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn build() -> DocBuf {
+        let mut docbuf = DocBuf::default();
+        assert_eq!(docbuf.append("a-1", br#"{"title":"fox"}"#), Some(0));
+        assert_eq!(docbuf.append("b-2", br#"{"title":"dog","count":2}"#), Some(1));
+        assert_eq!(docbuf.append("", b"{}"), Some(2));
+        return docbuf;
+    }
+
+    #[test]
+    fn test_append_and_get() {
+        let mut docbuf = build();
+        assert_eq!(docbuf.append("a-1", b"{}"), None);
+        assert_eq!(docbuf.len(), 3);
+        assert_eq!(docbuf.get(1), Some(&br#"{"title":"dog","count":2}"#[..]));
+        assert_eq!(docbuf.get(2), Some(&b"{}"[..]));
+        assert_eq!(docbuf.get(3), None);
+        assert_eq!(docbuf.external_id(0), Some("a-1"));
+        assert_eq!(docbuf.internal_id("b-2"), Some(1));
+        assert_eq!(docbuf.internal_id("c-3"), None);
+
+        assert_eq!(docbuf.remove_id("a-1"), Some(0));
+        assert_eq!(docbuf.append("a-1", b"{}"), Some(3));
+        assert_eq!(docbuf.external_id(0), Some("a-1"));
+        let mut bytes = BytesMut::new();
+        docbuf.write_bytes(&mut bytes);
+        assert_eq!(DocBuf::from_bytes(bytes.freeze()).unwrap().internal_id("a-1"), Some(3));
+    }
+
+    #[test]
+    fn test_from_bytes() {
+        let docbuf = build();
+        let mut bytes = BytesMut::new();
+        docbuf.write_bytes(&mut bytes);
+        let mut loaded = DocBuf::from_bytes(bytes.clone().freeze()).unwrap();
+        assert_eq!(loaded.len(), 3);
+        assert_eq!(loaded.get(0), Some(&br#"{"title":"fox"}"#[..]));
+        assert_eq!(loaded.internal_id(""), Some(2));
+
+        // Documents appended after loading are stored and saved after the loaded ones.
+        assert_eq!(loaded.append("a-1", b"{}"), None);
+        assert_eq!(loaded.append("c-3", br#"{"title":"cat"}"#), Some(3));
+        assert_eq!(loaded.get(3), Some(&br#"{"title":"cat"}"#[..]));
+        assert_eq!(loaded.external_id(2), Some(""));
+        let mut appended = BytesMut::new();
+        loaded.write_bytes(&mut appended);
+        let reloaded = DocBuf::from_bytes(appended.freeze()).unwrap();
+        assert_eq!(reloaded.external_id(3), Some("c-3"));
+        assert_eq!(reloaded.get(1), Some(&br#"{"title":"dog","count":2}"#[..]));
+
+        let truncated = bytes.clone().split_to(bytes.len() - 1).freeze();
+        assert!(DocBuf::from_bytes(truncated).is_none());
+        // Point the second record past the end of the data.
+        bytes[16 + DOC_REC_LEN] = 0xff;
+        assert!(DocBuf::from_bytes(bytes.freeze()).is_none());
+    }
+}
@@ -1,61 +1,904 @@
-// use bytes::{Buf, Bytes, BytesMut};
-// use tracing;
+use std::collections::BTreeMap;
+use std::ops::{Bound, Range};
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+
+use crate::analysis::Analyzer;
+use crate::postings::{write_postings, Postings};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IndexType {
+    Reverse,
+    Range,
+    Geospatial,
+}
+
+impl IndexType {
+    #[inline]
+    fn from_byte(byte: u8) -> Option<IndexType> {
+        return match byte {
+            0x1 => Some(IndexType::Reverse),
+            0x2 => Some(IndexType::Range),
+            0x3 => Some(IndexType::Geospatial),
+            _ => None,
+        };
+    }
+
+    #[inline]
+    fn to_byte(self) -> u8 {
+        return match self {
+            IndexType::Reverse => 0x1,
+            IndexType::Range => 0x2,
+            IndexType::Geospatial => 0x3,
+        };
+    }
+}
+
+// rec_buf holds fixed size records sorted by key so lookups can binary search them,
+// data_buf holds the variable length data the records point into.
 //
-// enum IndexType {
-//     Reverse,
-//     Range,
-//     Geospatial,
-// }
+// Reverse index record: term offset, term length, postings length, document count (u32 each).
+// The term bytes in data_buf are directly followed by its compressed posting list, see postings.rs.
+// norm_buf holds the field statistics for relevance scoring: the number of documents with the
+// field (u32), the total number of tokens in the field (u64) and the number of tokens of every
+// document (u32 each, by doc id, 0 without the field). Range and geospatial indexes have none.
 //
-// struct Index {
-//     index_name: String,
-//     index_type: IndexType,
-//     data_buf: BytesMut,
-//     rec_buf: BytesMut
-// }
+// Range index record: order preserving sort key of the value (u64) and doc id (u32),
+// sorted by key and then doc id. A range index has no data_buf.
 //
-// impl Index {
-//     fn from_bytes(name: String, mut bytes: Bytes, docbuf: DocBuf) -> Option<Index> {
-//         let index_type: IndexType = match bytes.get_u8() {
-//             0x1 => IndexType::Reverse,
-//             0x2 => IndexType::Range,
-//             0x3 => IndexType::Geospatial,
-//             x => {
-//                 tracing::warn!("Invalid index type: {:?}", x);
-//                 return None;
-//             },
-//         };
-//         bytes.advance(1);
-//         let rec_length: f64 = bytes.get_f64();
-//         if rec_length < 0.0 {
-//             tracing::warn!("Invalid index rec length! {:?}", rec_length);
-//             return None;
-//         }
-//         bytes.advance(4);
-//         let data_length: f64 = bytes.get_f64();
-//         if data_length < 0.0 {
-//             tracing::warn!("Invalid index data length! {:?}", data_length);
-//             return None;
-//         }
-//         bytes.advance(4);
-//         let rec_buf = BytesMut::from(&bytes[..rec_length as usize]);
-//         bytes.advance(rec_length as usize);
-//         let data: BytesMut = BytesMut::from(&bytes[..data_length as usize]);
-//         bytes.advance(data_length as usize);
+// Geospatial index record: the same as a range record with the Morton code of the point as key,
+// latitude and longitude quantized to 32 bits each, latitude in the odd bits.
 //
-//         for i in 0..data_length as usize {
-//         }
-//
-//
-//     }
-// }
-//
-// fn build_reverse(data_buf: &mut BytesMut, rec_buf: &mut BytesMut) {
-//
-//     Some(Index{
-//         index_name: name.clone(),
-//         index_type,
-//         data_buf,
-//         rec_buf,
-//     })
-// }
+// Indexes are immutable once built, the buffers may point into a memory mapped file.
+pub struct Index {
+    index_name: String,
+    index_type: IndexType,
+    data_buf: Bytes,
+    rec_buf: Bytes,
+    norm_buf: Bytes,
+}
+
+// Statistics of a text field over all documents of a segment, deleted ones included.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct FieldStats {
+    pub doc_count: u32,
+    pub total_length: u64,
+}
+
+const HEADER_LEN: usize = 13;
+const NORM_STATS_LEN: usize = 12;
+const REVERSE_REC_LEN: usize = 16;
+const RANGE_REC_LEN: usize = 12;
+const GEO_REC_LEN: usize = 12;
+
+const EARTH_RADIUS_METERS: f64 = 6_371_008.8;
+// Depth of the quad tree cells a bounding box is split into, deeper cells mean fewer points
+// outside the box are scanned but more binary searches.
+const GEO_COVER_LEVEL: u32 = 10;
+
+// A value of a numeric field, all values in one range index must have the same type.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Number {
+    Integer(i64),
+    Real(f64),
+}
+
+impl Number {
+    // Maps the value to an unsigned key with the same ordering.
+    #[inline]
+    fn sort_key(self) -> u64 {
+        return match self {
+            Number::Integer(v) => (v as u64) ^ (1 << 63),
+            Number::Real(v) => {
+                // Adding 0.0 turns -0.0 into 0.0 so both have the same key.
+                let bits = (v + 0.0).to_bits();
+                if bits >> 63 == 1 {
+                    !bits
+                } else {
+                    bits | (1 << 63)
+                }
+            }
+        };
+    }
+}
+
+struct ReverseRecord {
+    term_offset: usize,
+    term_len: usize,
+    postings_len: usize,
+    doc_count: u32,
+}
+
+impl Index {
+    pub fn from_bytes(name: String, mut bytes: Bytes) -> Option<Index> {
+        if bytes.remaining() < HEADER_LEN {
+            tracing::warn!("Index {} is truncated", name);
+            return None;
+        }
+        let index_type = match IndexType::from_byte(bytes.get_u8()) {
+            Some(index_type) => index_type,
+            None => {
+                tracing::warn!("Invalid index type for {}", name);
+                return None;
+            }
+        };
+        let rec_length = bytes.get_u32() as usize;
+        let data_length = bytes.get_u32() as usize;
+        let norm_length = bytes.get_u32() as usize;
+        if bytes.remaining() != rec_length + data_length + norm_length {
+            tracing::warn!(
+                "Invalid index lengths for {}: {} + {} + {}",
+                name,
+                rec_length,
+                data_length,
+                norm_length
+            );
+            return None;
+        }
+        let rec_len = match index_type {
+            IndexType::Reverse => REVERSE_REC_LEN,
+            IndexType::Range => RANGE_REC_LEN,
+            IndexType::Geospatial => GEO_REC_LEN,
+        };
+        if !rec_length.is_multiple_of(rec_len) {
+            tracing::warn!("Invalid {:?} index rec length for {}: {}", index_type, name, rec_length);
+            return None;
+        }
+        let valid_norms = match index_type {
+            IndexType::Reverse => norm_length >= NORM_STATS_LEN && (norm_length - NORM_STATS_LEN).is_multiple_of(4),
+            IndexType::Range | IndexType::Geospatial => norm_length == 0,
+        };
+        if !valid_norms {
+            tracing::warn!("Invalid {:?} index norm length for {}: {}", index_type, name, norm_length);
+            return None;
+        }
+        let rec_buf = bytes.split_to(rec_length);
+        let data_buf = bytes.split_to(data_length);
+        let norm_buf = bytes;
+
+        let index = Index {
+            index_name: name,
+            index_type,
+            data_buf,
+            rec_buf,
+            norm_buf,
+        };
+        if index_type == IndexType::Reverse {
+            for i in 0..index.term_count() {
+                let record = index.reverse_record(i);
+                let end = record.term_offset.checked_add(record.term_len + record.postings_len);
+                if end.is_none_or(|end| end > data_length) {
+                    tracing::warn!("Term {} of index {} is out of bounds", i, index.index_name);
+                    return None;
+                }
+            }
+        }
+        return Some(index);
+    }
+
+    pub fn write_bytes(&self, output: &mut BytesMut) {
+        output.reserve(HEADER_LEN + self.rec_buf.len() + self.data_buf.len() + self.norm_buf.len());
+        output.put_u8(self.index_type.to_byte());
+        output.put_u32(self.rec_buf.len() as u32);
+        output.put_u32(self.data_buf.len() as u32);
+        output.put_u32(self.norm_buf.len() as u32);
+        output.put_slice(&self.rec_buf);
+        output.put_slice(&self.data_buf);
+        output.put_slice(&self.norm_buf);
+    }
+
+    #[inline]
+    pub fn name(&self) -> &str {
+        return &self.index_name;
+    }
+
+    #[inline]
+    pub fn index_type(&self) -> IndexType {
+        return self.index_type;
+    }
+
+    // Number of distinct terms in a reverse index.
+    #[inline]
+    pub fn term_count(&self) -> usize {
+        return self.rec_buf.len() / REVERSE_REC_LEN;
+    }
+
+    // The term and postings at position i of a reverse index, terms are sorted.
+    #[inline]
+    pub fn term_at(&self, i: usize) -> (&[u8], Postings<'_>) {
+        let record = self.reverse_record(i);
+        let start = record.term_offset + record.term_len;
+        let postings = Postings::new(&self.data_buf[start..start + record.postings_len], record.doc_count);
+        return (self.record_term(&record), postings);
+    }
+
+    // The field statistics of a reverse index, zero for other indexes.
+    #[inline]
+    pub fn field_stats(&self) -> FieldStats {
+        if self.norm_buf.len() < NORM_STATS_LEN {
+            return FieldStats::default();
+        }
+        let mut stats = &self.norm_buf[..NORM_STATS_LEN];
+        return FieldStats {
+            doc_count: stats.get_u32(),
+            total_length: stats.get_u64(),
+        };
+    }
+
+    // Number of tokens of the field in the document, 0 if it does not have the field.
+    #[inline]
+    pub fn field_length(&self, doc_id: u32) -> u32 {
+        let start = NORM_STATS_LEN + doc_id as usize * 4;
+        return match self.norm_buf.get(start..start + 4) {
+            Some(mut length) => length.get_u32(),
+            None => 0,
+        };
+    }
+
+    // Number of values in a range or geospatial index.
+    #[inline]
+    pub fn record_count(&self) -> usize {
+        return self.rec_buf.len() / RANGE_REC_LEN;
+    }
+
+    // The sort key and doc id at position i of a range or geospatial index.
+    #[inline]
+    pub fn record_at(&self, i: usize) -> (u64, u32) {
+        let mut rec = &self.rec_buf[i * RANGE_REC_LEN..(i + 1) * RANGE_REC_LEN];
+        return (rec.get_u64(), rec.get_u32());
+    }
+
+    // Builds a range or geospatial index from records already sorted by key and doc id.
+    pub fn from_sorted_records(name: String, index_type: IndexType, rec_buf: BytesMut) -> Index {
+        return Index {
+            index_name: name,
+            index_type,
+            data_buf: Bytes::new(),
+            rec_buf: rec_buf.freeze(),
+            norm_buf: Bytes::new(),
+        };
+    }
+
+    // Looks up an analyzed term, see Analyzer.
+    pub fn lookup(&self, term: &str) -> Option<Postings<'_>> {
+        if self.index_type != IndexType::Reverse {
+            return None;
+        }
+        let term = term.as_bytes();
+        let (mut low, mut high) = (0, self.term_count());
+        while low < high {
+            let mid = low + (high - low) / 2;
+            let record = self.reverse_record(mid);
+            match self.record_term(&record).cmp(term) {
+                std::cmp::Ordering::Less => low = mid + 1,
+                std::cmp::Ordering::Greater => high = mid,
+                std::cmp::Ordering::Equal => return Some(self.term_at(mid).1),
+            }
+        }
+        return None;
+    }
+
+    // Positions of the terms starting with `prefix`, for term_at. Terms of a wildcard or fuzzy
+    // query are looked for in this range.
+    pub fn terms_with_prefix(&self, prefix: &[u8]) -> Range<usize> {
+        if self.index_type != IndexType::Reverse {
+            return 0..0;
+        }
+        let start = self.term_partition_point(|term| term < prefix);
+        let end = self.term_partition_point(|term| term < prefix || term.starts_with(prefix));
+        return start..end;
+    }
+
+    // Doc ids of all values between the bounds, in value order.
+    pub fn range(&self, lower: Bound<Number>, upper: Bound<Number>) -> RangeScan<'_> {
+        if self.index_type != IndexType::Range {
+            return RangeScan { records: &[] };
+        }
+        let count = self.rec_buf.len() / RANGE_REC_LEN;
+        let start = match lower {
+            Bound::Included(v) => self.range_partition_point(|key| key < v.sort_key()),
+            Bound::Excluded(v) => self.range_partition_point(|key| key <= v.sort_key()),
+            Bound::Unbounded => 0,
+        };
+        let end = match upper {
+            Bound::Included(v) => self.range_partition_point(|key| key <= v.sort_key()),
+            Bound::Excluded(v) => self.range_partition_point(|key| key < v.sort_key()),
+            Bound::Unbounded => count,
+        };
+        if start >= end {
+            return RangeScan { records: &[] };
+        }
+        return RangeScan {
+            records: &self.rec_buf[start * RANGE_REC_LEN..end * RANGE_REC_LEN],
+        };
+    }
+
+    // Doc ids with exactly this value.
+    #[inline]
+    pub fn equal(&self, value: Number) -> RangeScan<'_> {
+        return self.range(Bound::Included(value), Bound::Included(value));
+    }
+
+    // Appends the doc ids of all points inside the box, sorted by doc id. A box whose minimum
+    // longitude is larger than its maximum longitude crosses the antimeridian.
+    pub fn within_box(&self, min_lat: f64, min_lon: f64, max_lat: f64, max_lon: f64, output: &mut Vec<u32>) {
+        let start = output.len();
+        let inside = |_: f64, _: f64| true;
+        if min_lon > max_lon {
+            self.scan_geo_box((min_lat, min_lon, max_lat, 180.0), inside, output);
+            self.scan_geo_box((min_lat, -180.0, max_lat, max_lon), inside, output);
+        } else {
+            self.scan_geo_box((min_lat, min_lon, max_lat, max_lon), inside, output);
+        }
+        output[start..].sort_unstable();
+    }
+
+    // Appends the doc ids of all points within `meters` of the center, sorted by doc id.
+    pub fn within_distance(&self, lat: f64, lon: f64, meters: f64, output: &mut Vec<u32>) {
+        let start = output.len();
+        let inside = |point_lat: f64, point_lon: f64| haversine_distance(lat, lon, point_lat, point_lon) <= meters;
+        let angle = meters / EARTH_RADIUS_METERS;
+        let min_lat = lat - angle.to_degrees();
+        let max_lat = lat + angle.to_degrees();
+        // Close to a pole the circle covers every longitude.
+        let lon_delta = if min_lat <= -90.0 || max_lat >= 90.0 {
+            180.0
+        } else {
+            (angle.sin() / lat.to_radians().cos()).min(1.0).asin().to_degrees()
+        };
+        let (min_lat, max_lat) = (min_lat.max(-90.0), max_lat.min(90.0));
+        if lon_delta >= 180.0 {
+            self.scan_geo_box((min_lat, -180.0, max_lat, 180.0), inside, output);
+        } else if lon - lon_delta < -180.0 {
+            self.scan_geo_box((min_lat, lon - lon_delta + 360.0, max_lat, 180.0), inside, output);
+            self.scan_geo_box((min_lat, -180.0, max_lat, lon + lon_delta), inside, output);
+        } else if lon + lon_delta > 180.0 {
+            self.scan_geo_box((min_lat, lon - lon_delta, max_lat, 180.0), inside, output);
+            self.scan_geo_box((min_lat, -180.0, max_lat, lon + lon_delta - 360.0), inside, output);
+        } else {
+            self.scan_geo_box((min_lat, lon - lon_delta, max_lat, lon + lon_delta), inside, output);
+        }
+        output[start..].sort_unstable();
+    }
+
+    // Scans the key ranges of the cells covering the box (min lat, min lon, max lat, max lon)
+    // and keeps the points inside the box that `inside` accepts.
+    fn scan_geo_box(&self, geo_box: (f64, f64, f64, f64), inside: impl Fn(f64, f64) -> bool, output: &mut Vec<u32>) {
+        if self.index_type != IndexType::Geospatial {
+            return;
+        }
+        let (min_lat, min_lon, max_lat, max_lon) = geo_box;
+        let cell_box = (
+            quantize_lat(min_lat),
+            quantize_lon(min_lon),
+            quantize_lat(max_lat),
+            quantize_lon(max_lon),
+        );
+        let mut ranges = Vec::new();
+        cover_geo_box(cell_box, (0, 0), 1 << 32, 0, &mut ranges);
+        for (low, high) in ranges {
+            let mut records = &self.rec_buf[self.range_partition_point(|key| key < low) * GEO_REC_LEN..];
+            while !records.is_empty() {
+                let key = records.get_u64();
+                let doc_id = records.get_u32();
+                if key > high {
+                    break;
+                }
+                let (lat, lon) = (compact_bits(key >> 1) as u64, compact_bits(key) as u64);
+                let in_box = lat >= cell_box.0 && lon >= cell_box.1 && lat <= cell_box.2 && lon <= cell_box.3;
+                if in_box && inside(dequantize_lat(lat as u32), dequantize_lon(lon as u32)) {
+                    output.push(doc_id);
+                }
+            }
+        }
+    }
+
+    // Index of the first term that does not satisfy `pred`.
+    #[inline]
+    fn term_partition_point(&self, pred: impl Fn(&[u8]) -> bool) -> usize {
+        let (mut low, mut high) = (0, self.term_count());
+        while low < high {
+            let mid = low + (high - low) / 2;
+            if pred(self.record_term(&self.reverse_record(mid))) {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        return low;
+    }
+
+    // Index of the first record whose key does not satisfy `pred`.
+    #[inline]
+    fn range_partition_point(&self, pred: impl Fn(u64) -> bool) -> usize {
+        let (mut low, mut high) = (0, self.rec_buf.len() / RANGE_REC_LEN);
+        while low < high {
+            let mid = low + (high - low) / 2;
+            let mut rec = &self.rec_buf[mid * RANGE_REC_LEN..];
+            if pred(rec.get_u64()) {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        return low;
+    }
+
+    #[inline]
+    fn reverse_record(&self, i: usize) -> ReverseRecord {
+        let mut rec = &self.rec_buf[i * REVERSE_REC_LEN..(i + 1) * REVERSE_REC_LEN];
+        return ReverseRecord {
+            term_offset: rec.get_u32() as usize,
+            term_len: rec.get_u32() as usize,
+            postings_len: rec.get_u32() as usize,
+            doc_count: rec.get_u32(),
+        };
+    }
+
+    #[inline]
+    fn record_term(&self, record: &ReverseRecord) -> &[u8] {
+        return &self.data_buf[record.term_offset..record.term_offset + record.term_len];
+    }
+}
+
+// Collects the key ranges of the quad tree cells overlapping the quantized box, in key order.
+// A cell is an aligned square of `size` quantized units with its minimum corner at `corner`.
+fn cover_geo_box(cell_box: (u64, u64, u64, u64), corner: (u64, u64), size: u64, level: u32, ranges: &mut Vec<(u64, u64)>) {
+    let (min_lat, min_lon, max_lat, max_lon) = cell_box;
+    let (lat, lon) = corner;
+    let (last_lat, last_lon) = (lat + size - 1, lon + size - 1);
+    if last_lat < min_lat || lat > max_lat || last_lon < min_lon || lon > max_lon {
+        return;
+    }
+    let contained = lat >= min_lat && last_lat <= max_lat && lon >= min_lon && last_lon <= max_lon;
+    if contained || level == GEO_COVER_LEVEL {
+        let low = morton_key(lat as u32, lon as u32);
+        let high = morton_key(last_lat as u32, last_lon as u32);
+        match ranges.last_mut() {
+            Some((_, last_high)) if *last_high + 1 == low => *last_high = high,
+            _ => ranges.push((low, high)),
+        }
+        return;
+    }
+    let half = size / 2;
+    for (lat_offset, lon_offset) in [(0, 0), (0, half), (half, 0), (half, half)] {
+        cover_geo_box(cell_box, (lat + lat_offset, lon + lon_offset), half, level + 1, ranges);
+    }
+}
+
+#[inline]
+fn quantize_lat(lat: f64) -> u64 {
+    return ((lat + 90.0) / 180.0 * u32::MAX as f64).round().clamp(0.0, u32::MAX as f64) as u64;
+}
+
+#[inline]
+fn quantize_lon(lon: f64) -> u64 {
+    return ((lon + 180.0) / 360.0 * u32::MAX as f64).round().clamp(0.0, u32::MAX as f64) as u64;
+}
+
+#[inline]
+fn dequantize_lat(lat: u32) -> f64 {
+    return lat as f64 / u32::MAX as f64 * 180.0 - 90.0;
+}
+
+#[inline]
+fn dequantize_lon(lon: u32) -> f64 {
+    return lon as f64 / u32::MAX as f64 * 360.0 - 180.0;
+}
+
+#[inline]
+fn morton_key(lat: u32, lon: u32) -> u64 {
+    return (spread_bits(lat) << 1) | spread_bits(lon);
+}
+
+// Moves bit i of the value to bit 2i.
+#[inline]
+fn spread_bits(value: u32) -> u64 {
+    let mut v = value as u64;
+    v = (v | (v << 16)) & 0x0000_FFFF_0000_FFFF;
+    v = (v | (v << 8)) & 0x00FF_00FF_00FF_00FF;
+    v = (v | (v << 4)) & 0x0F0F_0F0F_0F0F_0F0F;
+    v = (v | (v << 2)) & 0x3333_3333_3333_3333;
+    v = (v | (v << 1)) & 0x5555_5555_5555_5555;
+    return v;
+}
+
+// Inverse of spread_bits, the odd bits are ignored.
+#[inline]
+fn compact_bits(value: u64) -> u32 {
+    let mut v = value & 0x5555_5555_5555_5555;
+    v = (v | (v >> 1)) & 0x3333_3333_3333_3333;
+    v = (v | (v >> 2)) & 0x0F0F_0F0F_0F0F_0F0F;
+    v = (v | (v >> 4)) & 0x00FF_00FF_00FF_00FF;
+    v = (v | (v >> 8)) & 0x0000_FFFF_0000_FFFF;
+    v = (v | (v >> 16)) & 0x0000_0000_FFFF_FFFF;
+    return v as u32;
+}
+
+// Great circle distance in meters.
+#[inline]
+pub fn haversine_distance(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> f64 {
+    let d_lat = (lat2 - lat1).to_radians() / 2.0;
+    let d_lon = (lon2 - lon1).to_radians() / 2.0;
+    let a = d_lat.sin().powi(2) + lat1.to_radians().cos() * lat2.to_radians().cos() * d_lon.sin().powi(2);
+    return 2.0 * EARTH_RADIUS_METERS * a.sqrt().min(1.0).asin();
+}
+
+pub struct RangeScan<'a> {
+    records: &'a [u8],
+}
+
+impl ExactSizeIterator for RangeScan<'_> {}
+
+impl Iterator for RangeScan<'_> {
+    type Item = u32;
+
+    #[inline]
+    fn next(&mut self) -> Option<u32> {
+        if self.records.is_empty() {
+            return None;
+        }
+        self.records.advance(8);
+        return Some(self.records.get_u32());
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = self.records.len() / RANGE_REC_LEN;
+        return (len, Some(len));
+    }
+}
+
+// Collects the postings of a text field, documents must be added in doc id order.
+#[derive(Default)]
+pub struct ReverseIndexBuilder {
+    terms: BTreeMap<String, Vec<(u32, Vec<u32>)>>,
+    // Number of terms by doc id.
+    field_lengths: Vec<u32>,
+}
+
+impl ReverseIndexBuilder {
+    pub fn add_document(&mut self, doc_id: u32, text: &str, analyzer: &Analyzer) {
+        // Checked before any token is added, the field lengths reach up to the last document.
+        if (doc_id as usize) + 1 < self.field_lengths.len() {
+            tracing::warn!("Document {} added out of order, ignoring", doc_id);
+            return;
+        }
+        let mut length = 0;
+        for (position, token) in analyzer.analyze(text) {
+            let postings = self.terms.entry(token).or_default();
+            match postings.last_mut() {
+                Some((last_id, positions)) if *last_id == doc_id => positions.push(position),
+                _ => postings.push((doc_id, vec![position])),
+            }
+            length += 1;
+        }
+        if self.field_lengths.len() <= doc_id as usize {
+            self.field_lengths.resize(doc_id as usize + 1, 0);
+        }
+        self.field_lengths[doc_id as usize] = length;
+    }
+
+    pub fn build(self, name: String) -> Index {
+        let mut writer = ReverseIndexWriter::default();
+        for (term, postings) in &self.terms {
+            writer.start_term(term.as_bytes());
+            for (doc_id, positions) in postings {
+                writer.add_posting(*doc_id, self.field_lengths[*doc_id as usize], positions.iter().copied());
+            }
+            writer.finish_term();
+        }
+        return writer.finish(name);
+    }
+}
+
+// Writes a reverse index one term at a time, terms must be added in sorted order and the
+// postings of a term in doc id order.
+#[derive(Default)]
+pub struct ReverseIndexWriter {
+    data_buf: BytesMut,
+    rec_buf: BytesMut,
+    term_offset: usize,
+    doc_ids: Vec<u32>,
+    freqs: Vec<u32>,
+    positions: Vec<u32>,
+    min_field_length: u32,
+    // Number of tokens by doc id, a document has a posting of every token of its field.
+    field_lengths: Vec<u32>,
+}
+
+impl ReverseIndexWriter {
+    #[inline]
+    pub fn start_term(&mut self, term: &[u8]) {
+        self.term_offset = self.data_buf.len();
+        self.data_buf.put_slice(term);
+        self.doc_ids.clear();
+        self.freqs.clear();
+        self.positions.clear();
+        self.min_field_length = u32::MAX;
+    }
+
+    // `field_length` is the number of tokens of the document's field.
+    #[inline]
+    pub fn add_posting(&mut self, doc_id: u32, field_length: u32, positions: impl Iterator<Item = u32>) {
+        let start = self.positions.len();
+        self.positions.extend(positions);
+        self.doc_ids.push(doc_id);
+        self.freqs.push((self.positions.len() - start) as u32);
+        self.min_field_length = self.min_field_length.min(field_length);
+        if self.field_lengths.len() <= doc_id as usize {
+            self.field_lengths.resize(doc_id as usize + 1, 0);
+        }
+        self.field_lengths[doc_id as usize] = field_length;
+    }
+
+    // A term without postings is left out.
+    #[inline]
+    pub fn finish_term(&mut self) {
+        if self.doc_ids.is_empty() {
+            self.data_buf.truncate(self.term_offset);
+            return;
+        }
+        let term_len = self.data_buf.len() - self.term_offset;
+        write_postings(&self.doc_ids, &self.freqs, &self.positions, self.min_field_length, &mut self.data_buf);
+        self.rec_buf.put_u32(self.term_offset as u32);
+        self.rec_buf.put_u32(term_len as u32);
+        self.rec_buf.put_u32((self.data_buf.len() - self.term_offset - term_len) as u32);
+        self.rec_buf.put_u32(self.doc_ids.len() as u32);
+    }
+
+    pub fn finish(self, name: String) -> Index {
+        let field_lengths = &self.field_lengths;
+        let mut norm_buf = BytesMut::with_capacity(NORM_STATS_LEN + field_lengths.len() * 4);
+        norm_buf.put_u32(field_lengths.iter().filter(|length| **length > 0).count() as u32);
+        norm_buf.put_u64(field_lengths.iter().map(|length| *length as u64).sum());
+        for length in field_lengths {
+            norm_buf.put_u32(*length);
+        }
+        return Index {
+            index_name: name,
+            index_type: IndexType::Reverse,
+            data_buf: self.data_buf.freeze(),
+            rec_buf: self.rec_buf.freeze(),
+            norm_buf: norm_buf.freeze(),
+        };
+    }
+}
+
+// Collects the values of a numeric field.
+#[derive(Default)]
+pub struct RangeIndexBuilder {
+    values: Vec<(u64, u32)>,
+}
+
+impl RangeIndexBuilder {
+    #[inline]
+    pub fn add_document(&mut self, doc_id: u32, value: Number) {
+        self.values.push((value.sort_key(), doc_id));
+    }
+
+    pub fn build(mut self, name: String) -> Index {
+        let mut rec_buf = BytesMut::with_capacity(self.values.len() * RANGE_REC_LEN);
+        build_range(&mut self.values, &mut rec_buf);
+        return Index {
+            index_name: name,
+            index_type: IndexType::Range,
+            data_buf: Bytes::new(),
+            rec_buf: rec_buf.freeze(),
+            norm_buf: Bytes::new(),
+        };
+    }
+}
+
+fn build_range(values: &mut [(u64, u32)], rec_buf: &mut BytesMut) {
+    values.sort_unstable();
+    for (key, doc_id) in values.iter() {
+        rec_buf.put_u64(*key);
+        rec_buf.put_u32(*doc_id);
+    }
+}
+
+// Collects the points of a geo point field.
+#[derive(Default)]
+pub struct GeoIndexBuilder {
+    values: Vec<(u64, u32)>,
+}
+
+impl GeoIndexBuilder {
+    #[inline]
+    pub fn add_document(&mut self, doc_id: u32, lat: f64, lon: f64) {
+        let key = morton_key(quantize_lat(lat) as u32, quantize_lon(lon) as u32);
+        self.values.push((key, doc_id));
+    }
+
+    pub fn build(mut self, name: String) -> Index {
+        let mut rec_buf = BytesMut::with_capacity(self.values.len() * GEO_REC_LEN);
+        build_range(&mut self.values, &mut rec_buf);
+        return Index {
+            index_name: name,
+            index_type: IndexType::Geospatial,
+            data_buf: Bytes::new(),
+            rec_buf: rec_buf.freeze(),
+            norm_buf: Bytes::new(),
+        };
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn build() -> Index {
+        let mut builder = ReverseIndexBuilder::default();
+        builder.add_document(0, "The quick brown fox", &Analyzer::STANDARD);
+        builder.add_document(1, "the lazy dog, the end", &Analyzer::STANDARD);
+        builder.add_document(4, "Quick! Quick fox", &Analyzer::STANDARD);
+        // Out of order, none of its tokens are added.
+        builder.add_document(2, "a lazy cat", &Analyzer::STANDARD);
+        return builder.build("body".to_owned());
+    }
+
+    fn postings(index: &Index, term: &str) -> Vec<(u32, u32, Vec<u32>)> {
+        return index
+            .lookup(term)
+            .unwrap()
+            .map(|p| (p.doc_id, p.frequency, p.positions().collect()))
+            .collect();
+    }
+
+    #[test]
+    fn test_reverse_lookup() {
+        let index = build();
+        assert_eq!(index.term_count(), 7);
+        assert_eq!(postings(&index, "the"), vec![(0, 1, vec![0]), (1, 2, vec![0, 3])]);
+        assert_eq!(postings(&index, "quick"), vec![(0, 1, vec![1]), (4, 2, vec![0, 1])]);
+        assert_eq!(postings(&index, "dog"), vec![(1, 1, vec![2])]);
+        assert_eq!(index.lookup("fox").unwrap().doc_count(), 2);
+        assert!(index.lookup("cat").is_none());
+        assert!(index.lookup("Quick").is_none());
+        assert!(index.lookup("").is_none());
+
+        let terms = |prefix: &str| -> Vec<&[u8]> {
+            return index.terms_with_prefix(prefix.as_bytes()).map(|i| index.term_at(i).0).collect();
+        };
+        assert_eq!(terms("qu"), vec![b"quick"]);
+        assert_eq!(terms("t"), vec![b"the"]);
+        assert_eq!(terms("").len(), 7);
+        assert!(terms("cat").is_empty());
+        assert!(terms("z").is_empty());
+    }
+
+    #[test]
+    fn test_from_bytes() {
+        let index = build();
+        let mut bytes = BytesMut::new();
+        index.write_bytes(&mut bytes);
+        let loaded = Index::from_bytes("body".to_owned(), bytes.clone().freeze()).unwrap();
+        assert_eq!(loaded.index_type(), IndexType::Reverse);
+        assert_eq!(postings(&loaded, "end"), vec![(1, 1, vec![4])]);
+        assert_eq!(loaded.field_stats(), FieldStats { doc_count: 3, total_length: 12 });
+        assert_eq!((loaded.field_length(1), loaded.field_length(2), loaded.field_length(4)), (5, 0, 3));
+        assert_eq!(loaded.field_length(5), 0);
+
+        let truncated = bytes.clone().split_to(bytes.len() - 1).freeze();
+        assert!(Index::from_bytes("body".to_owned(), truncated).is_none());
+        // The postings length of the last term record points past the data.
+        let mut out_of_bounds = bytes.clone();
+        let postings_len = HEADER_LEN + 6 * REVERSE_REC_LEN + 8;
+        out_of_bounds[postings_len..postings_len + 4].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(Index::from_bytes("body".to_owned(), out_of_bounds.freeze()).is_none());
+        bytes[0] = 0x9;
+        assert!(Index::from_bytes("body".to_owned(), bytes.freeze()).is_none());
+    }
+
+    fn build_numbers(values: &[(u32, Number)]) -> Index {
+        let mut builder = RangeIndexBuilder::default();
+        for (doc_id, value) in values {
+            builder.add_document(*doc_id, *value);
+        }
+        return builder.build("price".to_owned());
+    }
+
+    #[test]
+    fn test_range_scan() {
+        use Bound::*;
+        use Number::Real;
+
+        let index = build_numbers(&[
+            (0, Real(10.0)),
+            (1, Real(-2.5)),
+            (2, Real(20.0)),
+            (3, Real(15.5)),
+            (4, Real(10.0)),
+            (5, Real(-0.0)),
+            (6, Real(1e300)),
+        ]);
+        let scan = |lower, upper| index.range(lower, upper).collect::<Vec<_>>();
+        assert_eq!(scan(Included(Real(10.0)), Included(Real(20.0))), vec![0, 4, 3, 2]);
+        assert_eq!(scan(Excluded(Real(10.0)), Excluded(Real(20.0))), vec![3]);
+        assert_eq!(scan(Unbounded, Excluded(Real(10.0))), vec![1, 5]);
+        assert_eq!(scan(Excluded(Real(15.5)), Unbounded), vec![2, 6]);
+        assert_eq!(scan(Included(Real(-1e9)), Included(Real(0.0))), vec![1, 5]);
+        assert_eq!(scan(Included(Real(20.0)), Excluded(Real(10.0))), Vec::<u32>::new());
+        assert_eq!(index.equal(Real(10.0)).collect::<Vec<_>>(), vec![0, 4]);
+        assert_eq!(index.equal(Real(0.0)).collect::<Vec<_>>(), vec![5]);
+        assert_eq!(index.equal(Real(11.0)).count(), 0);
+    }
+
+    #[test]
+    fn test_integer_range_scan() {
+        use Number::Integer;
+
+        let index = build_numbers(&[(0, Integer(i64::MIN)), (1, Integer(-1)), (2, Integer(0)), (3, Integer(i64::MAX))]);
+        let scan = |lower, upper| index.range(lower, upper).collect::<Vec<_>>();
+        assert_eq!(scan(Bound::Unbounded, Bound::Included(Integer(-1))), vec![0, 1]);
+        assert_eq!(scan(Bound::Excluded(Integer(-1)), Bound::Unbounded), vec![2, 3]);
+        assert_eq!(index.range(Bound::Unbounded, Bound::Unbounded).len(), 4);
+
+        let mut bytes = BytesMut::new();
+        index.write_bytes(&mut bytes);
+        let loaded = Index::from_bytes("count".to_owned(), bytes.freeze()).unwrap();
+        assert_eq!(loaded.index_type(), IndexType::Range);
+        assert_eq!(loaded.equal(Integer(i64::MAX)).collect::<Vec<_>>(), vec![3]);
+        assert!(loaded.lookup("0").is_none());
+    }
+
+    // Amsterdam, Rotterdam, New York, Fiji on both sides of the antimeridian, McMurdo station.
+    const PLACES: [(f64, f64); 6] = [
+        (52.3676, 4.9041),
+        (51.9244, 4.4777),
+        (40.7128, -74.0060),
+        (-17.7134, 178.0650),
+        (-16.5, -179.9),
+        (-77.8419, 166.6863),
+    ];
+
+    fn build_geo() -> Index {
+        let mut builder = GeoIndexBuilder::default();
+        for (doc_id, (lat, lon)) in PLACES.iter().enumerate() {
+            builder.add_document(doc_id as u32, *lat, *lon);
+        }
+        return builder.build("loc".to_owned());
+    }
+
+    #[test]
+    fn test_geo_box() {
+        let index = build_geo();
+        let within_box = |min_lat, min_lon, max_lat, max_lon| {
+            let mut output = Vec::new();
+            index.within_box(min_lat, min_lon, max_lat, max_lon, &mut output);
+            return output;
+        };
+        assert_eq!(within_box(50.0, 3.0, 54.0, 7.0), vec![0, 1]);
+        assert_eq!(within_box(52.0, 3.0, 54.0, 7.0), vec![0]);
+        assert_eq!(within_box(52.3676, 4.9041, 52.3676, 4.9041), vec![0]);
+        assert_eq!(within_box(-20.0, 170.0, -10.0, -170.0), vec![3, 4]);
+        assert_eq!(within_box(-90.0, -180.0, 90.0, 180.0), vec![0, 1, 2, 3, 4, 5]);
+        assert_eq!(within_box(0.0, 0.0, 10.0, 10.0), Vec::<u32>::new());
+    }
+
+    #[test]
+    fn test_geo_distance() {
+        let index = build_geo();
+        let within_distance = |lat, lon, meters| {
+            let mut output = Vec::new();
+            index.within_distance(lat, lon, meters, &mut output);
+            return output;
+        };
+        let (amsterdam, rotterdam) = (PLACES[0], PLACES[1]);
+        let distance = haversine_distance(amsterdam.0, amsterdam.1, rotterdam.0, rotterdam.1);
+        assert!((distance - 57_000.0).abs() < 1_000.0, "{}", distance);
+        assert_eq!(within_distance(amsterdam.0, amsterdam.1, 10_000.0), vec![0]);
+        assert_eq!(within_distance(amsterdam.0, amsterdam.1, 60_000.0), vec![0, 1]);
+        assert_eq!(within_distance(-17.0, 180.0, 300_000.0), vec![3, 4]);
+        assert_eq!(within_distance(-90.0, 0.0, 1_500_000.0), vec![5]);
+        assert_eq!(within_distance(0.0, 0.0, 1_000.0), Vec::<u32>::new());
+
+        let mut bytes = BytesMut::new();
+        index.write_bytes(&mut bytes);
+        let loaded = Index::from_bytes("loc".to_owned(), bytes.freeze()).unwrap();
+        let mut output = Vec::new();
+        loaded.within_distance(40.7, -74.0, 5_000.0, &mut output);
+        assert_eq!(output, vec![2]);
+    }
+}
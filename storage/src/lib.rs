@@ -1,17 +1,873 @@
-mod indexes;
-mod docbuf;
+#![allow(clippy::needless_return)]
+
+mod checksum;
+pub mod analysis;
+pub mod doc_ids;
+pub mod docbuf;
+pub mod indexes;
+pub mod postings;
+pub mod scoring;
+pub mod segment;
+pub mod wal;
 
 use std::collections::HashMap;
-use bytes::BytesMut;
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use memmap2::Mmap;
+
+use checksum::crc32;
+use docbuf::DocBuf;
+use indexes::Index;
+use segment::{FieldValue, MergeTask, Segment, SegmentWriter, TieredMergePolicy};
+
+// Database and segment file layout, all integers big endian:
+//
+// header:   magic (8 bytes), version (u32), section count (u32),
+//           section table length (u64), section table checksum (u32)
+// table:    per section: kind (u8), collection name length (u16), collection name,
+//           section name length (u16), section name, offset (u64), length (u64), checksum (u32)
+// sections: the section data at the offsets from the start of the file
+//
+// The database file has for every collection a section named "definition", one named
+// "segments" with the ids (u64 each) of its segments and one named "deletes" with, for every
+// segment with deleted documents, its id (u64), the number of deleted documents (u32) and
+// their doc ids (u32 each). Segment i is stored next to the database
+// file in `<database>.<i>.seg`, with a section named "docs" and one index section per field,
+// named after the field, all with an empty collection name. Segment files are written once and
+// never changed, a merge writes a new segment and the old files are removed once the database
+// file no longer lists them.
+const MAGIC: &[u8; 8] = b"FINNE\0DB";
+pub const FORMAT_VERSION: u32 = 5;
+const HEADER_LEN: usize = 28;
+// Length of a section table entry without its names.
+const SECTION_ENTRY_LEN: usize = 1 + 2 + 2 + 8 + 8 + 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SectionKind {
+    Definition,
+    Docs,
+    Index,
+    Segments,
+    Deletes,
+}
+
+impl SectionKind {
+    #[inline]
+    fn from_byte(byte: u8) -> Option<SectionKind> {
+        return match byte {
+            0x1 => Some(SectionKind::Definition),
+            0x2 => Some(SectionKind::Docs),
+            0x3 => Some(SectionKind::Index),
+            0x4 => Some(SectionKind::Segments),
+            0x5 => Some(SectionKind::Deletes),
+            _ => None,
+        };
+    }
+
+    #[inline]
+    fn to_byte(self) -> u8 {
+        return match self {
+            SectionKind::Definition => 0x1,
+            SectionKind::Docs => 0x2,
+            SectionKind::Index => 0x3,
+            SectionKind::Segments => 0x4,
+            SectionKind::Deletes => 0x5,
+        };
+    }
+}
+
+#[derive(Debug)]
+pub enum StorageError {
+    Io(io::Error),
+    NotADatabase,
+    UnsupportedVersion(u32),
+    Corrupt(String),
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return match self {
+            StorageError::Io(e) => write!(f, "{}", e),
+            StorageError::NotADatabase => write!(f, "not a Finne database file"),
+            StorageError::UnsupportedVersion(version) => write!(
+                f,
+                "unsupported database format version {}, expected {}",
+                version, FORMAT_VERSION
+            ),
+            StorageError::Corrupt(reason) => write!(f, "corrupt database: {}", reason),
+        };
+    }
+}
+
+impl std::error::Error for StorageError {}
+
+impl From<io::Error> for StorageError {
+    #[inline]
+    fn from(e: io::Error) -> Self {
+        return StorageError::Io(e);
+    }
+}
+
+// The stored data of one collection: immutable segments and the in-memory segment new
+// documents are added to.
+pub struct CollectionBuf {
+    pub name: String,
+    // The collection definition as given by the server, storage does not interpret it.
+    pub definition: Bytes,
+    // Oldest first, a search runs on every segment.
+    pub segments: Vec<Rc<Segment>>,
+    // New documents, searchable once flushed into a segment.
+    pub writer: SegmentWriter,
+    merge: Option<MergeTask>,
+    // Persisted segments replaced by a merge, their files are removed on the next save.
+    removed_segments: Vec<u64>,
+}
+
+impl CollectionBuf {
+    #[inline]
+    pub fn new(name: String, definition: Bytes) -> CollectionBuf {
+        return CollectionBuf {
+            name,
+            definition,
+            segments: Vec::new(),
+            writer: SegmentWriter::default(),
+            merge: None,
+            removed_segments: Vec::new(),
+        };
+    }
+
+    // Adds the document to the in-memory segment and deletes the document it replaces.
+    // Returns whether there was such a document.
+    pub fn update(&mut self, external_id: &str, doc: &[u8], fields: &[(&str, FieldValue<'_>)]) -> bool {
+        let existed = self.delete(external_id);
+        // The id is free after the delete.
+        self.writer.add_document(external_id, doc, fields);
+        return existed;
+    }
+
+    // Returns whether there was such a document.
+    pub fn delete(&mut self, external_id: &str) -> bool {
+        for segment in &self.segments {
+            if let Some(doc_id) = segment.live_id(external_id) {
+                return segment.deletes.delete(doc_id);
+            }
+        }
+        return self.writer.delete(external_id);
+    }
+
+    #[inline]
+    pub fn contains(&self, external_id: &str) -> bool {
+        return self.get(external_id).is_some();
+    }
+
+    // The stored document, also when it is not flushed yet.
+    pub fn get(&self, external_id: &str) -> Option<&[u8]> {
+        for segment in &self.segments {
+            if let Some(doc_id) = segment.live_id(external_id) {
+                return segment.docs.get(doc_id);
+            }
+        }
+        return self.writer.get(external_id);
+    }
 
-struct MemoryBuf {
-    indexes: BytesMut,
-    index_count: usize,
-    index: HashMap<&'static str, BytesMut>,
-    docs: BytesMut,
+    // Number of documents that are not deleted.
+    #[inline]
+    pub fn doc_count(&self) -> u32 {
+        return self.segments.iter().map(|s| s.live_count()).sum::<u32>() + self.writer.live_count();
+    }
+
+    // Turns the in-memory segment into an immutable segment, making its documents searchable.
+    pub fn flush(&mut self, next_segment_id: &mut u64) {
+        if self.writer.is_empty() {
+            return;
+        }
+        let writer = std::mem::take(&mut self.writer);
+        if writer.live_count() == 0 {
+            return;
+        }
+        self.segments.push(Rc::new(writer.finish(*next_segment_id)));
+        *next_segment_id += 1;
+    }
+
+    #[inline]
+    pub fn is_merging(&self) -> bool {
+        return self.merge.is_some();
+    }
+
+    // Starts a merge when the policy finds one and runs the current merge for about `budget`
+    // units of work. Returns the merged segment when a merge was completed, it replaces its
+    // inputs at the position of the first one.
+    pub fn merge_step(
+        &mut self,
+        policy: &TieredMergePolicy,
+        budget: usize,
+        next_segment_id: &mut u64,
+    ) -> Option<Rc<Segment>> {
+        let merge = match &mut self.merge {
+            Some(merge) => merge,
+            None => {
+                // Deleted documents do not count, so segments with many of them are merged sooner.
+                let sizes: Vec<u32> = self.segments.iter().map(|s| s.live_count()).collect();
+                let inputs = policy.find_merge(&sizes)?;
+                let inputs = inputs.into_iter().map(|i| self.segments[i].clone()).collect();
+                self.merge.insert(MergeTask::new(inputs))
+            }
+        };
+        if !merge.step(budget) {
+            return None;
+        }
+        let merge = self.merge.take().unwrap();
+        let inputs: Vec<Rc<Segment>> = merge.inputs().to_vec();
+        let merged = Rc::new(merge.finish(*next_segment_id));
+        *next_segment_id += 1;
+        let position = self.segments.iter().position(|s| Rc::ptr_eq(s, &inputs[0])).unwrap();
+        self.segments.retain(|s| !inputs.iter().any(|input| Rc::ptr_eq(s, input)));
+        self.segments.insert(position, merged.clone());
+        for input in inputs {
+            if input.persisted.get() {
+                self.removed_segments.push(input.id);
+            }
+        }
+        return Some(merged);
+    }
+}
+
+#[derive(Default)]
+pub struct MemoryBuf {
+    pub collections: Vec<CollectionBuf>,
+    // The id the next flushed or merged segment gets.
+    pub next_segment_id: u64,
 }
 
 impl MemoryBuf {
-    // fn from_file(path: &str) -> MemoryBuf {
-    // }
+    #[inline]
+    pub fn from_file(path: &Path) -> Result<MemoryBuf, StorageError> {
+        return MemoryBuf::load(path, false);
+    }
+
+    // Maps the segment files read-only instead of reading them, indexes and documents point
+    // into the mapping, so processes loading the same files share their pages in the page cache.
+    //
+    // Segment files are never modified, see the file layout above.
+    #[inline]
+    pub fn from_file_mmap(path: &Path) -> Result<MemoryBuf, StorageError> {
+        return MemoryBuf::load(path, true);
+    }
+
+    fn load(path: &Path, mmap: bool) -> Result<MemoryBuf, StorageError> {
+        let manifest = read_manifest(Bytes::from(fs::read(path)?))?;
+        let mut db = MemoryBuf::default();
+        for (name, definition, segment_ids, deletes) in manifest {
+            let mut collection = CollectionBuf::new(name, definition);
+            for id in segment_ids {
+                let segment_path = segment_path(path, id);
+                let bytes = match read_file(&segment_path, mmap) {
+                    Ok(bytes) => bytes,
+                    Err(StorageError::Io(e)) if e.kind() == io::ErrorKind::NotFound => {
+                        return Err(corrupt(format!("missing segment file {}", segment_path.display())));
+                    }
+                    Err(e) => return Err(e),
+                };
+                let segment = read_segment(id, bytes)?;
+                if let Some((_, doc_ids)) = deletes.iter().find(|(segment_id, _)| *segment_id == id) {
+                    for doc_id in doc_ids {
+                        if !segment.deletes.delete(*doc_id) {
+                            return Err(corrupt(format!("invalid delete of document {} in segment {}", doc_id, id)));
+                        }
+                    }
+                }
+                segment.persisted.set(true);
+                collection.segments.push(Rc::new(segment));
+                db.next_segment_id = db.next_segment_id.max(id + 1);
+            }
+            db.collections.push(collection);
+        }
+        return Ok(db);
+    }
+
+    #[inline]
+    pub fn save(&mut self, path: &Path) -> Result<(), StorageError> {
+        return save_collections(path, self.collections.iter_mut());
+    }
+}
+
+// The file of a segment of the database at `path`.
+pub fn segment_path(path: &Path, id: u64) -> PathBuf {
+    let mut segment_path = path.as_os_str().to_owned();
+    segment_path.push(format!(".{}.seg", id));
+    return PathBuf::from(segment_path);
+}
+
+#[inline]
+fn read_file(path: &Path, mmap: bool) -> Result<Bytes, StorageError> {
+    if !mmap {
+        return Ok(Bytes::from(fs::read(path)?));
+    }
+    let file = fs::File::open(path)?;
+    // Safety: segment files are only written under a new name, never in place.
+    let mmap = unsafe { Mmap::map(&file)? };
+    return Ok(Bytes::from_owner(mmap));
+}
+
+// Writes the segments not yet saved to their own files and then the collection definitions and
+// segment lists to `path`. The documents in the in-memory segment are not saved, flush them
+// first. Every file is written under a temporary name and renamed, so a crash while saving
+// leaves the previous database intact.
+pub fn save_collections<'a>(
+    path: &Path,
+    collections: impl Iterator<Item = &'a mut CollectionBuf>,
+) -> Result<(), StorageError> {
+    let collections: Vec<&mut CollectionBuf> = collections.collect();
+    let mut bytes = BytesMut::new();
+    for collection in &collections {
+        for segment in &collection.segments {
+            if segment.persisted.get() {
+                continue;
+            }
+            bytes.clear();
+            write_segment(segment, &mut bytes);
+            write_file(&segment_path(path, segment.id), &bytes)?;
+            segment.persisted.set(true);
+        }
+    }
+    bytes.clear();
+    write_manifest(collections.iter().map(|c| &**c), &mut bytes);
+    write_file(path, &bytes)?;
+
+    for collection in collections {
+        for id in collection.removed_segments.drain(..) {
+            let segment_path = segment_path(path, id);
+            if let Err(e) = fs::remove_file(&segment_path) {
+                tracing::warn!("Could not remove {}: {}", segment_path.display(), e);
+            }
+        }
+    }
+    return Ok(());
+}
+
+fn write_file(path: &Path, bytes: &[u8]) -> Result<(), StorageError> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let mut file = fs::File::create(&tmp_path)?;
+    file.write_all(bytes)?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)?;
+    return sync_dir(path);
+}
+
+// Makes a rename into the directory of `path` durable.
+#[inline]
+fn sync_dir(path: &Path) -> Result<(), StorageError> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    fs::File::open(dir)?.sync_all()?;
+    return Ok(());
+}
+
+// Writes a segment file a slice at a time, for merged segments that are too large to write
+// between two requests. The file gets its final name once complete, the database file lists it
+// from the next save on. A save before that writes the segment itself, the slices written so
+// far are then thrown away.
+pub struct SegmentFileWriter {
+    segment: Rc<Segment>,
+    path: PathBuf,
+    tmp_path: PathBuf,
+    file: Option<fs::File>,
+    bytes: BytesMut,
+    written: usize,
+}
+
+impl SegmentFileWriter {
+    pub fn create(path: &Path, segment: Rc<Segment>) -> Result<SegmentFileWriter, StorageError> {
+        let path = segment_path(path, segment.id);
+        // Not the name write_file uses, a save may write the same segment in the meantime.
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".part");
+        let tmp_path = PathBuf::from(tmp_path);
+        let file = fs::File::create(&tmp_path)?;
+        let mut bytes = BytesMut::new();
+        write_segment(&segment, &mut bytes);
+        return Ok(SegmentFileWriter {
+            segment,
+            path,
+            tmp_path,
+            file: Some(file),
+            bytes,
+            written: 0,
+        });
+    }
+
+    // Writes up to `budget` bytes. Returns true once the file is complete, or no longer needed
+    // because it was saved or merged away in the meantime.
+    pub fn step(&mut self, budget: usize) -> Result<bool, StorageError> {
+        // The collection holds the other reference as long as the segment is searched.
+        if self.segment.persisted.get() || Rc::strong_count(&self.segment) == 1 {
+            self.abandon();
+            return Ok(true);
+        }
+        let file = match &mut self.file {
+            Some(file) => file,
+            None => return Ok(true),
+        };
+        let end = self.bytes.len().min(self.written + budget);
+        file.write_all(&self.bytes[self.written..end])?;
+        self.written = end;
+        if self.written < self.bytes.len() {
+            return Ok(false);
+        }
+        file.sync_all()?;
+        self.file = None;
+        fs::rename(&self.tmp_path, &self.path)?;
+        sync_dir(&self.path)?;
+        self.segment.persisted.set(true);
+        return Ok(true);
+    }
+
+    #[inline]
+    pub fn segment_id(&self) -> u64 {
+        return self.segment.id;
+    }
+
+    fn abandon(&mut self) {
+        if self.file.take().is_some() {
+            if let Err(e) = fs::remove_file(&self.tmp_path) {
+                tracing::warn!("Could not remove {}: {}", self.tmp_path.display(), e);
+            }
+        }
+    }
+}
+
+impl Drop for SegmentFileWriter {
+    // A failed or unfinished write leaves no file behind.
+    fn drop(&mut self) {
+        self.abandon();
+    }
+}
+
+pub fn write_manifest<'a>(collections: impl Iterator<Item = &'a CollectionBuf>, output: &mut BytesMut) {
+    let mut sections: Vec<(SectionKind, &str, &str, BytesMut)> = Vec::new();
+    for collection in collections {
+        let name = collection.name.as_str();
+        sections.push((SectionKind::Definition, name, "definition", BytesMut::from(&collection.definition[..])));
+        let mut segment_ids = BytesMut::with_capacity(collection.segments.len() * 8);
+        for segment in &collection.segments {
+            segment_ids.put_u64(segment.id);
+        }
+        sections.push((SectionKind::Segments, name, "segments", segment_ids));
+        let mut deletes = BytesMut::new();
+        for segment in collection.segments.iter().filter(|s| s.deletes.count() > 0) {
+            deletes.put_u64(segment.id);
+            deletes.put_u32(segment.deletes.count());
+            for doc_id in segment.deletes.iter() {
+                deletes.put_u32(doc_id);
+            }
+        }
+        sections.push((SectionKind::Deletes, name, "deletes", deletes));
+    }
+    write_sections(&sections, output);
+}
+
+pub fn write_segment(segment: &Segment, output: &mut BytesMut) {
+    let mut sections: Vec<(SectionKind, &str, &str, BytesMut)> = Vec::new();
+    let mut docs = BytesMut::new();
+    segment.docs.write_bytes(&mut docs);
+    sections.push((SectionKind::Docs, "", "docs", docs));
+    let mut index_names: Vec<&String> = segment.indexes.keys().collect();
+    index_names.sort();
+    for index_name in index_names {
+        let mut index = BytesMut::new();
+        segment.indexes[index_name].write_bytes(&mut index);
+        sections.push((SectionKind::Index, "", index_name, index));
+    }
+    write_sections(&sections, output);
+}
+
+fn write_sections(sections: &[(SectionKind, &str, &str, BytesMut)], output: &mut BytesMut) {
+    let mut table = BytesMut::new();
+    let table_len: usize = sections.iter().map(|(_, c, n, _)| SECTION_ENTRY_LEN + c.len() + n.len()).sum();
+    let mut offset = HEADER_LEN + table_len;
+    for (kind, collection, name, data) in sections {
+        table.put_u8(kind.to_byte());
+        table.put_u16(collection.len() as u16);
+        table.put_slice(collection.as_bytes());
+        table.put_u16(name.len() as u16);
+        table.put_slice(name.as_bytes());
+        table.put_u64(offset as u64);
+        table.put_u64(data.len() as u64);
+        table.put_u32(crc32(data));
+        offset += data.len();
+    }
+
+    output.reserve(offset);
+    output.put_slice(MAGIC);
+    output.put_u32(FORMAT_VERSION);
+    output.put_u32(sections.len() as u32);
+    output.put_u64(table.len() as u64);
+    output.put_u32(crc32(&table));
+    output.put_slice(&table);
+    for (_, _, _, data) in sections {
+        output.put_slice(data);
+    }
+}
+
+// Returns the name, definition, segment ids and deleted doc ids by segment of every collection
+// in the database file.
+fn read_manifest(bytes: Bytes) -> Result<Vec<ManifestEntry>, StorageError> {
+    let mut collections: Vec<ManifestEntry> = Vec::new();
+    let mut segment_lists = Vec::new();
+    let mut delete_lists = Vec::new();
+    for (kind, collection, _, data) in read_sections(&bytes)? {
+        match kind {
+            SectionKind::Definition => {
+                if collections.iter().any(|(name, _, _, _)| *name == collection) {
+                    return Err(corrupt(format!("collection '{}' is defined twice", collection)));
+                }
+                collections.push((collection, data, Vec::new(), Vec::new()));
+            }
+            SectionKind::Segments => segment_lists.push((collection, data)),
+            SectionKind::Deletes => delete_lists.push((collection, data)),
+            SectionKind::Docs | SectionKind::Index => {
+                return Err(corrupt(format!("segment data in the database file for '{}'", collection)));
+            }
+        }
+    }
+    for (collection, mut data) in segment_lists {
+        let segment_ids = match collections.iter_mut().find(|(name, _, _, _)| *name == collection) {
+            Some((_, _, segment_ids, _)) => segment_ids,
+            None => return Err(corrupt(format!("section of unknown collection '{}'", collection))),
+        };
+        if !data.len().is_multiple_of(8) {
+            return Err(corrupt(format!("invalid segment list of '{}'", collection)));
+        }
+        while data.has_remaining() {
+            segment_ids.push(data.get_u64());
+        }
+    }
+    for (collection, mut data) in delete_lists {
+        let (segment_ids, deletes) = match collections.iter_mut().find(|(name, _, _, _)| *name == collection) {
+            Some((_, _, segment_ids, deletes)) => (segment_ids, deletes),
+            None => return Err(corrupt(format!("section of unknown collection '{}'", collection))),
+        };
+        let invalid = || corrupt(format!("invalid delete list of '{}'", collection));
+        while data.has_remaining() {
+            if data.remaining() < 12 {
+                return Err(invalid());
+            }
+            let segment_id = data.get_u64();
+            let count = data.get_u32() as usize;
+            if !segment_ids.contains(&segment_id) || data.remaining() / 4 < count {
+                return Err(invalid());
+            }
+            deletes.push((segment_id, (0..count).map(|_| data.get_u32()).collect()));
+        }
+    }
+    return Ok(collections);
+}
+
+// Name, definition, segment ids and deleted doc ids by segment of a collection.
+type ManifestEntry = (String, Bytes, Vec<u64>, Vec<(u64, Vec<u32>)>);
+
+fn read_segment(id: u64, bytes: Bytes) -> Result<Segment, StorageError> {
+    let mut docs = None;
+    let mut indexes = HashMap::new();
+    for (kind, _, name, data) in read_sections(&bytes)? {
+        match kind {
+            SectionKind::Docs => match DocBuf::from_bytes(data) {
+                Some(loaded) => docs = Some(loaded),
+                None => return Err(corrupt(format!("invalid documents in segment {}", id))),
+            },
+            SectionKind::Index => match Index::from_bytes(name.clone(), data) {
+                Some(index) => {
+                    indexes.insert(name, index);
+                }
+                None => return Err(corrupt(format!("invalid index '{}' in segment {}", name, id))),
+            },
+            SectionKind::Definition | SectionKind::Segments | SectionKind::Deletes => {
+                return Err(corrupt(format!("unexpected section '{}' in segment {}", name, id)));
+            }
+        }
+    }
+    let docs = match docs {
+        Some(docs) => docs,
+        None => return Err(corrupt(format!("segment {} has no documents", id))),
+    };
+    return Ok(Segment {
+        id,
+        deletes: segment::Tombstones::new(docs.len()),
+        docs,
+        indexes,
+        persisted: std::cell::Cell::new(false),
+    });
+}
+
+// Checks the header and section table and returns the kind, collection, name and data of
+// every section.
+fn read_sections(bytes: &Bytes) -> Result<Vec<(SectionKind, String, String, Bytes)>, StorageError> {
+    if bytes.len() < MAGIC.len() || &bytes[..MAGIC.len()] != MAGIC {
+        return Err(StorageError::NotADatabase);
+    }
+    if bytes.len() < HEADER_LEN {
+        return Err(corrupt("truncated header"));
+    }
+    let mut header = &bytes[MAGIC.len()..HEADER_LEN];
+    let version = header.get_u32();
+    if version != FORMAT_VERSION {
+        return Err(StorageError::UnsupportedVersion(version));
+    }
+    let section_count = header.get_u32();
+    let table_len = header.get_u64() as usize;
+    let table_checksum = header.get_u32();
+    if bytes.len() - HEADER_LEN < table_len {
+        return Err(corrupt("truncated section table"));
+    }
+    let mut table = &bytes[HEADER_LEN..HEADER_LEN + table_len];
+    if crc32(table) != table_checksum {
+        return Err(corrupt("section table checksum mismatch"));
+    }
+    // The count is not covered by the checksum, it must not reserve more than the table holds.
+    if section_count as usize > table_len / SECTION_ENTRY_LEN {
+        return Err(corrupt("more sections than the section table holds"));
+    }
+    let mut sections = Vec::with_capacity(section_count as usize);
+    for _ in 0..section_count {
+        sections.push(read_section(&mut table, bytes)?);
+    }
+    if !table.is_empty() {
+        return Err(corrupt("unexpected data after the section table"));
+    }
+    return Ok(sections);
+}
+
+#[inline]
+fn corrupt(reason: impl Into<String>) -> StorageError {
+    return StorageError::Corrupt(reason.into());
+}
+
+// Reads one section table entry and returns the kind, collection, section name and data.
+fn read_section(table: &mut &[u8], bytes: &Bytes) -> Result<(SectionKind, String, String, Bytes), StorageError> {
+    let truncated = || corrupt("truncated section table");
+    if table.remaining() < 3 {
+        return Err(truncated());
+    }
+    let kind = match SectionKind::from_byte(table.get_u8()) {
+        Some(kind) => kind,
+        None => return Err(corrupt("unknown section kind")),
+    };
+    let collection = read_name(table).ok_or_else(truncated)?;
+    let name = read_name(table).ok_or_else(truncated)?;
+    if table.remaining() < 20 {
+        return Err(truncated());
+    }
+    let offset = table.get_u64() as usize;
+    let len = table.get_u64() as usize;
+    let checksum = table.get_u32();
+    let section = format!("{}/{}", collection, name);
+    let data = match offset.checked_add(len) {
+        Some(end) if end <= bytes.len() => bytes.slice(offset..end),
+        _ => return Err(corrupt(format!("section '{}' is out of bounds", section))),
+    };
+    if crc32(&data) != checksum {
+        return Err(corrupt(format!("section '{}' checksum mismatch", section)));
+    }
+    return Ok((kind, collection, name, data));
+}
+
+#[inline]
+fn read_name(table: &mut &[u8]) -> Option<String> {
+    if table.remaining() < 2 {
+        return None;
+    }
+    let len = table.get_u16() as usize;
+    if table.remaining() < len {
+        return None;
+    }
+    let name = String::from_utf8_lossy(&table[..len]).into_owned();
+    table.advance(len);
+    return Some(name);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use analysis::Analyzer;
+    use indexes::Number;
+
+    fn temp_path(name: &str) -> PathBuf {
+        return std::env::temp_dir().join(format!("finne_storage_{}_test_{}.db", name, std::process::id()));
+    }
+
+    fn add(collection: &mut CollectionBuf, id: &str, text: &str) {
+        let doc = format!(r#"{{"al":"{}"}}"#, text);
+        let fields = [("al", FieldValue::Text(text, &Analyzer::STANDARD)), ("n", FieldValue::Number(Number::Integer(1)))];
+        assert!(!collection.update(id, doc.as_bytes(), &fields));
+    }
+
+    fn build() -> MemoryBuf {
+        let mut db = MemoryBuf::default();
+        let mut pets = CollectionBuf::new("pets".to_owned(), Bytes::from_static(br#"{"name":"pets"}"#));
+        add(&mut pets, "1", "quick fox");
+        add(&mut pets, "2", "lazy dog");
+        pets.flush(&mut db.next_segment_id);
+        add(&mut pets, "3", "cat");
+        pets.flush(&mut db.next_segment_id);
+        let empty = CollectionBuf::new("empty".to_owned(), Bytes::from_static(b"{}"));
+        db.collections = vec![pets, empty];
+        return db;
+    }
+
+    fn remove(path: &Path) {
+        for id in 0..10 {
+            let _ = fs::remove_file(segment_path(path, id));
+        }
+        fs::remove_file(path).unwrap();
+    }
+
+    fn manifest_error(bytes: BytesMut) -> String {
+        return read_manifest(bytes.freeze()).err().unwrap().to_string();
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let path = temp_path("load");
+        let mut db = build();
+        db.save(&path).unwrap();
+        assert!(segment_path(&path, 1).exists());
+
+        let loaded = MemoryBuf::from_file(&path).unwrap();
+        assert_eq!(loaded.collections.len(), 2);
+        assert_eq!(loaded.next_segment_id, 2);
+        let pets = &loaded.collections[0];
+        assert_eq!((pets.name.as_str(), &pets.definition[..]), ("pets", &br#"{"name":"pets"}"#[..]));
+        assert_eq!(pets.segments.len(), 2);
+        assert_eq!(pets.get("2"), Some(&br#"{"al":"lazy dog"}"#[..]));
+        assert_eq!(pets.doc_count(), 3);
+        let postings: Vec<u32> = pets.segments[0].indexes["al"].lookup("dog").unwrap().map(|p| p.doc_id).collect();
+        assert_eq!(postings, vec![1]);
+        assert_eq!(loaded.collections[1].doc_count(), 0);
+        remove(&path);
+        assert!(matches!(MemoryBuf::from_file(&path), Err(StorageError::Io(_))));
+    }
+
+    #[test]
+    fn test_merge_and_mmap_load() {
+        let path = temp_path("mmap");
+        let mut db = build();
+        db.save(&path).unwrap();
+        let mut loaded = MemoryBuf::from_file_mmap(&path).unwrap();
+        let policy = TieredMergePolicy {
+            segments_per_tier: 2,
+            min_segment_size: 10,
+        };
+        let pets = &mut loaded.collections[0];
+        assert_eq!(pets.get("1"), Some(&br#"{"al":"quick fox"}"#[..]));
+        let merged = loop {
+            match pets.merge_step(&policy, 1, &mut loaded.next_segment_id) {
+                Some(merged) => break merged,
+                None => assert!(pets.is_merging()),
+            }
+        };
+        assert_eq!(pets.segments.len(), 1);
+        assert_eq!(pets.segments[0].id, 2);
+        assert_eq!(pets.segments[0].indexes["n"].equal(Number::Integer(1)).len(), 3);
+
+        // The merged segment is written in slices, the save then only writes the database file
+        // and removes the replaced, still mapped, files.
+        let mut writer = SegmentFileWriter::create(&path, merged).unwrap();
+        let mut steps = 1;
+        while !writer.step(64).unwrap() {
+            steps += 1;
+        }
+        assert!(steps > 2);
+        assert!(segment_path(&path, 2).exists() && pets.segments[0].persisted.get());
+        drop(writer);
+        assert!(segment_path(&path, 2).exists());
+        loaded.save(&path).unwrap();
+        assert!(!segment_path(&path, 0).exists());
+        assert_eq!(loaded.collections[0].get("3"), Some(&br#"{"al":"cat"}"#[..]));
+        let reloaded = MemoryBuf::from_file_mmap(&path).unwrap();
+        assert_eq!(reloaded.collections[0].segments[0].docs.internal_id("3"), Some(2));
+
+        fs::remove_file(segment_path(&path, 2)).unwrap();
+        let missing = MemoryBuf::from_file(&path).err().unwrap().to_string();
+        assert!(missing.starts_with("corrupt database: missing segment file"), "{}", missing);
+        remove(&path);
+    }
+
+    #[test]
+    fn test_update_and_delete() {
+        let path = temp_path("delete");
+        let mut db = build();
+        let pets = &mut db.collections[0];
+        assert!(pets.update("2", br#"{"al":"dog"}"#, &[("al", FieldValue::Text("dog", &Analyzer::STANDARD))]));
+        assert!(pets.segments[0].deletes.is_deleted(1));
+        assert_eq!(pets.get("2"), Some(&br#"{"al":"dog"}"#[..]));
+        // Replacing and deleting a document that is not flushed yet.
+        assert!(pets.update("2", b"{}", &[]));
+        assert!(pets.delete("3"));
+        assert!(!pets.delete("3"));
+        assert!(!pets.delete("4"));
+        assert_eq!(pets.doc_count(), 2);
+        pets.flush(&mut db.next_segment_id);
+        assert_eq!(pets.segments[2].deletes.iter().collect::<Vec<_>>(), vec![0]);
+        assert!(pets.delete("1"));
+
+        db.save(&path).unwrap();
+        let mut loaded = MemoryBuf::from_file(&path).unwrap();
+        let pets = &mut loaded.collections[0];
+        assert_eq!(pets.get("1"), None);
+        assert_eq!(pets.get("2"), Some(&b"{}"[..]));
+        assert_eq!(pets.segments[0].deletes.count(), 2);
+
+        // Merging drops the deleted documents, deletes during the merge are carried over.
+        let policy = TieredMergePolicy {
+            segments_per_tier: 3,
+            min_segment_size: 10,
+        };
+        assert!(pets.merge_step(&policy, 1, &mut loaded.next_segment_id).is_none());
+        assert!(pets.delete("2"));
+        while pets.merge_step(&policy, 1, &mut loaded.next_segment_id).is_none() {}
+        assert_eq!(pets.segments.len(), 1);
+        assert_eq!((pets.segments[0].len(), pets.segments[0].live_count()), (1, 0));
+        assert!(pets.segments[0].indexes["al"].lookup("fox").is_none());
+        remove(&path);
+    }
+
+    #[test]
+    fn test_load_errors() {
+        let mut bytes = BytesMut::new();
+        write_manifest(build().collections.iter(), &mut bytes);
+        assert_eq!(manifest_error(BytesMut::from(&b"{\"name\":1}"[..])), "not a Finne database file");
+
+        let mut wrong_version = bytes.clone();
+        wrong_version[11] = 9;
+        assert_eq!(manifest_error(wrong_version), "unsupported database format version 9, expected 5");
+
+        let mut bad_table = bytes.clone();
+        bad_table[HEADER_LEN + 1] ^= 0xff;
+        assert_eq!(manifest_error(bad_table), "corrupt database: section table checksum mismatch");
+        let mut bad_count = bytes.clone();
+        bad_count[12..16].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert_eq!(manifest_error(bad_count), "corrupt database: more sections than the section table holds");
+
+        let mut bad_section = bytes.clone();
+        let last = bad_section.len() - 1;
+        bad_section[last] ^= 0xff;
+        assert_eq!(manifest_error(bad_section), "corrupt database: section 'empty/definition' checksum mismatch");
+
+        let truncated = bytes.clone().split_to(bytes.len() - 1);
+        assert_eq!(manifest_error(truncated), "corrupt database: section 'empty/definition' is out of bounds");
+
+        let mut segment = BytesMut::new();
+        write_segment(&build().collections[0].segments[0], &mut segment);
+        let last = segment.len() - 1;
+        segment[last] ^= 0xff;
+        assert!(read_segment(0, segment.freeze()).is_err());
+    }
 }
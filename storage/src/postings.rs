@@ -0,0 +1,452 @@
+use bytes::{BufMut, BytesMut};
+
+// Posting list layout of a term, the documents in doc id order:
+//
+// header:    length of the doc id section and of the frequency section (varint each), the
+//            highest frequency and the shortest field length of the documents (varint each) and
+//            the length of the skip section when there is at least one full block
+// skips:     for each full block the delta of its last doc id to the previous block's (varint)
+//            and the length in bytes of its positions (varint)
+// doc ids:   delta to the previous doc id, the first to 0, in blocks of BLOCK_LEN
+// freqs:     term frequency minus one, in the same blocks
+// positions: for each document its word positions, each a delta to the previous one (varints)
+//
+// A full block is frame-of-reference bit packed: the bit width (u8) of its largest value followed
+// by BLOCK_LEN values of that width, so blocks of small deltas take a few bits per document.
+// The last, partial block is stored as varints. Iteration decodes one block at a time and
+// `advance_to` steps over the blocks before its target with the skip entries, a skipped block's
+// doc ids and freqs are passed by their bit width alone and are never decoded. The highest
+// frequency and shortest field bound the relevance of every document of the term, so top-k
+// search skips the documents of terms that cannot reach the best hits.
+pub const BLOCK_LEN: usize = 128;
+
+// Appends the posting list of one term, `positions` holds the positions of all documents
+// after each other. `min_field_length` is the number of tokens of the shortest field.
+pub fn write_postings(doc_ids: &[u32], freqs: &[u32], positions: &[u32], min_field_length: u32, output: &mut BytesMut) {
+    let mut skip_section = BytesMut::new();
+    let mut doc_section = BytesMut::new();
+    let mut freq_section = BytesMut::new();
+    let mut position_section = BytesMut::new();
+    let mut deltas = [0; BLOCK_LEN];
+    let mut last = 0;
+    let mut offset = 0;
+    for (block, block_freqs) in doc_ids.chunks(BLOCK_LEN).zip(freqs.chunks(BLOCK_LEN)) {
+        let previous_last = last;
+        for (delta, doc_id) in deltas.iter_mut().zip(block) {
+            *delta = doc_id - last;
+            last = *doc_id;
+        }
+        let mut block_freqs_minus_one = [0; BLOCK_LEN];
+        for (freq_minus_one, freq) in block_freqs_minus_one.iter_mut().zip(block_freqs) {
+            *freq_minus_one = freq - 1;
+        }
+        write_block(&deltas[..block.len()], &mut doc_section);
+        write_block(&block_freqs_minus_one[..block.len()], &mut freq_section);
+        let positions_start = position_section.len();
+        for freq in block_freqs {
+            let mut last_position = 0;
+            for position in &positions[offset..offset + *freq as usize] {
+                put_varint(&mut position_section, position - last_position);
+                last_position = *position;
+            }
+            offset += *freq as usize;
+        }
+        if block.len() == BLOCK_LEN {
+            put_varint(&mut skip_section, last - previous_last);
+            put_varint(&mut skip_section, (position_section.len() - positions_start) as u32);
+        }
+    }
+    put_varint(output, doc_section.len() as u32);
+    put_varint(output, freq_section.len() as u32);
+    put_varint(output, freqs.iter().copied().max().unwrap_or(0));
+    put_varint(output, min_field_length);
+    if doc_ids.len() >= BLOCK_LEN {
+        put_varint(output, skip_section.len() as u32);
+        output.put_slice(&skip_section);
+    }
+    output.put_slice(&doc_section);
+    output.put_slice(&freq_section);
+    output.put_slice(&position_section);
+}
+
+#[inline]
+fn write_block(values: &[u32], output: &mut BytesMut) {
+    if values.len() < BLOCK_LEN {
+        for value in values {
+            put_varint(output, *value);
+        }
+        return;
+    }
+    let width = 32 - values.iter().fold(0, |acc, v| acc | v).leading_zeros();
+    output.put_u8(width as u8);
+    let mut acc: u64 = 0;
+    let mut bits = 0;
+    for value in values {
+        acc |= (*value as u64) << bits;
+        bits += width;
+        while bits >= 8 {
+            output.put_u8(acc as u8);
+            acc >>= 8;
+            bits -= 8;
+        }
+    }
+    if bits > 0 {
+        output.put_u8(acc as u8);
+    }
+}
+
+// Decodes a block of `len` values into `output` and returns the rest of the data.
+#[inline]
+fn read_block<'a>(mut data: &'a [u8], len: usize, output: &mut [u32; BLOCK_LEN]) -> &'a [u8] {
+    if len < BLOCK_LEN {
+        for value in output[..len].iter_mut() {
+            *value = get_varint(&mut data);
+        }
+        return data;
+    }
+    let (width, packed) = match data.split_first() {
+        Some((width, packed)) => ((*width as u32).min(32), packed),
+        None => return data,
+    };
+    let byte_len = (BLOCK_LEN * width as usize).div_ceil(8).min(packed.len());
+    let (packed, rest) = packed.split_at(byte_len);
+    let mask = (1u64 << width) - 1;
+    let mut acc: u64 = 0;
+    let mut bits = 0;
+    let mut bytes = packed.iter();
+    for value in output.iter_mut() {
+        while bits < width {
+            acc |= (*bytes.next().unwrap_or(&0) as u64) << bits;
+            bits += 8;
+        }
+        *value = (acc & mask) as u32;
+        acc >>= width;
+        bits -= width;
+    }
+    return rest;
+}
+
+// Returns the data after a full block without decoding it.
+#[inline]
+fn skip_block(data: &[u8]) -> &[u8] {
+    let width = data.first().map_or(0, |width| (*width as usize).min(32));
+    return &data[(1 + (BLOCK_LEN * width).div_ceil(8)).min(data.len())..];
+}
+
+// Splits off the first `len` bytes, or all if there are fewer.
+#[inline]
+fn take<'a>(data: &mut &'a [u8], len: usize) -> &'a [u8] {
+    let (taken, rest) = data.split_at(len.min(data.len()));
+    *data = rest;
+    return taken;
+}
+
+#[inline]
+pub fn put_varint(output: &mut BytesMut, mut value: u32) {
+    while value >= 0x80 {
+        output.put_u8(value as u8 | 0x80);
+        value >>= 7;
+    }
+    output.put_u8(value as u8);
+}
+
+// Reads a varint and advances the data past it, stops at the end of the data.
+#[inline]
+pub fn get_varint(data: &mut &[u8]) -> u32 {
+    if let Some((byte, rest)) = data.split_first() {
+        if byte & 0x80 == 0 {
+            *data = rest;
+            return *byte as u32;
+        }
+    }
+    let mut value: u32 = 0;
+    let mut shift = 0;
+    while let Some((byte, rest)) = data.split_first() {
+        *data = rest;
+        value |= ((byte & 0x7f) as u32).wrapping_shl(shift);
+        if byte & 0x80 == 0 {
+            break;
+        }
+        shift += 7;
+    }
+    return value;
+}
+
+// Length in bytes of the first `count` varints.
+#[inline]
+fn varints_len(data: &[u8], count: u32) -> usize {
+    if count == 1 && data.first().is_some_and(|byte| byte & 0x80 == 0) {
+        return 1;
+    }
+    let mut remaining = count;
+    for (i, byte) in data.iter().enumerate() {
+        if remaining == 0 {
+            return i;
+        }
+        if byte & 0x80 == 0 {
+            remaining -= 1;
+        }
+    }
+    return data.len();
+}
+
+pub struct Postings<'a> {
+    skip_data: &'a [u8],
+    docs_data: &'a [u8],
+    freqs_data: &'a [u8],
+    positions_data: &'a [u8],
+    // The positions after those of the decoded block.
+    block_positions_end: &'a [u8],
+    doc_count: u32,
+    max_frequency: u32,
+    min_field_length: u32,
+    // Documents not decoded into a block yet.
+    remaining: u32,
+    doc_ids: [u32; BLOCK_LEN],
+    freqs: [u32; BLOCK_LEN],
+    block_len: usize,
+    block_pos: usize,
+    last_doc_id: u32,
+}
+
+impl<'a> Postings<'a> {
+    pub fn new(mut data: &'a [u8], doc_count: u32) -> Postings<'a> {
+        let docs_len = get_varint(&mut data) as usize;
+        let freqs_len = get_varint(&mut data) as usize;
+        let max_frequency = get_varint(&mut data);
+        let min_field_length = get_varint(&mut data);
+        let skips_len = if doc_count as usize >= BLOCK_LEN { get_varint(&mut data) as usize } else { 0 };
+        let skip_data = take(&mut data, skips_len);
+        let docs_data = take(&mut data, docs_len);
+        let freqs_data = take(&mut data, freqs_len);
+        return Postings {
+            skip_data,
+            docs_data,
+            freqs_data,
+            positions_data: data,
+            block_positions_end: data,
+            doc_count,
+            max_frequency,
+            min_field_length,
+            remaining: doc_count,
+            doc_ids: [0; BLOCK_LEN],
+            freqs: [0; BLOCK_LEN],
+            block_len: 0,
+            block_pos: 0,
+            last_doc_id: 0,
+        };
+    }
+
+    #[inline]
+    pub fn doc_count(&self) -> u32 {
+        return self.doc_count;
+    }
+
+    // The highest term frequency of the documents.
+    #[inline]
+    pub fn max_frequency(&self) -> u32 {
+        return self.max_frequency;
+    }
+
+    // The number of tokens of the shortest field of the documents.
+    #[inline]
+    pub fn min_field_length(&self) -> u32 {
+        return self.min_field_length;
+    }
+
+    // Moves to the first posting not returned yet with a doc id of at least `target`. Blocks
+    // that end before the target are skipped, within a block the doc id is found by galloping.
+    pub fn advance_to(&mut self, target: u32) -> Option<Posting<'a>> {
+        if self.block_pos == self.block_len || self.doc_ids[self.block_len - 1] < target {
+            self.block_pos = self.block_len;
+            self.positions_data = self.block_positions_end;
+            while self.remaining as usize >= BLOCK_LEN {
+                let mut skip = self.skip_data;
+                let last_doc_id = self.last_doc_id.wrapping_add(get_varint(&mut skip));
+                if last_doc_id >= target {
+                    break;
+                }
+                let positions_len = get_varint(&mut skip) as usize;
+                self.skip_data = skip;
+                self.docs_data = skip_block(self.docs_data);
+                self.freqs_data = skip_block(self.freqs_data);
+                take(&mut self.positions_data, positions_len);
+                self.last_doc_id = last_doc_id;
+                self.remaining -= BLOCK_LEN as u32;
+            }
+            if self.remaining == 0 {
+                return None;
+            }
+            self.decode_block();
+        }
+        let block = &self.doc_ids[self.block_pos..self.block_len];
+        let (mut low, mut high) = (0, 1);
+        while high < block.len() && block[high] < target {
+            low = high;
+            high *= 2;
+        }
+        let skipped = low + block[low..(high + 1).min(block.len())].partition_point(|doc_id| *doc_id < target);
+        let skipped_positions = self.freqs[self.block_pos..self.block_pos + skipped]
+            .iter()
+            .fold(0u32, |sum, freq| sum.saturating_add(*freq));
+        let skipped_len = varints_len(self.positions_data, skipped_positions);
+        take(&mut self.positions_data, skipped_len);
+        self.block_pos += skipped;
+        return self.next();
+    }
+
+    fn decode_block(&mut self) {
+        let len = (self.remaining as usize).min(BLOCK_LEN);
+        let mut positions = self.positions_data;
+        if len == BLOCK_LEN {
+            // The doc ids come from the block itself, only the positions length is needed.
+            get_varint(&mut self.skip_data);
+            take(&mut positions, get_varint(&mut self.skip_data) as usize);
+        } else {
+            positions = &positions[positions.len()..];
+        }
+        self.block_positions_end = positions;
+        self.docs_data = read_block(self.docs_data, len, &mut self.doc_ids);
+        self.freqs_data = read_block(self.freqs_data, len, &mut self.freqs);
+        for i in 0..len {
+            self.last_doc_id = self.last_doc_id.wrapping_add(self.doc_ids[i]);
+            self.doc_ids[i] = self.last_doc_id;
+            self.freqs[i] = self.freqs[i].wrapping_add(1);
+        }
+        self.remaining -= len as u32;
+        self.block_len = len;
+        self.block_pos = 0;
+    }
+}
+
+impl<'a> Iterator for Postings<'a> {
+    type Item = Posting<'a>;
+
+    #[inline]
+    fn next(&mut self) -> Option<Posting<'a>> {
+        if self.block_pos == self.block_len {
+            if self.remaining == 0 {
+                return None;
+            }
+            self.decode_block();
+        }
+        let doc_id = self.doc_ids[self.block_pos];
+        let frequency = self.freqs[self.block_pos];
+        self.block_pos += 1;
+        let positions_len = varints_len(self.positions_data, frequency);
+        let positions = take(&mut self.positions_data, positions_len);
+        return Some(Posting {
+            doc_id,
+            frequency,
+            positions,
+        });
+    }
+}
+
+pub struct Posting<'a> {
+    pub doc_id: u32,
+    pub frequency: u32,
+    positions: &'a [u8],
+}
+
+impl<'a> Posting<'a> {
+    #[inline]
+    pub fn positions(&self) -> Positions<'a> {
+        return Positions {
+            data: self.positions,
+            remaining: self.frequency,
+            last: 0,
+        };
+    }
+}
+
+pub struct Positions<'a> {
+    data: &'a [u8],
+    remaining: u32,
+    last: u32,
+}
+
+impl ExactSizeIterator for Positions<'_> {}
+
+impl Iterator for Positions<'_> {
+    type Item = u32;
+
+    #[inline]
+    fn next(&mut self) -> Option<u32> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        self.last = self.last.wrapping_add(get_varint(&mut self.data));
+        return Some(self.last);
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        return (self.remaining as usize, Some(self.remaining as usize));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_varint() {
+        let mut bytes = BytesMut::new();
+        for value in [0, 127, 128, 300, u32::MAX] {
+            put_varint(&mut bytes, value);
+        }
+        assert_eq!(bytes.len(), 1 + 1 + 2 + 2 + 5);
+        let mut data = &bytes[..];
+        let values: Vec<u32> = (0..5).map(|_| get_varint(&mut data)).collect();
+        assert_eq!(values, vec![0, 127, 128, 300, u32::MAX]);
+        assert_eq!(varints_len(&bytes, 3), 4);
+        assert!(data.is_empty());
+    }
+
+    #[test]
+    fn test_blocks_round_trip() {
+        // Two full bit packed blocks, one with a large gap, and a partial varint block.
+        let doc_ids: Vec<u32> = (0..300).map(|i| if i < 200 { i * 3 } else { 1_000_000 + i }).collect();
+        let freqs: Vec<u32> = (0..300).map(|i| i % 4 + 1).collect();
+        let mut positions = Vec::new();
+        for (i, freq) in freqs.iter().enumerate() {
+            positions.extend((0..*freq).map(|p| i as u32 + p * 7));
+        }
+        let mut bytes = BytesMut::new();
+        write_postings(&doc_ids, &freqs, &positions, 5, &mut bytes);
+        assert!(bytes.len() < doc_ids.len() * 8 + positions.len() * 4);
+
+        let postings = Postings::new(&bytes, 300);
+        assert_eq!((postings.max_frequency(), postings.min_field_length()), (4, 5));
+        let mut decoded_positions = Vec::new();
+        let mut decoded_ids = Vec::new();
+        for posting in Postings::new(&bytes, 300) {
+            assert_eq!(posting.positions().len(), posting.frequency as usize);
+            decoded_positions.extend(posting.positions());
+            decoded_ids.push((posting.doc_id, posting.frequency));
+        }
+        let expected: Vec<(u32, u32)> = doc_ids.iter().copied().zip(freqs.iter().copied()).collect();
+        assert_eq!(decoded_ids, expected);
+        assert_eq!(decoded_positions, positions);
+
+        // Skipping to each target lands on the same posting as walking the list.
+        let mut postings = Postings::new(&bytes, 300);
+        for target in [0, 1, 4, 200, 380, 500, 1_000_000, 1_000_250, 1_000_299] {
+            let i = doc_ids.partition_point(|doc_id| *doc_id < target);
+            let posting = postings.advance_to(target).unwrap();
+            assert_eq!((posting.doc_id, posting.frequency), expected[i]);
+            let offset: u32 = freqs[..i].iter().sum();
+            let expected_positions = &positions[offset as usize..(offset + freqs[i]) as usize];
+            assert_eq!(posting.positions().collect::<Vec<_>>(), expected_positions);
+        }
+        assert!(postings.advance_to(1_000_300).is_none());
+        assert!(Postings::new(&bytes, 300).advance_to(u32::MAX).is_none());
+
+        let mut bytes = BytesMut::new();
+        write_postings(&[u32::MAX - 1], &[1], &[u32::MAX], u32::MAX, &mut bytes);
+        let posting = Postings::new(&bytes, 1).next().unwrap();
+        assert_eq!((posting.doc_id, posting.positions().collect::<Vec<_>>()), (u32::MAX - 1, vec![u32::MAX]));
+    }
+}
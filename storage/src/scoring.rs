@@ -0,0 +1,105 @@
+use crate::indexes::Index;
+
+// Okapi BM25, the relevance of a document for a term grows with the term's frequency in the
+// document up to a limit set by k1, and shrinks for documents with a longer field than the
+// average by a share of b. Rare terms weigh more through the inverse document frequency.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Bm25 {
+    pub k1: f32,
+    pub b: f32,
+}
+
+impl Default for Bm25 {
+    #[inline]
+    fn default() -> Self {
+        return Bm25 { k1: 1.2, b: 0.75 };
+    }
+}
+
+impl Bm25 {
+    // Inverse document frequency of a term in `doc_freq` of the `doc_count` documents with
+    // the field.
+    #[inline]
+    pub fn idf(doc_count: u64, doc_freq: u64) -> f32 {
+        let doc_freq = doc_freq.min(doc_count) as f64;
+        return (1.0 + (doc_count as f64 - doc_freq + 0.5) / (doc_freq + 0.5)).ln() as f32;
+    }
+
+    // Scores a term of one field in one segment. The weight is the term's idf times its boost,
+    // the average field length is taken over the whole collection so the scores of segments
+    // compare.
+    #[inline]
+    pub fn scorer(self, weight: f32, avg_length: f32, norms: &Index) -> Bm25Scorer<'_> {
+        return Bm25Scorer {
+            weight,
+            k1: self.k1,
+            b: self.b,
+            avg_length: if avg_length > 0.0 { avg_length } else { 1.0 },
+            norms,
+        };
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Bm25Scorer<'a> {
+    weight: f32,
+    k1: f32,
+    b: f32,
+    avg_length: f32,
+    norms: &'a Index,
+}
+
+impl Bm25Scorer<'_> {
+    #[inline]
+    pub fn score(&self, doc_id: u32, frequency: u32) -> f32 {
+        return self.score_length(frequency, self.norms.field_length(doc_id));
+    }
+
+    // The score grows with the frequency and shrinks with the field length, so the highest
+    // frequency and shortest field of a term's documents bound their scores. A negative weight
+    // only lowers scores, its bound is 0.
+    #[inline]
+    pub fn max_score(&self, max_frequency: u32, min_field_length: u32) -> f32 {
+        return self.score_length(max_frequency, min_field_length).max(0.0);
+    }
+
+    #[inline]
+    fn score_length(&self, frequency: u32, length: u32) -> f32 {
+        let norm = self.k1 * (1.0 - self.b + self.b * length as f32 / self.avg_length);
+        let frequency = frequency as f32;
+        return self.weight * frequency * (self.k1 + 1.0) / (frequency + norm);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::analysis::Analyzer;
+    use crate::indexes::ReverseIndexBuilder;
+
+    #[test]
+    fn test_bm25() {
+        assert!(Bm25::idf(100, 1) > Bm25::idf(100, 50));
+        assert!(Bm25::idf(100, 100) > 0.0);
+        assert_eq!(Bm25::idf(10, 20), Bm25::idf(10, 10));
+
+        let mut builder = ReverseIndexBuilder::default();
+        builder.add_document(0, "fox", &Analyzer::STANDARD);
+        builder.add_document(1, "fox fox", &Analyzer::STANDARD);
+        builder.add_document(2, "fox and a lazy dog", &Analyzer::STANDARD);
+        let index = builder.build("body".to_owned());
+        let stats = index.field_stats();
+        let avg_length = stats.total_length as f32 / stats.doc_count as f32;
+        let scorer = Bm25::default().scorer(2.0, avg_length, &index);
+        // More occurrences score higher, a longer field lower.
+        assert!(scorer.score(1, 2) > scorer.score(0, 1));
+        assert!(scorer.score(0, 1) > scorer.score(2, 1));
+        let max_score = scorer.max_score(2, 1);
+        assert!((0..3).all(|doc_id| scorer.score(doc_id, 2) <= max_score));
+        assert_eq!(Bm25::default().scorer(-1.0, avg_length, &index).max_score(2, 1), 0.0);
+        // Without length normalization only the frequency counts, saturating at k1 + 1.
+        let scorer = Bm25 { k1: 1.2, b: 0.0 }.scorer(1.0, avg_length, &index);
+        assert_eq!(scorer.score(0, 1), scorer.score(2, 1));
+        assert!(scorer.score(1, 1000) < 2.2);
+    }
+}
@@ -0,0 +1,575 @@
+use std::cell::Cell;
+use std::collections::{BTreeMap, HashMap};
+use std::rc::Rc;
+
+use bytes::{BufMut, BytesMut};
+
+use crate::analysis::Analyzer;
+use crate::docbuf::DocBuf;
+use crate::indexes::{
+    GeoIndexBuilder, Index, IndexType, Number, RangeIndexBuilder, ReverseIndexBuilder, ReverseIndexWriter,
+};
+
+// A value of an indexed field, the variant decides the type of the field's index. A field must
+// have the same type of value in every document of a collection.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FieldValue<'a> {
+    // The text with the analyzer that turns it into terms.
+    Text(&'a str, &'a Analyzer),
+    Number(Number),
+    // Latitude and longitude in degrees.
+    GeoPoint(f64, f64),
+}
+
+// Documents and the indexes of their fields, immutable once built except for deletes. Doc ids
+// are local to the segment, a search runs on every segment of a collection and skips the
+// deleted documents.
+pub struct Segment {
+    pub id: u64,
+    pub docs: DocBuf,
+    pub indexes: HashMap<String, Index>,
+    pub deletes: Tombstones,
+    // Set once the segment is written to its own file, see save_collections.
+    pub(crate) persisted: Cell<bool>,
+}
+
+impl Segment {
+    #[inline]
+    pub fn len(&self) -> u32 {
+        return self.docs.len();
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        return self.docs.is_empty();
+    }
+
+    // Number of documents that are not deleted.
+    #[inline]
+    pub fn live_count(&self) -> u32 {
+        return self.docs.len() - self.deletes.count();
+    }
+
+    // The doc id of a document that is not deleted.
+    #[inline]
+    pub fn live_id(&self, external_id: &str) -> Option<u32> {
+        return self.docs.internal_id(external_id).filter(|doc_id| !self.deletes.is_deleted(*doc_id));
+    }
+}
+
+// Deleted documents of a segment, one bit per doc id. Bits are only ever set, through a shared
+// reference, so a segment can take deletes while a merge reads it. A merge drops the deleted
+// documents.
+#[derive(Default)]
+pub struct Tombstones {
+    words: Vec<Cell<u64>>,
+    count: Cell<u32>,
+}
+
+impl Tombstones {
+    #[inline]
+    pub fn new(len: u32) -> Tombstones {
+        let mut tombstones = Tombstones::default();
+        tombstones.grow(len);
+        return tombstones;
+    }
+
+    #[inline]
+    fn grow(&mut self, len: u32) {
+        self.words.resize_with(len.div_ceil(64) as usize, Cell::default);
+    }
+
+    // Marks the document as deleted, false if it already was or is out of range.
+    #[inline]
+    pub fn delete(&self, doc_id: u32) -> bool {
+        let word = match self.words.get(doc_id as usize / 64) {
+            Some(word) => word,
+            None => return false,
+        };
+        let bit = 1 << (doc_id % 64);
+        if word.get() & bit != 0 {
+            return false;
+        }
+        word.set(word.get() | bit);
+        self.count.set(self.count.get() + 1);
+        return true;
+    }
+
+    #[inline]
+    pub fn is_deleted(&self, doc_id: u32) -> bool {
+        return match self.words.get(doc_id as usize / 64) {
+            Some(word) => word.get() & (1 << (doc_id % 64)) != 0,
+            None => false,
+        };
+    }
+
+    #[inline]
+    pub fn count(&self) -> u32 {
+        return self.count.get();
+    }
+
+    // The deleted doc ids in ascending order.
+    pub fn iter(&self) -> impl Iterator<Item = u32> + '_ {
+        return self.words.iter().enumerate().flat_map(|(i, word)| {
+            let mut bits = word.get();
+            std::iter::from_fn(move || {
+                if bits == 0 {
+                    return None;
+                }
+                let bit = bits.trailing_zeros();
+                bits &= bits - 1;
+                return Some(i as u32 * 64 + bit);
+            })
+        });
+    }
+}
+
+// The in-memory segment new documents are added to until it is flushed into a Segment.
+#[derive(Default)]
+pub struct SegmentWriter {
+    docs: DocBuf,
+    deletes: Tombstones,
+    reverse: HashMap<String, ReverseIndexBuilder>,
+    range: HashMap<String, RangeIndexBuilder>,
+    geo: HashMap<String, GeoIndexBuilder>,
+}
+
+impl SegmentWriter {
+    // Stores and indexes the document, None if the external id is taken.
+    pub fn add_document(&mut self, external_id: &str, doc: &[u8], fields: &[(&str, FieldValue<'_>)]) -> Option<u32> {
+        let doc_id = self.docs.append(external_id, doc)?;
+        self.deletes.grow(doc_id + 1);
+        for (field, value) in fields {
+            match value {
+                FieldValue::Text(text, analyzer) => builder(&mut self.reverse, field).add_document(doc_id, text, analyzer),
+                FieldValue::Number(number) => builder(&mut self.range, field).add_document(doc_id, *number),
+                FieldValue::GeoPoint(lat, lon) => builder(&mut self.geo, field).add_document(doc_id, *lat, *lon),
+            }
+        }
+        return Some(doc_id);
+    }
+
+    #[inline]
+    pub fn get(&self, external_id: &str) -> Option<&[u8]> {
+        return self.docs.get(self.docs.internal_id(external_id)?);
+    }
+
+    // Deletes the document, its id can then be added again. False if there is no such document.
+    pub fn delete(&mut self, external_id: &str) -> bool {
+        return match self.docs.remove_id(external_id) {
+            Some(doc_id) => self.deletes.delete(doc_id),
+            None => false,
+        };
+    }
+
+    #[inline]
+    pub fn len(&self) -> u32 {
+        return self.docs.len();
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        return self.docs.is_empty();
+    }
+
+    #[inline]
+    pub fn live_count(&self) -> u32 {
+        return self.docs.len() - self.deletes.count();
+    }
+
+    pub fn finish(self, id: u64) -> Segment {
+        let mut indexes = HashMap::with_capacity(self.reverse.len() + self.range.len() + self.geo.len());
+        for (field, builder) in self.reverse {
+            indexes.insert(field.clone(), builder.build(field));
+        }
+        for (field, builder) in self.range {
+            indexes.insert(field.clone(), builder.build(field));
+        }
+        for (field, builder) in self.geo {
+            indexes.insert(field.clone(), builder.build(field));
+        }
+        return Segment {
+            id,
+            docs: self.docs,
+            indexes,
+            deletes: self.deletes,
+            persisted: Cell::new(false),
+        };
+    }
+}
+
+#[inline]
+fn builder<'a, T: Default>(builders: &'a mut HashMap<String, T>, field: &str) -> &'a mut T {
+    if !builders.contains_key(field) {
+        builders.insert(field.to_owned(), T::default());
+    }
+    return builders.get_mut(field).unwrap();
+}
+
+const DROPPED: u32 = u32::MAX;
+
+// Groups segments into tiers by size, tier n holds segments of up to
+// min_segment_size * segments_per_tier^n documents. Once a tier holds segments_per_tier
+// segments they are merged into one segment of a higher tier, so a document is merged about
+// once per tier and the number of segments grows with the log of the collection size.
+#[derive(Debug, Clone, Copy)]
+pub struct TieredMergePolicy {
+    pub segments_per_tier: usize,
+    pub min_segment_size: u32,
+}
+
+impl Default for TieredMergePolicy {
+    #[inline]
+    fn default() -> Self {
+        return TieredMergePolicy {
+            segments_per_tier: 8,
+            min_segment_size: 1000,
+        };
+    }
+}
+
+impl TieredMergePolicy {
+    // Picks the segments to merge next from the segment sizes, in segment order.
+    pub fn find_merge(&self, sizes: &[u32]) -> Option<Vec<usize>> {
+        let segments_per_tier = self.segments_per_tier.max(2);
+        let mut tiers: BTreeMap<u32, Vec<usize>> = BTreeMap::new();
+        for (i, size) in sizes.iter().enumerate() {
+            tiers.entry(self.tier(*size)).or_default().push(i);
+        }
+        // Small tiers fill up fastest and are the cheapest to merge.
+        for (_, mut segments) in tiers {
+            if segments.len() >= segments_per_tier {
+                segments.sort_by_key(|i| sizes[*i]);
+                segments.truncate(segments_per_tier);
+                segments.sort_unstable();
+                return Some(segments);
+            }
+        }
+        return None;
+    }
+
+    #[inline]
+    fn tier(&self, size: u32) -> u32 {
+        let mut tier = 0;
+        let mut limit = self.min_segment_size.max(1) as u64;
+        while size as u64 > limit {
+            tier += 1;
+            limit *= self.segments_per_tier.max(2) as u64;
+        }
+        return tier;
+    }
+}
+
+// Merges segments into one in small steps, so the event loop can run it between requests.
+// The documents are copied first, then the indexes one field at a time. Documents deleted when
+// the merge starts are dropped, the others are numbered in input order. Documents deleted
+// while the merge runs are deleted in the merged segment when it is finished.
+pub struct MergeTask {
+    inputs: Vec<Rc<Segment>>,
+    // The merged doc id of every input doc id, DROPPED for deleted documents.
+    doc_maps: Vec<Vec<u32>>,
+    docs: DocBuf,
+    fields: Vec<(String, IndexType)>,
+    indexes: HashMap<String, Index>,
+    // The input and doc id to copy next, then the field to merge next.
+    input: usize,
+    doc_id: u32,
+    field: usize,
+    // Next term or record of every input in the current field.
+    cursors: Vec<usize>,
+    // The term being merged when a step ran out of budget inside it, with the input and the
+    // input doc id its postings continue at.
+    term: Vec<u8>,
+    in_term: bool,
+    term_input: usize,
+    term_doc_id: u32,
+    reverse: ReverseIndexWriter,
+    records: BytesMut,
+}
+
+impl MergeTask {
+    pub fn new(inputs: Vec<Rc<Segment>>) -> MergeTask {
+        let mut doc_maps = Vec::with_capacity(inputs.len());
+        let mut next_doc_id = 0;
+        let mut fields: BTreeMap<&str, IndexType> = BTreeMap::new();
+        for input in &inputs {
+            let mut doc_map = Vec::with_capacity(input.len() as usize);
+            for doc_id in 0..input.len() {
+                if input.deletes.is_deleted(doc_id) {
+                    doc_map.push(DROPPED);
+                } else {
+                    doc_map.push(next_doc_id);
+                    next_doc_id += 1;
+                }
+            }
+            doc_maps.push(doc_map);
+            for (name, index) in &input.indexes {
+                let index_type = *fields.entry(name).or_insert(index.index_type());
+                if index_type != index.index_type() {
+                    tracing::warn!("Field {} of segment {} has a different index type, skipping", name, input.id);
+                }
+            }
+        }
+        let fields = fields.into_iter().map(|(name, index_type)| (name.to_owned(), index_type)).collect();
+        return MergeTask {
+            cursors: vec![0; inputs.len()],
+            inputs,
+            doc_maps,
+            docs: DocBuf::default(),
+            fields,
+            indexes: HashMap::new(),
+            input: 0,
+            doc_id: 0,
+            field: 0,
+            term: Vec::new(),
+            in_term: false,
+            term_input: 0,
+            term_doc_id: 0,
+            reverse: ReverseIndexWriter::default(),
+            records: BytesMut::new(),
+        };
+    }
+
+    #[inline]
+    pub fn inputs(&self) -> &[Rc<Segment>] {
+        return &self.inputs;
+    }
+
+    // Does about `budget` units of work, a document, posting or record each. Returns true
+    // once the merge is done.
+    pub fn step(&mut self, budget: usize) -> bool {
+        let mut work = 0;
+        while work < budget {
+            if self.input < self.inputs.len() {
+                let input = &self.inputs[self.input];
+                if self.doc_id >= input.len() {
+                    self.input += 1;
+                    self.doc_id = 0;
+                    continue;
+                }
+                if self.doc_maps[self.input][self.doc_id as usize] != DROPPED {
+                    let external_id = input.docs.external_id(self.doc_id).unwrap();
+                    let doc = input.docs.get(self.doc_id).unwrap();
+                    // Only one document of an external id is not deleted.
+                    self.docs.append(external_id, doc);
+                }
+                self.doc_id += 1;
+                work += 1;
+            } else if self.field < self.fields.len() {
+                let merged = match self.fields[self.field].1 {
+                    IndexType::Reverse => self.merge_term(budget - work),
+                    IndexType::Range | IndexType::Geospatial => self.merge_record(),
+                };
+                match merged {
+                    Some(merged) => work += merged,
+                    None => self.finish_field(),
+                }
+            } else {
+                return true;
+            }
+        }
+        return self.input >= self.inputs.len() && self.field >= self.fields.len();
+    }
+
+    // Builds the merged segment, the merge must be done.
+    pub fn finish(self, id: u64) -> Segment {
+        let deletes = Tombstones::new(self.docs.len());
+        for (input, doc_map) in self.inputs.iter().zip(&self.doc_maps) {
+            for doc_id in input.deletes.iter() {
+                if doc_map[doc_id as usize] != DROPPED {
+                    deletes.delete(doc_map[doc_id as usize]);
+                }
+            }
+        }
+        return Segment {
+            id,
+            docs: self.docs,
+            indexes: self.indexes,
+            deletes,
+            persisted: Cell::new(false),
+        };
+    }
+
+    // Writes the smallest term of the inputs with the postings of all inputs, about `budget`
+    // postings at a time so a frequent term takes several steps. None when every input is done
+    // with the current field.
+    fn merge_term(&mut self, budget: usize) -> Option<usize> {
+        let (field, index_type) = &self.fields[self.field];
+        let mut work = 0;
+        if !self.in_term {
+            let mut next_term: Option<&[u8]> = None;
+            for (i, input) in self.inputs.iter().enumerate() {
+                if let Some(index) = field_index(input, field, *index_type) {
+                    if self.cursors[i] < index.term_count() {
+                        let (term, _) = index.term_at(self.cursors[i]);
+                        if next_term.is_none_or(|next_term| term < next_term) {
+                            next_term = Some(term);
+                        }
+                    }
+                }
+            }
+            self.term.clear();
+            self.term.extend_from_slice(next_term?);
+            self.reverse.start_term(&self.term);
+            (self.in_term, self.term_input, self.term_doc_id) = (true, 0, 0);
+            work += 1;
+        }
+        while self.term_input < self.inputs.len() {
+            let i = self.term_input;
+            if let Some(index) = field_index(&self.inputs[i], field, *index_type) {
+                if self.cursors[i] < index.term_count() {
+                    let (term, mut postings) = index.term_at(self.cursors[i]);
+                    if term == self.term.as_slice() {
+                        let mut posting = postings.advance_to(self.term_doc_id);
+                        while let Some(next) = posting {
+                            if work >= budget {
+                                self.term_doc_id = next.doc_id;
+                                return Some(work);
+                            }
+                            let doc_id = self.doc_maps[i][next.doc_id as usize];
+                            if doc_id != DROPPED {
+                                let field_length = index.field_length(next.doc_id);
+                                self.reverse.add_posting(doc_id, field_length, next.positions());
+                            }
+                            work += 1;
+                            posting = postings.next();
+                        }
+                        self.cursors[i] += 1;
+                    }
+                }
+            }
+            self.term_input += 1;
+            self.term_doc_id = 0;
+        }
+        self.reverse.finish_term();
+        self.in_term = false;
+        return Some(work);
+    }
+
+    // Writes the smallest record of the inputs, None when every input is done with the
+    // current field.
+    fn merge_record(&mut self) -> Option<usize> {
+        let (field, index_type) = &self.fields[self.field];
+        let mut next: Option<(u64, u32, usize)> = None;
+        for (i, input) in self.inputs.iter().enumerate() {
+            if let Some(index) = field_index(input, field, *index_type) {
+                if self.cursors[i] < index.record_count() {
+                    let (key, doc_id) = index.record_at(self.cursors[i]);
+                    // Dropped records sort last among equal keys and are skipped below.
+                    let record = (key, self.doc_maps[i][doc_id as usize], i);
+                    if next.is_none_or(|next| record < next) {
+                        next = Some(record);
+                    }
+                }
+            }
+        }
+        let (key, doc_id, i) = next?;
+        if doc_id != DROPPED {
+            self.records.put_u64(key);
+            self.records.put_u32(doc_id);
+        }
+        self.cursors[i] += 1;
+        return Some(1);
+    }
+
+    fn finish_field(&mut self) {
+        let (field, index_type) = self.fields[self.field].clone();
+        let index = match index_type {
+            IndexType::Reverse => std::mem::take(&mut self.reverse).finish(field.clone()),
+            IndexType::Range | IndexType::Geospatial => {
+                Index::from_sorted_records(field.clone(), index_type, std::mem::take(&mut self.records))
+            }
+        };
+        self.indexes.insert(field, index);
+        self.cursors.fill(0);
+        self.field += 1;
+    }
+}
+
+#[inline]
+fn field_index<'a>(segment: &'a Segment, field: &str, index_type: IndexType) -> Option<&'a Index> {
+    return segment.indexes.get(field).filter(|index| index.index_type() == index_type);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn segment(id: u64, docs: &[(&str, &str, i64)]) -> Rc<Segment> {
+        let mut writer = SegmentWriter::default();
+        for (external_id, text, count) in docs {
+            let fields = [("body", FieldValue::Text(text, &Analyzer::STANDARD)), ("count", FieldValue::Number(Number::Integer(*count)))];
+            assert!(writer.add_document(external_id, text.as_bytes(), &fields).is_some());
+        }
+        assert!(writer.add_document(docs[0].0, b"", &[]).is_none());
+        return Rc::new(writer.finish(id));
+    }
+
+    #[test]
+    fn test_merge_in_steps() {
+        let inputs = vec![
+            segment(1, &[("a", "quick fox", 5), ("b", "lazy dog", 1)]),
+            segment(2, &[("c", "the dog", 3)]),
+            segment(3, &[("d", "quick quick dog", 5)]),
+        ];
+        let mut task = MergeTask::new(inputs);
+        let mut steps = 1;
+        while !task.step(2) {
+            steps += 1;
+        }
+        assert!(steps > 5, "{}", steps);
+        let merged = task.finish(4);
+        assert_eq!(merged.len(), 4);
+        assert_eq!(merged.docs.internal_id("c"), Some(2));
+        assert_eq!(merged.docs.get(3), Some(&b"quick quick dog"[..]));
+
+        let body = &merged.indexes["body"];
+        assert_eq!(body.term_count(), 5);
+        let postings = |term| -> Vec<(u32, Vec<u32>)> {
+            body.lookup(term).unwrap().map(|p| (p.doc_id, p.positions().collect())).collect()
+        };
+        assert_eq!(postings("dog"), vec![(1, vec![1]), (2, vec![1]), (3, vec![2])]);
+        assert_eq!(postings("quick"), vec![(0, vec![0]), (3, vec![0, 1])]);
+        assert_eq!((0..4).map(|doc_id| body.field_length(doc_id)).collect::<Vec<_>>(), vec![2, 2, 2, 3]);
+        assert_eq!(body.field_stats().total_length, 9);
+        let count = &merged.indexes["count"];
+        assert_eq!(count.equal(Number::Integer(5)).collect::<Vec<_>>(), vec![0, 3]);
+        let all: Vec<u32> = count.range(std::ops::Bound::Unbounded, std::ops::Bound::Unbounded).collect();
+        assert_eq!(all, vec![1, 2, 0, 3]);
+    }
+
+    #[test]
+    fn test_merge_frequent_term_in_steps() {
+        let docs: Vec<(String, String, i64)> =
+            (0..60).map(|i| (i.to_string(), format!("dog w{}", i), i)).collect();
+        let docs: Vec<(&str, &str, i64)> = docs.iter().map(|(id, text, count)| (id.as_str(), text.as_str(), *count)).collect();
+        let inputs = vec![segment(1, &docs[..30]), segment(2, &docs[30..])];
+        inputs[0].deletes.delete(4);
+        let mut task = MergeTask::new(inputs);
+        // Copying the documents takes 6 steps, the 60 postings of dog 6 more.
+        let mut steps = 1;
+        while !task.step(10) {
+            steps += 1;
+            assert!(steps < 100);
+        }
+        assert!(steps > 12, "{}", steps);
+        let merged = task.finish(3);
+        let dog: Vec<u32> = merged.indexes["body"].lookup("dog").unwrap().map(|p| p.doc_id).collect();
+        assert_eq!(dog, (0..59).collect::<Vec<u32>>());
+        assert_eq!(merged.indexes["body"].lookup("w5").unwrap().next().map(|p| p.doc_id), Some(4));
+    }
+
+    #[test]
+    fn test_tiered_merge_policy() {
+        let policy = TieredMergePolicy {
+            segments_per_tier: 3,
+            min_segment_size: 10,
+        };
+        assert_eq!(policy.find_merge(&[]), None);
+        assert_eq!(policy.find_merge(&[5, 100, 7]), None);
+        assert_eq!(policy.find_merge(&[5, 100, 7, 1]), Some(vec![0, 2, 3]));
+        // The smallest segments of a full tier are merged first.
+        assert_eq!(policy.find_merge(&[25, 30, 11, 20]), Some(vec![0, 2, 3]));
+        assert_eq!(policy.find_merge(&[100, 5, 90, 40, 80]), Some(vec![2, 3, 4]));
+    }
+}
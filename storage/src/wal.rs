@@ -0,0 +1,375 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use bytes::{Buf, BufMut, BytesMut};
+
+use crate::checksum::crc32;
+use crate::StorageError;
+
+// Write-ahead log of the changes made since the database file was last saved.
+//
+// Record layout: payload length (u32), payload checksum (u32), payload. The payload is the
+// record kind (u8) followed by its fields, each a length (u32) and the bytes.
+pub struct Wal {
+    path: PathBuf,
+    file: File,
+    policy: FsyncPolicy,
+    write_buf: BytesMut,
+    // Records written since the last fsync.
+    unsynced: usize,
+    last_sync: Instant,
+    // Length of the records written so far, a failed append is cut off here.
+    len: u64,
+    // Set when a failed append could not be cut off, the log then refuses appends.
+    broken: bool,
+    // Makes the next append fail after writing this many bytes.
+    #[cfg(test)]
+    fail_after: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsyncPolicy {
+    // fsync before a write is acknowledged.
+    Always,
+    // fsync at most once per interval, a crash loses at most the last interval of writes.
+    Interval(Duration),
+    // Leave flushing to the OS.
+    Never,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum WalRecord<'a> {
+    Create { collection: &'a str, definition: &'a [u8] },
+    Update { collection: &'a str, id: &'a str, doc: &'a [u8] },
+    Delete { collection: &'a str, id: &'a str },
+    // Replaces the definition of an existing collection, its documents stay.
+    Define { collection: &'a str, definition: &'a [u8] },
+}
+
+const RECORD_HEADER_LEN: usize = 8;
+
+impl WalRecord<'_> {
+    fn write_bytes(&self, output: &mut BytesMut) {
+        let start = output.len();
+        output.put_u32(0);
+        output.put_u32(0);
+        match self {
+            WalRecord::Create { collection, definition } => {
+                output.put_u8(0x1);
+                put_field(output, collection.as_bytes());
+                put_field(output, definition);
+            }
+            WalRecord::Update { collection, id, doc } => {
+                output.put_u8(0x2);
+                put_field(output, collection.as_bytes());
+                put_field(output, id.as_bytes());
+                put_field(output, doc);
+            }
+            WalRecord::Delete { collection, id } => {
+                output.put_u8(0x3);
+                put_field(output, collection.as_bytes());
+                put_field(output, id.as_bytes());
+            }
+            WalRecord::Define { collection, definition } => {
+                output.put_u8(0x4);
+                put_field(output, collection.as_bytes());
+                put_field(output, definition);
+            }
+        }
+        let payload_len = output.len() - start - RECORD_HEADER_LEN;
+        let checksum = crc32(&output[start + RECORD_HEADER_LEN..]);
+        output[start..start + 4].copy_from_slice(&(payload_len as u32).to_be_bytes());
+        output[start + 4..start + 8].copy_from_slice(&checksum.to_be_bytes());
+    }
+
+    fn from_payload(mut payload: &[u8]) -> Option<WalRecord<'_>> {
+        if payload.is_empty() {
+            return None;
+        }
+        let record = match payload.get_u8() {
+            0x1 => WalRecord::Create {
+                collection: get_str_field(&mut payload)?,
+                definition: get_field(&mut payload)?,
+            },
+            0x2 => WalRecord::Update {
+                collection: get_str_field(&mut payload)?,
+                id: get_str_field(&mut payload)?,
+                doc: get_field(&mut payload)?,
+            },
+            0x3 => WalRecord::Delete {
+                collection: get_str_field(&mut payload)?,
+                id: get_str_field(&mut payload)?,
+            },
+            0x4 => WalRecord::Define {
+                collection: get_str_field(&mut payload)?,
+                definition: get_field(&mut payload)?,
+            },
+            _ => return None,
+        };
+        if !payload.is_empty() {
+            return None;
+        }
+        return Some(record);
+    }
+}
+
+#[inline]
+fn put_field(output: &mut BytesMut, field: &[u8]) {
+    output.put_u32(field.len() as u32);
+    output.put_slice(field);
+}
+
+#[inline]
+fn get_field<'a>(payload: &mut &'a [u8]) -> Option<&'a [u8]> {
+    if payload.remaining() < 4 {
+        return None;
+    }
+    let len = payload.get_u32() as usize;
+    if payload.remaining() < len {
+        return None;
+    }
+    let (field, rest) = payload.split_at(len);
+    *payload = rest;
+    return Some(field);
+}
+
+#[inline]
+fn get_str_field<'a>(payload: &mut &'a [u8]) -> Option<&'a str> {
+    return std::str::from_utf8(get_field(payload)?).ok();
+}
+
+impl Wal {
+    // Opens the log for appending, replay it first.
+    pub fn open(path: &Path, policy: FsyncPolicy) -> io::Result<Wal> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let len = file.metadata()?.len();
+        return Ok(Wal {
+            path: path.to_owned(),
+            file,
+            policy,
+            write_buf: BytesMut::new(),
+            unsynced: 0,
+            last_sync: Instant::now(),
+            len,
+            broken: false,
+            #[cfg(test)]
+            fail_after: None,
+        });
+    }
+
+    // Calls `apply` for every record in the log and returns the number of records. A torn or
+    // corrupt record ends the log, it and anything after it are cut off.
+    pub fn replay(path: &Path, mut apply: impl FnMut(WalRecord<'_>)) -> Result<usize, StorageError> {
+        let bytes = match fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(StorageError::Io(e)),
+        };
+        let mut offset = 0;
+        let mut count = 0;
+        while let Some((record, len)) = read_record(&bytes[offset..]) {
+            apply(record);
+            offset += len;
+            count += 1;
+        }
+        if offset < bytes.len() {
+            tracing::warn!(
+                "Discarding {} bytes of torn or corrupt write-ahead log at offset {}",
+                bytes.len() - offset,
+                offset
+            );
+            OpenOptions::new().write(true).open(path)?.set_len(offset as u64)?;
+        }
+        return Ok(count);
+    }
+
+    // A record whose write or fsync fails is cut off again, so it is not replayed and the next
+    // record follows the last one that was acknowledged. When cutting it off fails as well,
+    // every later append fails.
+    pub fn append(&mut self, record: &WalRecord<'_>) -> io::Result<()> {
+        if self.broken {
+            return Err(io::Error::other("the write-ahead log could not be repaired after a failed write"));
+        }
+        self.write_buf.clear();
+        record.write_bytes(&mut self.write_buf);
+        if let Err(e) = self.write_record() {
+            if let Err(truncate_error) = self.truncate_to(self.len) {
+                tracing::warn!("Could not cut off a failed write-ahead log write: {}", truncate_error);
+                self.broken = true;
+            }
+            return Err(e);
+        }
+        self.len += self.write_buf.len() as u64;
+        return Ok(());
+    }
+
+    fn write_record(&mut self) -> io::Result<()> {
+        #[cfg(test)]
+        if let Some(written) = self.fail_after.take() {
+            self.file.write_all(&self.write_buf[..written])?;
+            return Err(io::Error::other("injected write failure"));
+        }
+        self.file.write_all(&self.write_buf)?;
+        self.unsynced += 1;
+        if self.policy == FsyncPolicy::Always {
+            self.sync()?;
+        }
+        return Ok(());
+    }
+
+    #[inline]
+    fn truncate_to(&mut self, len: u64) -> io::Result<()> {
+        self.file.set_len(len)?;
+        self.file.seek(SeekFrom::Start(len))?;
+        return Ok(());
+    }
+
+    // Called from the event loop, syncs when the interval of the batched policy has passed.
+    #[inline]
+    pub fn sync_if_due(&mut self) -> io::Result<()> {
+        if let FsyncPolicy::Interval(interval) = self.policy {
+            if self.unsynced > 0 && self.last_sync.elapsed() >= interval {
+                self.sync()?;
+            }
+        }
+        return Ok(());
+    }
+
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.file.sync_data()?;
+        self.unsynced = 0;
+        self.last_sync = Instant::now();
+        return Ok(());
+    }
+
+    // Empties the log once everything in it is saved in the database file.
+    pub fn reset(&mut self) -> io::Result<()> {
+        self.truncate_to(0)?;
+        self.len = 0;
+        self.file.sync_all()?;
+        self.unsynced = 0;
+        return Ok(());
+    }
+
+    #[inline]
+    pub fn path(&self) -> &Path {
+        return &self.path;
+    }
+}
+
+// Returns the record at the start of `bytes` and its length including the header.
+#[inline]
+fn read_record(mut bytes: &[u8]) -> Option<(WalRecord<'_>, usize)> {
+    if bytes.remaining() < RECORD_HEADER_LEN {
+        return None;
+    }
+    let len = bytes.get_u32() as usize;
+    let checksum = bytes.get_u32();
+    if bytes.remaining() < len || crc32(&bytes[..len]) != checksum {
+        return None;
+    }
+    let record = WalRecord::from_payload(&bytes[..len])?;
+    return Some((record, RECORD_HEADER_LEN + len));
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        return std::env::temp_dir().join(format!("finne_wal_test_{}_{}.wal", name, std::process::id()));
+    }
+
+    fn replay(path: &Path) -> Vec<String> {
+        let mut records = Vec::new();
+        Wal::replay(path, |record| records.push(format!("{:?}", record))).unwrap();
+        return records;
+    }
+
+    const RECORDS: [WalRecord<'static>; 4] = [
+        WalRecord::Create {
+            collection: "pets",
+            definition: br#"{"name":"pets"}"#,
+        },
+        WalRecord::Update {
+            collection: "pets",
+            id: "1",
+            doc: br#"{"al":"dog"}"#,
+        },
+        WalRecord::Delete {
+            collection: "pets",
+            id: "1",
+        },
+        WalRecord::Define {
+            collection: "pets",
+            definition: br#"{"name":"pets","synonyms":["cat, kitten"]}"#,
+        },
+    ];
+
+    #[test]
+    fn test_append_and_replay() {
+        let path = temp_path("replay");
+        let _ = fs::remove_file(&path);
+        assert_eq!(Wal::replay(&path, |_| unreachable!()).unwrap(), 0);
+
+        let mut wal = Wal::open(&path, FsyncPolicy::Always).unwrap();
+        for record in &RECORDS {
+            wal.append(record).unwrap();
+        }
+        let expected: Vec<String> = RECORDS.iter().map(|r| format!("{:?}", r)).collect();
+        assert_eq!(replay(&path), expected);
+
+        wal.reset().unwrap();
+        wal.append(&RECORDS[2]).unwrap();
+        assert_eq!(replay(&path), vec![expected[2].clone()]);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_torn_write_is_cut_off() {
+        let path = temp_path("torn");
+        let _ = fs::remove_file(&path);
+        let mut wal = Wal::open(&path, FsyncPolicy::Interval(Duration::from_secs(60))).unwrap();
+        for record in &RECORDS {
+            wal.append(record).unwrap();
+        }
+        wal.sync_if_due().unwrap();
+        assert_eq!(wal.unsynced, 4);
+
+        // Lose the end of the last record, replay cuts it off.
+        let mut bytes = fs::read(&path).unwrap();
+        bytes.truncate(bytes.len() - 2);
+        fs::write(&path, &bytes).unwrap();
+        assert_eq!(replay(&path).len(), 3);
+        assert!(fs::metadata(&path).unwrap().len() < bytes.len() as u64);
+
+        // Corrupt the second record, it and the third are cut off.
+        let mut bytes = fs::read(&path).unwrap();
+        let first_len = RECORD_HEADER_LEN + u32::from_be_bytes(bytes[..4].try_into().unwrap()) as usize;
+        bytes[first_len + RECORD_HEADER_LEN] ^= 0xff;
+        fs::write(&path, &bytes).unwrap();
+        assert_eq!(replay(&path).len(), 1);
+        assert_eq!(fs::metadata(&path).unwrap().len(), first_len as u64);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_failed_append_is_cut_off() {
+        let path = temp_path("failed");
+        let _ = fs::remove_file(&path);
+        let mut wal = Wal::open(&path, FsyncPolicy::Always).unwrap();
+        wal.append(&RECORDS[0]).unwrap();
+        wal.fail_after = Some(5);
+        assert!(wal.append(&RECORDS[1]).is_err());
+        wal.append(&RECORDS[2]).unwrap();
+        assert_eq!(replay(&path), vec![format!("{:?}", RECORDS[0]), format!("{:?}", RECORDS[2])]);
+
+        // A log that could not be repaired refuses appends, nothing follows the torn bytes.
+        wal.broken = true;
+        assert!(wal.append(&RECORDS[3]).is_err());
+        assert_eq!(replay(&path).len(), 2);
+        fs::remove_file(&path).unwrap();
+    }
+}